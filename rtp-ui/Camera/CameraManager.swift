@@ -43,12 +43,12 @@ class CameraManager: NSObject {
         }
     }()
     
-    override init() {
+    init(session: CallSession) {
         super.init()
         
-        compressionSession = CompressionManager()
+        compressionSession = CompressionManager(session: session)
         
-        run_runtime_server(StreamType(1))
+        run_runtime_server(session.pointer, StreamType(1))
         //run_runtime_server(StreamType(0))
         
         Task {
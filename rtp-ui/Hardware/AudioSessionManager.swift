@@ -17,16 +17,20 @@ class AudioManager {
     
     private var participantNodes: [UInt32: ParticipantAudio] = [:]
     
+    /// where encoded audio goes
+    private let session: CallSession
+    
     private let OPUS_ENCODER_SAMPLE_RATE: Double = 48000
     private let OPUS_ENCODER_DURATION_MS: Int = 20
     private let AUDIO_OUTPUT_SAMPLE_RATE: Double = 48000
     private let AUDIO_OUTPUT_CHANNELS: AVAudioChannelCount = 1
+    /// earlier frames repeated in each packet (RED) once peers report loss, 0 turns it off
+    private let AUDIO_REDUNDANCY_MAX_DEPTH: UInt8 = 2
     
-    init() {
+    init(session: CallSession) {
+        self.session = session
+        
         do {
-            run_runtime_server(StreamType(0))
-            rust_send_opus_config(OPUS_ENCODER_SAMPLE_RATE, AUDIO_OUTPUT_CHANNELS)
-            
             audioEngine = AVAudioEngine()
             inputNode = audioEngine.inputNode
             
@@ -39,26 +43,41 @@ class AudioManager {
         catch {
             print("Audio setup error: \(error)")
         }
+        
+        // peers can start showing up as soon as the stream's running
+        session.audioManager = self
+        rust_set_audio_redundancy(session.pointer, AUDIO_REDUNDANCY_MAX_DEPTH)
+        run_runtime_server(session.pointer, StreamType(0))
+        rust_send_opus_config(session.pointer, OPUS_ENCODER_SAMPLE_RATE, AUDIO_OUTPUT_CHANNELS, true)
     }
     
     func startRecording() {
         let inputFormat = AVAudioFormat(standardFormatWithSampleRate: OPUS_ENCODER_SAMPLE_RATE, channels: 1)!
         let desiredBufferSize = AVAudioFrameCount((Double(OPUS_ENCODER_DURATION_MS) / 1000.0) * OPUS_ENCODER_SAMPLE_RATE)
         
-        inputNode.installTap(onBus: 0, bufferSize: desiredBufferSize, format: inputFormat) { [weak self] buffer, _ in
-            self?.processBuffer(buffer)
+        inputNode.installTap(onBus: 0, bufferSize: desiredBufferSize, format: inputFormat) { [weak self] buffer, when in
+            self?.processBuffer(buffer, when: when)
         }
     }
     
-    private func processBuffer(_ buffer: AVAudioPCMBuffer) {
+    private func processBuffer(_ buffer: AVAudioPCMBuffer, when: AVAudioTime) {
         guard let encoder = encoder else { return }
         
         do {
             var encodedData = Data(count: Int(buffer.frameLength) * MemoryLayout<Float32>.size)
-            _ = try encoder.encode(buffer, to: &encodedData)    // this might be blocking, but anything is better than using AVAudioConverter 🤮
-                        
+            let length = try encoder.encode(buffer, to: &encodedData)    // this might be blocking, but anything is better than using AVAudioConverter 🤮
             
-            // TODO: Send to RUST
+            // 48kHz off the host time clock, same one the media clock (and so our SRs) reads
+            let captured = AVAudioTime.seconds(forHostTime: when.hostTime)
+            let timestamp = UInt32(truncatingIfNeeded: UInt64(captured * OPUS_ENCODER_SAMPLE_RATE))
+            
+            let result = encodedData.withUnsafeBytes { bytes in
+                rust_send_audio_sample(session.pointer, bytes.bindMemory(to: UInt8.self).baseAddress, UInt(length), timestamp)
+            }
+            
+            if result != Ok {
+                print("Failed to send audio: \(result)")
+            }
         } catch {
             print("Failed to encode buffer: \(error.localizedDescription)")
         }
@@ -75,24 +94,71 @@ class AudioManager {
         
         return participantAudio
     }
+    
+    func removeParticipant(ssrc: UInt32) {
+        guard let participantAudio = participantNodes.removeValue(forKey: ssrc) else { return }
+        
+        participantAudio.unregister(audioEngine: audioEngine)
+    }
 }
 
-@_cdecl("swift_receive_audio_config")
-public func swift_receive_audio_config(
+// a session's audio_peer_joined, `audio_manager_context` is the CallSession
+func swift_receive_audio_config(
     _ audio_manager_context: UnsafeMutableRawPointer?,
     _ sample_rate: Double,
     _ channels: UInt32,
-    _ ssrc: UInt32
+    _ ssrc: UInt32,
+    _ use_inband_fec: Bool
 ) -> UnsafeMutableRawPointer? {
-    guard let audio_manager_context else { return nil }
-    
-    let audioManager = Unmanaged<AudioManager>.fromOpaque(audio_manager_context).takeUnretainedValue()
+    guard
+        let audio_manager_context,
+        let audioManager = Unmanaged<CallSession>.fromOpaque(audio_manager_context).takeUnretainedValue().audioManager
+    else { return nil }
     
     let participantAudio = audioManager.addParticipant(ssrc: ssrc, sample_rate: sample_rate, channels: channels)
     
     return Unmanaged.passRetained(participantAudio).toOpaque()
 }
 
+// a session's receive_audio, `context` is the ParticipantAudio swift_receive_audio_config made
+func swift_receive_audio(
+    _ context: UnsafeMutableRawPointer?,
+    _ data: UnsafePointer<UInt8>?,
+    _ length: UInt,
+    _ ssrc: UInt32,
+    _ playout_time: UInt32,
+    _ samples: UInt32,
+    _ kind: AudioFrameKind
+) {
+    guard let context else { return }
+    
+    let participantAudio = Unmanaged<ParticipantAudio>.fromOpaque(context).takeUnretainedValue()
+    let when = hostTime(forPlayoutTime: playout_time)
+    
+    switch kind {
+    case Normal:
+        guard let data else { return }
+        
+        // copied, rust frees its buffer as soon as this returns
+        participantAudio.play(encodedData: Data(bytes: data, count: Int(length)), at: when)
+    default:
+        // TODO: the Opus package doesn't expose PLC or FEC decoding yet, keep the timing with silence
+        participantAudio.conceal(samples: AVAudioFrameCount(samples), at: when)
+    }
+}
+
+/// rust's playout_time (our wall clock in ms, times 48, wrapping) as a host time.
+/// nil if it's already passed, that plays as soon as possible
+func hostTime(forPlayoutTime playoutTime: UInt32) -> AVAudioTime? {
+    let now = UInt32(truncatingIfNeeded: UInt64(Date().timeIntervalSince1970 * 1000)) &* 48
+    let ahead = Int32(bitPattern: playoutTime &- now)
+    
+    guard ahead > 0 else { return nil }
+    
+    let seconds = Double(ahead) / 48_000
+    return AVAudioTime(hostTime: mach_absolute_time() + AVAudioTime.hostTime(forSeconds: seconds))
+}
+
 class ParticipantAudio {
     private var decoder: Opus.Decoder?
     private var playerNode: AVAudioPlayerNode!
@@ -115,7 +181,19 @@ class ParticipantAudio {
         
     }
     
-    func play(encodedData: Data) {
+    func unregister(audioEngine: AVAudioEngine) {
+        playerNode.stop()
+        audioEngine.detach(playerNode)
+    }
+    
+    func conceal(samples: AVAudioFrameCount, at when: AVAudioTime?) {
+        guard let silence = AVAudioPCMBuffer(pcmFormat: playerNode.outputFormat(forBus: 0), frameCapacity: samples) else { return }
+        
+        silence.frameLength = samples
+        playerNode.scheduleBuffer(silence, at: when)
+    }
+    
+    func play(encodedData: Data, at when: AVAudioTime?) {
         guard let decoder else { return }
         
         playerNode.play()
@@ -123,7 +201,7 @@ class ParticipantAudio {
         do {
             let decodedBuffer = try decoder.decode(encodedData)
             
-            playerNode.scheduleBuffer(decodedBuffer)
+            playerNode.scheduleBuffer(decodedBuffer, at: when)
         }
         catch {
             print("Failed to decode buffer: \(error.localizedDescription)")
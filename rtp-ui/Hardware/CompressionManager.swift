@@ -12,10 +12,13 @@ import RTPmacos
 class CompressionManager {
     
     private var compressionSessionOut: VTCompressionSession?
+    let session: CallSession
     var pps: [UInt8]?
     var sps: [UInt8]?
     
-    init () {
+    init (session: CallSession) {
+        self.session = session
+        
         //let videoEncoderSpecification = [kVTVideoEncoderSpecification_EnableLowLatencyRateControl: true as CFBoolean] as CFDictionary
         
         VTCompressionSessionCreate(allocator: kCFAllocatorDefault,
@@ -145,7 +148,7 @@ private let outputCallback: VTCompressionOutputCallback = { refcon, sourceFrameR
                 compression.pps = ppsArray
                 compression.sps = spsArray
                 
-                rust_send_h264_config(pparameterSetPointer, UInt(pparamSetSize), sparameterSetPointer, UInt(sparamSetSize))
+                rust_send_h264_config(compression.session.pointer, pparameterSetPointer, UInt(pparamSetSize), sparameterSetPointer, UInt(sparamSetSize))
             }
         }
     }
@@ -167,7 +170,8 @@ private let outputCallback: VTCompressionOutputCallback = { refcon, sourceFrameR
     let unmanagedBuffer = Unmanaged.passRetained(sampleBuffer)  // increments the counter
     let context = unmanagedBuffer.toOpaque()                    // get a pointer to pass to C
     
-    rust_send_frame(dataPointer, UInt(length), context, swift_release_frame_buffer, timestamp)
+    let compression = Unmanaged<CompressionManager>.fromOpaque(refcon).takeUnretainedValue()
+    rust_send_frame(compression.session.pointer, dataPointer, UInt(length), context, swift_release_frame_buffer, timestamp)
 }
 
 func swift_release_frame_buffer(_ context: UnsafeMutableRawPointer?) {
@@ -177,7 +181,7 @@ func swift_release_frame_buffer(_ context: UnsafeMutableRawPointer?) {
     let _ = Unmanaged<CMSampleBuffer>.fromOpaque(context).takeRetainedValue()
 }
 
-@_cdecl("swift_send_cmclocktime")
+// the session's media_clock, so sender reports use the same clock as the presentation timestamps
 func swift_send_cmclocktime() -> Float64 {
     let now = CMClockGetTime(CMClockGetHostTimeClock()).seconds
     
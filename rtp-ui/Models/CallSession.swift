@@ -0,0 +1,179 @@
+//
+//  CallSession.swift
+//  rtp-ui
+//
+
+import Foundation
+import RTPmacos
+
+/// The rust side of one call. Leaving the call is just letting go of this
+@Observable
+final class CallSession {
+    /// only nil if rust turned the session down, init fails then
+    @ObservationIgnored private(set) var pointer: OpaquePointer!
+    
+    /// whatever went wrong in the background most recently, the UI clears it once it's been shown
+    var errorMessage: String?
+    
+    /// where peers' video ends up
+    let peerVideoManager = PeerVideoManager()
+    
+    /// where peers' audio ends up, peers that join before it's set don't get any
+    @ObservationIgnored weak var audioManager: AudioManager?
+    
+    /// what anyone else needs to join, shown during the call
+    let room: String
+    let secret: String
+    
+    /// `address` is anyone already in the call, nil starts a new one.
+    /// With `viaRendezvous`, `address` is a rendezvous server and we join whoever's in the room.
+    /// Everyone in the call needs the same `room` and `secret`, neither can be empty.
+    /// Fails if rust won't take them, see `lastErrorMessage`
+    init?(address: String?, room: String, secret: String, viaRendezvous: Bool = false) {
+        self.room = room
+        self.secret = secret
+        
+        // unretained is fine, deinit waits for everything in rust to stop before letting go
+        let refcon = Unmanaged.passUnretained(self).toOpaque()
+        
+        let callbacks = SessionCallbacks(
+            user_data: refcon,
+            receive_frame: swift_receive_frame,
+            receive_audio: swift_receive_audio,
+            video_peer_joined: swift_receive_pps_sps,
+            audio_peer_joined: swift_receive_audio_config,
+            peer_left: swift_peer_left,
+            media_clock: swift_send_cmclocktime
+        )
+        
+        let roomBytes = Array(room.utf8)
+        let secretBytes = Array(secret.utf8)
+        
+        pointer = roomBytes.withUnsafeBufferPointer { roomBytes in
+            secretBytes.withUnsafeBufferPointer { secretBytes in
+                if let address, viaRendezvous {
+                    return Array(address.utf8).withUnsafeBufferPointer { addressBytes in
+                        rust_session_create_in_room(
+                            addressBytes.baseAddress, UInt(addressBytes.count),
+                            roomBytes.baseAddress, UInt(roomBytes.count),
+                            secretBytes.baseAddress, UInt(secretBytes.count),
+                            callbacks
+                        )
+                    }
+                } else if let address {
+                    return Array(address.utf8).withUnsafeBufferPointer { addressBytes in
+                        rust_session_create(
+                            addressBytes.baseAddress, UInt(addressBytes.count),
+                            roomBytes.baseAddress, UInt(roomBytes.count),
+                            secretBytes.baseAddress, UInt(secretBytes.count),
+                            callbacks
+                        )
+                    }
+                } else {
+                    return rust_session_create(
+                        nil, 0,
+                        roomBytes.baseAddress, UInt(roomBytes.count),
+                        secretBytes.baseAddress, UInt(secretBytes.count),
+                        callbacks
+                    )
+                }
+            }
+        }
+        
+        guard pointer != nil else { return nil }
+        
+        rust_session_set_error_callback(pointer, swift_session_error, refcon)
+    }
+    
+    /// A random secret for a new room
+    static func generateSecret() -> String {
+        guard let secret = rust_generate_room_secret() else { return "" }
+        defer { rust_string_free(secret) }
+        return String(cString: secret)
+    }
+    
+    /// Why the last rust call on this thread failed
+    static var lastErrorMessage: String? {
+        guard let message = rust_last_error_message() else { return nil }
+        return String(cString: message)
+    }
+    
+    /// Lets people on the local network find this call as `room`
+    func advertise(room: String) {
+        _ = Array(room.utf8).withUnsafeBufferPointer { bytes in
+            rust_session_advertise(pointer, bytes.baseAddress, UInt(bytes.count))
+        }
+    }
+    
+    /// Calls advertised on the local network. Blocks for `wait`, keep it off the main thread
+    static func nearbyRooms(wait: Duration = .seconds(1)) -> [NearbyCall] {
+        let found = rust_discover_rooms(UInt32(wait.components.seconds * 1000))
+        defer { rust_nearby_rooms_free(found) }
+        
+        guard let rooms = found.rooms else { return [] }
+        
+        return UnsafeBufferPointer(start: rooms, count: Int(found.count)).map { room in
+            NearbyCall(room: String(cString: room.room), address: String(cString: room.signaling_addr))
+        }
+    }
+    
+    /// Says goodbye to everyone and stops the streams. Blocks until that's done
+    func leave() {
+        rust_leave_call(pointer)
+    }
+    
+    deinit {
+        guard let pointer else { return }
+        
+        // rust still has us as user_data, nothing can call back once this returns.
+        // Already done if the call was ended, leaving twice is free
+        rust_leave_call(pointer)
+        rust_session_destroy(pointer)
+    }
+}
+
+/// Somebody's call on the local network, `address` is what to join
+struct NearbyCall: Identifiable, Hashable {
+    let room: String
+    let address: String
+    
+    var id: String { address }
+}
+
+/// Comes in on one of rust's threads, `message` only lives for the call
+func swift_session_error(_ userData: UnsafeMutableRawPointer?, _ code: RtpError, _ message: UnsafePointer<CChar>?) {
+    guard let userData, let message else { return }
+    
+    let session = Unmanaged<CallSession>.fromOpaque(userData).takeUnretainedValue()
+    // someone without the room secret, or pretending to be someone else. Worth standing out
+    let text = code == Security ? "Security: \(String(cString: message))" : String(cString: message)
+    
+    print("Session error \(code): \(text)")
+    
+    DispatchQueue.main.async {
+        session.errorMessage = text
+    }
+}
+
+/// A session's peer_left, `context` is the model swift_receive_pps_sps or swift_receive_audio_config retained
+func swift_peer_left(_ userData: UnsafeMutableRawPointer?, _ ssrc: UInt32, _ streamType: StreamType, _ context: UnsafeMutableRawPointer?) {
+    guard let userData, let context else { return }
+    
+    let session = Unmanaged<CallSession>.fromOpaque(userData).takeUnretainedValue()
+    
+    switch streamType {
+    case Video:
+        let model = Unmanaged<PeerVideoModel>.fromOpaque(context).takeUnretainedValue()
+        session.peerVideoManager.removePeer(model: model)
+        
+        // a frame for them might still be on its way through, let go once it's done
+        DispatchQueue.main.async {
+            Unmanaged<PeerVideoModel>.fromOpaque(context).release()
+        }
+    default:
+        DispatchQueue.main.async {
+            session.audioManager?.removeParticipant(ssrc: ssrc)
+            Unmanaged<ParticipantAudio>.fromOpaque(context).release()
+        }
+    }
+}
@@ -15,6 +15,9 @@ class PeerVideoModel {
     var currentFrame: CGImage?
     
     private var decompressionManager: DecompressionManager
+    
+    /// frames were lost, anything before the next keyframe would only decode as smears
+    @ObservationIgnored private var waitingForKeyframe = false
 
     init(pps: [UInt8], sps: [UInt8]) {
         
@@ -39,6 +42,19 @@ class PeerVideoModel {
         }
     }
     
+    /// false if the frame should be skipped, it depends on frames that were lost
+    func shouldDecode(data: UnsafeMutableRawPointer, length: Int, afterGap: Bool) -> Bool {
+        if afterGap {
+            waitingForKeyframe = true
+        }
+        
+        if waitingForKeyframe && containsKeyframe(data: data, length: length) {
+            waitingForKeyframe = false
+        }
+        
+        return !waitingForKeyframe
+    }
+    
     // every time a frame comes in, place into decompression manager
     func decompressFrame(blockBuffer : CMBlockBuffer) {
         var sampleBuffer: CMSampleBuffer?
@@ -71,6 +87,24 @@ class PeerVideoModel {
 }
 
 
+/// Whether an AVCC frame (4 byte length before each NAL unit) has an IDR slice in it
+func containsKeyframe(data: UnsafeMutableRawPointer, length: Int) -> Bool {
+    let bytes = UnsafeBufferPointer(start: data.assumingMemoryBound(to: UInt8.self), count: length)
+    var offset = 0
+    
+    while offset + 4 < length {
+        let naluLength = bytes[offset..<offset + 4].reduce(0) { ($0 << 8) | Int($1) }
+        
+        if bytes[offset + 4] & 0x1F == 5 {
+            return true
+        }
+        
+        offset += 4 + naluLength
+    }
+    
+    return false
+}
+
 func auditNALU(data: UnsafeMutableRawPointer, length: Int) {
     // 1. Move past the 4-byte AVCC length header
     let payload = data.assumingMemoryBound(to: UInt8.self).advanced(by: 4)
@@ -95,11 +129,12 @@ func auditNALU(data: UnsafeMutableRawPointer, length: Int) {
     print("NALU Type: \(naluType) [\(typeName)] | Header Byte: \(String(format: "%02X", headerByte))")
 }
 
-@_cdecl("swift_receive_frame")
-public func swift_receive_frame(
+// a session's receive_frame, `context` is the PeerVideoModel swift_receive_pps_sps made
+func swift_receive_frame(
     _ context: UnsafeMutableRawPointer?,
     _ frameData: UnsafeMutableRawPointer?,
-    _ frameDataLength: UInt
+    _ frameDataLength: UInt,
+    _ afterGap: Bool
 ) {
     guard let context = context, let frameData = frameData else { return }
     
@@ -107,6 +142,8 @@ public func swift_receive_frame(
     
     let peerVideoModel = Unmanaged<PeerVideoModel>.fromOpaque(context).takeUnretainedValue()
     
+    guard peerVideoModel.shouldDecode(data: frameData, length: Int(frameDataLength), afterGap: afterGap) else { return }
+    
     // TODO: I'm copying for now, but look into a zero copy solution.
     let frameDataCopy = UnsafeMutableRawPointer.allocate(byteCount: Int(frameDataLength), alignment: 16)
     
@@ -14,11 +14,12 @@ import RTPmacos
 class ViewModel {
     var currentFrame: CGImage?
     
-    private let cameraManager = CameraManager()
-    private let audioManager = AudioManager()
+    private let cameraManager: CameraManager
+    private let audioManager: AudioManager
     
-    init() {
-        rust_send_audio_manger_context(Unmanaged.passUnretained(audioManager).toOpaque())
+    init(session: CallSession) {
+        cameraManager = CameraManager(session: session)
+        audioManager = AudioManager(session: session)
         
         audioManager.startRecording()
         
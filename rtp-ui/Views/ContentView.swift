@@ -10,14 +10,15 @@ import RTPmacos
 
 
 struct ContentView: View {
-    @State private var viewModel = ViewModel()
+    @State private var viewModel: ViewModel
     
-    @State private var peerVideoManager = PeerVideoManager()
+    let session: CallSession
+    let endCall: () -> Void
     
-    init () {
-        // send the context to rust!
-        let refcon = Unmanaged.passRetained(peerVideoManager).toOpaque()
-        rust_send_video_callback(refcon)
+    init (session: CallSession, endCall: @escaping () -> Void) {
+        self.session = session
+        self.endCall = endCall
+        _viewModel = State(initialValue: ViewModel(session: session))
     }
     
     
@@ -26,19 +27,32 @@ struct ContentView: View {
             VideoGrid {
                 CameraView(image: $viewModel.currentFrame)
                 
-                ForEach(peerVideoManager.allPeers) { peer in
+                ForEach(session.peerVideoManager.allPeers) { peer in
                     peer
                 }
             }
             .background(Color.black)
             
-            UIView()
+            // what anyone else needs to get in
+            Text("Room \(session.room), secret \(session.secret)")
+                .textSelection(.enabled)
+                .padding(.vertical, 4)
+            
+            UIView(endCall: endCall)
+        }
+        .alert("Something went wrong", isPresented: Binding(
+            get: { session.errorMessage != nil },
+            set: { if !$0 { session.errorMessage = nil } }
+        )) {
+            Button("OK", role: .cancel) {}
+        } message: {
+            Text(session.errorMessage ?? "")
         }
     }
 }
 
 #Preview {
-    ContentView()
+    ContentView(session: CallSession(address: nil, room: "Preview", secret: CallSession.generateSecret())!, endCall: {})
 }
 
 struct VideoGrid: Layout {
@@ -10,33 +10,90 @@ import RTPmacos
 
 struct JoinView: View {
     
-    @Binding var state: Bool
+    @Binding var session: CallSession?
     @State private var address = ""
+    @State private var room = ""
+    @State private var secret = ""
+    @State private var joinError: String?
+    @State private var nearby: [NearbyCall] = []
     
     var body: some View {
         
         VStack {
             Button(action: {
-                state = false
+                // a new call gets a fresh secret unless one was typed in, the call view shows it to share
+                if secret.isEmpty {
+                    secret = CallSession.generateSecret()
+                }
+                
+                // findable nearby under the room name, or the computer's if there isn't one
+                let name = room.isEmpty ? (Host.current().localizedName ?? "Call") : room
+                session = CallSession(address: nil, room: name, secret: secret)
+                joinError = session == nil ? CallSession.lastErrorMessage : nil
+                session?.advertise(room: name)
             }, label: {
                 Text("Start Session")
             })
                 .padding()
             
+            // everyone in the call types the same one, whoever started it can see it
+            SecureField("Room secret", text: $secret)
+                .textFieldStyle(.roundedBorder)
+                .frame(maxWidth: 200)
+            
             TextField("Enter SIP address", text: $address)
                 .textFieldStyle(.roundedBorder)
                 .frame(maxWidth: 200)
 
             Button(action: {
-                state = false
-                address.withCString { pointer in
-                    rust_set_signalling_addr(pointer, UInt(strlen(pointer)))
-                }
+                session = CallSession(address: address, room: room, secret: secret)
+                joinError = session == nil ? CallSession.lastErrorMessage : nil
             }, label: {
                 Text("Submit")
             })
+            
+            // the call's name, the secret only works with it. With Join Room the address is a rendezvous server
+            TextField("Room", text: $room)
+                .textFieldStyle(.roundedBorder)
+                .frame(maxWidth: 200)
+            
+            Button(action: {
+                session = CallSession(address: address, room: room, secret: secret, viaRendezvous: true)
+                joinError = session == nil ? CallSession.lastErrorMessage : nil
+            }, label: {
+                Text("Join Room")
+            })
+            
+            Text("Nearby")
+                .padding(.top)
+            
+            ForEach(nearby) { call in
+                Button(action: {
+                    session = CallSession(address: call.address, room: call.room, secret: secret)
+                    joinError = session == nil ? CallSession.lastErrorMessage : nil
+                }, label: {
+                    Text("\(call.room) (\(call.address))")
+                })
+            }
+            
+            Button(action: refreshNearby, label: {
+                Text("Refresh")
+            })
+            
+            if let joinError {
+                Text(joinError)
+                    .foregroundStyle(.red)
+            }
 
         }
         .frame(minWidth: 500, minHeight: 300)
+        .onAppear(perform: refreshNearby)
+    }
+    
+    private func refreshNearby() {
+        Task.detached {
+            let found = CallSession.nearbyRooms()
+            await MainActor.run { nearby = found }
+        }
     }
 }
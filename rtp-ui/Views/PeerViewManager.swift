@@ -15,21 +15,34 @@ class PeerVideoManager {
     // observing this in particular to get video feeds of our peers
     private var peers: Dictionary<String, PeerView> = [:]
     
+    // which view belongs to which model, so a peer leaving can find theirs
+    @ObservationIgnored private var addresses: Dictionary<ObjectIdentifier, String> = [:]
+    
     var allPeers: [PeerView] {
         Array(peers.values)
     }
     
-    func addPeer(peerView : PeerView, address: String) {
+    func addPeer(peerView : PeerView, model: PeerVideoModel, address: String) {
         
         DispatchQueue.main.async {
             self.peers[address] = peerView
+            self.addresses[ObjectIdentifier(model)] = address
+        }
+        
+    }
+    
+    func removePeer(model: PeerVideoModel) {
+        
+        DispatchQueue.main.async {
+            guard let address = self.addresses.removeValue(forKey: ObjectIdentifier(model)) else { return }
+            self.peers[address] = nil
         }
         
     }
 }
 
-@_cdecl("swift_receive_pps_sps")
-public func swift_receive_pps_sps(
+// a session's video_peer_joined, `context` is the CallSession
+func swift_receive_pps_sps(
     _ context: UnsafeMutableRawPointer?,
     _ pps: UnsafePointer<UInt8>?,
     _ ppsLength: UInt,
@@ -42,7 +55,7 @@ public func swift_receive_pps_sps(
         let addr = addr
     else { return nil }
     
-    let peerVideoManager = Unmanaged<PeerVideoManager>.fromOpaque(context).takeUnretainedValue()
+    let peerVideoManager = Unmanaged<CallSession>.fromOpaque(context).takeUnretainedValue().peerVideoManager
     
     // copy the data - rust will drop the original
     let pps = Array(UnsafeBufferPointer(start: pps, count: Int(ppsLength)))
@@ -52,7 +65,7 @@ public func swift_receive_pps_sps(
     let model = PeerVideoModel(pps: pps, sps: sps)
     let view = PeerView(peerVideoModel: model)
     
-    peerVideoManager.addPeer(peerView: view, address: address)
+    peerVideoManager.addPeer(peerView: view, model: model, address: address)
 
     // MARK: return the pointer of the peer model
     return Unmanaged.passRetained(model).toOpaque()
@@ -8,11 +8,11 @@
 import SwiftUI
 
 struct UIView: View {
+    var endCall: () -> Void = {}
+    
     var body: some View {
         HStack {
-            Button(action: {
-                print("HI")
-            }) {
+            Button(action: endCall) {
                 Label("End Call", systemImage: "phone.down")
                     .padding(10)
                     .background(.red)
@@ -10,16 +10,20 @@ import SwiftUI
 @main
 struct rtp_uiApp: App {
     
-    @State var showingJoinScreen: Bool = true
+    @State var session: CallSession?
     
     var body: some Scene {
         WindowGroup {
-            if showingJoinScreen {
-                JoinView(state: $showingJoinScreen)
+            if let session {
+                ContentView(session: session, endCall: {
+                    // the task keeps the session alive until it's done leaving
+                    Task.detached { session.leave() }
+                    self.session = nil
+                })
+                    .frame(minWidth: 600, minHeight: 400)
             }
             else {
-                ContentView()
-                    .frame(minWidth: 600, minHeight: 400)
+                JoinView(session: $session)
             }
         }
         .windowResizability(.contentSize)
@@ -0,0 +1,33 @@
+/*
+    Generates the C header for the FFI surface into OUT_DIR.
+    RTP_UPDATE_HEADER=1 also writes it over libs/rtpmacos.h, that's what `make header` does.
+    A test in interop checks the two haven't drifted apart.
+*/
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=RTP_UPDATE_HEADER");
+
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .expect("cbindgen.toml should parse");
+
+    let header = cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src/lib.rs"))
+        .generate()
+        .expect("Failed to generate the C header");
+
+    let generated = out_dir.join("rtpmacos.h");
+    header.write_to_file(&generated);
+
+    if env::var_os("RTP_UPDATE_HEADER").is_some() {
+        fs::copy(&generated, crate_dir.join("libs/rtpmacos.h"))
+            .expect("Failed to update libs/rtpmacos.h");
+    }
+}
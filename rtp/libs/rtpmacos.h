@@ -1,48 +1,239 @@
+/* Generated by cbindgen from the rust sources (`make header`), don't edit by hand */
+
+#pragma once
+
 #include <stdarg.h>
 #include <stdbool.h>
 #include <stdint.h>
 #include <stdlib.h>
 
+// What the decoder is supposed to do with a receive_audio callback
+typedef enum AudioFrameKind {
+  // decode `data` as usual
+  Normal,
+  // nothing arrived, conceal `samples` worth of audio (decoder PLC). `data` is null
+  Missing,
+  // the frame right before `data` was lost, rebuild `samples` of it
+  // from the in-band FEC (LBRR) carried in `data`
+  Fec,
+} AudioFrameKind;
+
 typedef enum StreamType {
   Audio,
   Video,
 } StreamType;
 
+typedef enum RtpError {
+  Ok,
+  NullPointer,
+  InvalidArgument,
+  StreamNotStarted,
+  StreamAlreadyStarted,
+  ChannelFull,
+  ChannelClosed,
+  Network,
+  Signaling,
+  // someone tried to join without the room secret, or to speak for another participant
+  Security,
+} RtpError;
+
+typedef struct Session Session;
+
+// One reassembled H.264 frame for the peer behind `context`. `frame_data` is only valid for the call.
+// `after_gap` means frames before it were lost, anything but a keyframe will decode with artifacts
+typedef void (*ReceiveFrameCallback)(void *context,
+                                     void *frame_data,
+                                     uintptr_t frame_data_length,
+                                     bool after_gap);
+
+// One Opus frame for the peer behind `context`, see AudioFrameKind for what to do with it.
+// `playout_time` is when it should be heard, our wall clock in ms times 48 (so 48kHz units), wrapping
+typedef void (*ReceiveAudioCallback)(void *context,
+                                     const uint8_t *data,
+                                     uintptr_t length,
+                                     uint32_t ssrc,
+                                     uint32_t playout_time,
+                                     uint32_t samples,
+                                     enum AudioFrameKind kind);
+
+// A video peer joined, `addr` is their media address as a C string.
+// Returns the `context` their frames get handed back with
+typedef void *(*VideoPeerJoinedCallback)(void *user_data,
+                                         const uint8_t *pps,
+                                         uintptr_t pps_length,
+                                         const uint8_t *sps,
+                                         uintptr_t sps_length,
+                                         const uint8_t *addr);
+
+// An audio peer joined. Returns the `context` their audio gets handed back with
+typedef void *(*AudioPeerJoinedCallback)(void *user_data,
+                                         double sample_rate,
+                                         uint32_t channels,
+                                         uint32_t ssrc,
+                                         bool use_inband_fec);
+
+// A peer's gone, `context` is what their *_peer_joined returned. Frame callbacks that were
+// already running can still be using it, so free it somewhere they'll be done by (a later main queue turn)
+typedef void (*PeerLeftCallback)(void *user_data,
+                                 uint32_t ssrc,
+                                 enum StreamType stream_type,
+                                 void *context);
+
+// Returns the current time in seconds, swift passes `swift_send_cmclocktime`
+typedef double (*MediaClockCallback)(void);
+
+// Everything a session calls back into. Any of them can be null,
+// whatever they'd have been handed just gets dropped
+typedef struct SessionCallbacks {
+  void *user_data;
+  ReceiveFrameCallback receive_frame;
+  ReceiveAudioCallback receive_audio;
+  VideoPeerJoinedCallback video_peer_joined;
+  AudioPeerJoinedCallback audio_peer_joined;
+  PeerLeftCallback peer_left;
+  // what the app stamps its media with, see media_clock.rs. Null for a monotonic clock
+  MediaClockCallback media_clock;
+} SessionCallbacks;
+
+// `message` is only valid for the duration of the call. Null clears the callback
+typedef void (*ErrorCallback)(void *user_data, enum RtpError code, const char *message);
+
+// One advertised call, both strings are rust's
+typedef struct NearbyRoom {
+  char *room;
+  // what to hand rust_session_create
+  char *signaling_addr;
+} NearbyRoom;
+
+typedef struct NearbyRooms {
+  struct NearbyRoom *rooms;
+  uintptr_t count;
+} NearbyRooms;
+
 typedef void (*ReleaseCallback)(void*);
 
-bool rust_send_frame(const uint8_t *data,
-                     uintptr_t len,
-                     void *context,
-                     ReleaseCallback release_callback,
-                     uint32_t timestamp);
+// Joins the call `host_addr` (anyone's signaling address) is in.
+// A null `host_addr` starts a new call instead.
+// `room` names the call and `secret` is its room secret (see rust_generate_room_secret),
+// everyone in it needs the same pair. Working out the key takes a moment.
+// Use a generated secret, not a password someone picked: whoever answers the join
+// (even a fake participant found over mDNS or the rendezvous server) can guess it offline.
+// `callbacks` is where everything received goes, for the whole session.
+// Null if any of them isn't UTF-8, or `room` or `secret` is null or empty,
+// see rust_last_error_message
+//
+// # Safety
+// `host_addr`, `room` and `secret` have to point to that many readable bytes,
+// or be null
+struct Session *rust_session_create(const uint8_t *host_addr,
+                                    uintptr_t host_addr_length,
+                                    const uint8_t *room,
+                                    uintptr_t room_length,
+                                    const uint8_t *secret,
+                                    uintptr_t secret_length,
+                                    struct SessionCallbacks callbacks);
+
+// Joins `room` on the rendezvous server at `rendezvous_addr` (see src/bin/rendezvous.rs),
+// starting it if nobody's there yet. Otherwise the same as rust_session_create.
+// The rendezvous server never sees `secret`, only who's in the room. It does decide who
+// gets our join though, so `secret` should be a generated one here too.
+// Null if any of them isn't UTF-8, or `room` or `secret` is empty
+//
+// # Safety
+// `rendezvous_addr`, `room` and `secret` have to point to that many readable bytes,
+// or be null
+struct Session *rust_session_create_in_room(const uint8_t *rendezvous_addr,
+                                            uintptr_t rendezvous_addr_length,
+                                            const uint8_t *room,
+                                            uintptr_t room_length,
+                                            const uint8_t *secret,
+                                            uintptr_t secret_length,
+                                            struct SessionCallbacks callbacks);
+
+// A random secret for a new room, show it to whoever should join.
+// Random enough that it can't be guessed offline, which a human-chosen one can be.
+// Free it with rust_string_free
+char *rust_generate_room_secret(void);
+
+// Where failures in the session's background tasks go (socket errors, signaling dying).
+// Called from any thread. `callback` can be null to stop hearing about them
+void rust_session_set_error_callback(const struct Session *session,
+                                     ErrorCallback callback,
+                                     void *user_data);
+
+// Says goodbye (RTCP BYE and signaling) and stops everything, blocking until it's done.
+// The session still needs rust_session_destroy after
+void rust_leave_call(const struct Session *session);
+
+// The session's streams as SDP, for pointing gstreamer or ffplay at them.
+// Null on failure, see rust_last_error_message. Free it with rust_string_free
+char *rust_session_description(const struct Session *session);
+
+// Lets people on the local network find this call under `room`, until it's left.
+// See rust_discover_rooms for the other side
+//
+// # Safety
+// `room` has to point to `room_length` readable bytes
+enum RtpError rust_session_advertise(const struct Session *session,
+                                     const uint8_t *room,
+                                     uintptr_t room_length);
+
+// Every call advertised on the local network, listening for `wait_ms` before answering.
+// Blocks, so keep it off the main thread. Empty (null `rooms`) if browsing failed,
+// see rust_last_error_message. Free it with rust_nearby_rooms_free
+struct NearbyRooms rust_discover_rooms(uint32_t wait_ms);
 
-void run_runtime_server(enum StreamType stream);
+// # Safety
+// `rooms` has to be from rust_discover_rooms, and not freed already
+void rust_nearby_rooms_free(struct NearbyRooms rooms);
 
-extern void swift_receive_frame(void *context, void *frameData, uintptr_t frameDataLength);
+// Frees a string we handed out
+//
+// # Safety
+// `string` has to be from rust_session_description or rust_generate_room_secret (or null),
+// and not freed already
+void rust_string_free(char *string);
 
-extern double swift_send_cmclocktime(void);
+// Frees the session. If it hasn't left the call yet, it leaves in the background.
+// `session` can't be used after this
+void rust_session_destroy(struct Session *session);
 
-extern void *swift_receive_pps_sps(void *context,
-                                   const uint8_t *pps,
-                                   uintptr_t pps_length,
-                                   const uint8_t *sps,
-                                   uintptr_t sps_length,
-                                   const uint8_t *addr);
+// `data` is one encoded Opus packet, `timestamp` is in 48kHz units (RFC 7587)
+//
+// # Safety
+// `data` has to point to `len` readable bytes
+enum RtpError rust_send_audio_sample(const struct Session *session,
+                                     const uint8_t *data,
+                                     uintptr_t len,
+                                     uint32_t timestamp);
 
-extern void *swift_receive_audio_config(void *audio_manager_context,
-                                        double sample_rate,
-                                        uint32_t channels,
-                                        uint32_t ssrc);
+// Most earlier frames (RFC 2198 RED) to repeat in each audio packet when peers report loss,
+// 0 turns it off. Anything over 2 is 2
+void rust_set_audio_redundancy(const struct Session *session, uint8_t max_depth);
 
-void rust_set_signalling_addr(const uint8_t *host_addr, uintptr_t host_addr_length);
+// `release_callback` gets `context` back once we're done with `data`, even on failure
+enum RtpError rust_send_frame(const struct Session *session,
+                              const uint8_t *data,
+                              uintptr_t len,
+                              void *context,
+                              ReleaseCallback release_callback,
+                              uint32_t timestamp);
 
-void rust_send_video_callback(void *context);
+enum RtpError run_runtime_server(const struct Session *session, enum StreamType stream);
 
-void rust_send_audio_manger_context(void *context);
+void rust_send_opus_config(const struct Session *session,
+                           double sample_rate,
+                           uint32_t channels,
+                           bool use_inband_fec);
 
-void rust_send_opus_config(double sample_rate, uint32_t channels);
+// # Safety
+// `pps` and `sps` have to point to `pps_length` and `sps_length` readable bytes
+enum RtpError rust_send_h264_config(const struct Session *session,
+                                    const uint8_t *pps,
+                                    uintptr_t pps_length,
+                                    const uint8_t *sps,
+                                    uintptr_t sps_length);
 
-void rust_send_h264_config(const uint8_t *pps,
-                           uintptr_t pps_length,
-                           const uint8_t *sps,
-                           uintptr_t sps_length);
+// The message behind the last error returned on this thread, null if there wasn't one.
+// Only valid until the next failing call on the same thread
+const char *rust_last_error_message(void);
@@ -0,0 +1,26 @@
+/*
+    Hosts rooms so people can find each other, see session_management/rendezvous.rs.
+
+    cargo run --bin rendezvous -- 0.0.0.0:7000
+
+    The address defaults to 0.0.0.0:7000. Clients point rust_session_create_in_room at it.
+*/
+
+use std::{env, sync::Arc};
+
+use rtp::session_management::rendezvous::Rendezvous;
+use tokio::{io, net::TcpListener};
+
+const DEFAULT_ADDR: &str = "0.0.0.0:7000";
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string());
+
+    let listener = TcpListener::bind(&addr).await?;
+    println!("Rendezvous listening on {}", listener.local_addr()?);
+
+    Arc::new(Rendezvous::new()).serve(listener).await
+}
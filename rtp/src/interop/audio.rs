@@ -1,56 +1,124 @@
-use std::{io, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicU8, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use bytes::{BufMut, Bytes, BytesMut};
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::sync::mpsc;
 
-use crate::{packets::rtp::rtp::RTPHeader, session_management::{delay_calculator::calculate_playout_time, peer_manager::PeerManager}};
+use crate::{
+    interop::error::{Error, ErrorReporter, RtpError},
+    packets::rtp::{
+        opus::{
+            MAX_CONCEALMENT_SAMPLES, OPUS_PAYLOAD_TYPE, OpusDepayloader, OpusPayloader,
+            packet_samples,
+        },
+        red::{self, RED_PAYLOAD_TYPE, RedEncoder, depth_for_loss},
+        rtp::RTPHeader,
+    },
+    session_management::{
+        delay_calculator::calculate_playout_time,
+        lip_sync::LipSync,
+        media_sink::MediaSink,
+        network_monitor::SwappableSocket,
+        peer_manager::{PeerManager, PlayoutBufferNode},
+    },
+};
+
+/// What the decoder is supposed to do with a receive_audio callback
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFrameKind {
+    /// decode `data` as usual
+    Normal,
+
+    /// nothing arrived, conceal `samples` worth of audio (decoder PLC). `data` is null
+    Missing,
+
+    /// the frame right before `data` was lost, rebuild `samples` of it
+    /// from the in-band FEC (LBRR) carried in `data`
+    Fec,
+}
 
 pub struct EncodedAudio {
     pub data: Bytes,
+
+    /// 48kHz units, RFC 7587 doesn't care what rate the encoder runs at
     pub timestamp: u32,
 }
 
 pub async fn rtp_audio_sender(
-    socket: Arc<UdpSocket>,
+    socket: Arc<SwappableSocket>,
     peer_manager: Arc<PeerManager>,
     mut rx: mpsc::Receiver<EncodedAudio>,
+    redundancy: Arc<AtomicU8>,
+    errors: Arc<ErrorReporter>,
 ) {
+    let mut payloader = OpusPayloader::new();
+    let mut red_encoder = RedEncoder::new();
+
     loop {
         let sample = match rx.recv().await {
             Some(s) => s,
-            None => continue,
+            None => break, // the session's gone
         };
 
-        let peers = peer_manager.get_peers();
+        // checked before the peer list, silence while alone still counts as silence
+        let talkspurt = payloader.starts_talkspurt(sample.timestamp, &sample.data);
+
+        let peers = peer_manager.get_receivers();
 
         if peers.is_empty() {
             continue;
         }
 
-        let header = peer_manager.rtp_session.get_packet(
-            false, 
-            sample.timestamp, 
-            sample.data.len() as u32
-        );
+        let max_depth = redundancy.load(Ordering::Relaxed);
+
+        let (payload_type, payload) = if max_depth > 0 && peer_manager.all_peers_accept_red() {
+            let depth = depth_for_loss(peer_manager.max_reported_fraction_lost(), max_depth);
+
+            let payload =
+                red_encoder.encode(OPUS_PAYLOAD_TYPE, sample.timestamp, &sample.data, depth);
+
+            (RED_PAYLOAD_TYPE, payload)
+        } else {
+            (OPUS_PAYLOAD_TYPE, sample.data)
+        };
+
+        let mut header =
+            peer_manager
+                .rtp_session
+                .get_packet(talkspurt, sample.timestamp, payload.len() as u32);
+        header.payload_type = payload_type;
 
         let mut packet = header.serialize();
-        packet.put(sample.data);
+        packet.put(payload);
 
         for addr in peers.iter() {
-            match socket.send_to(&packet, addr).await {
-                Ok(_) => {}
-                Err(e) => eprintln!("Failed to send to {}: {}", addr, e),
+            if let Err(e) = socket.send_to(&packet, addr).await {
+                errors.report(Error::new(
+                    RtpError::Network,
+                    format!("Failed to send audio to {}: {}", addr, e),
+                ));
             }
         }
     }
 }
 
 pub async fn rtp_audio_receiver(
-    socket: Arc<UdpSocket>,
+    socket: Arc<SwappableSocket>,
     peer_manager: Arc<PeerManager>,
     media_clock_rate: u32,
+    sink: Arc<dyn MediaSink>,
+    lip_sync: Arc<LipSync>,
 ) -> io::Result<()> {
     let mut buffer = [0u8; 1500];
+    let mut depayloaders: HashMap<u32, OpusDepayloader> = HashMap::new();
 
     loop {
         let (bytes_read, _) = socket.recv_from(&mut buffer).await?;
@@ -73,6 +141,24 @@ pub async fn rtp_audio_receiver(
         data.put_slice(&buffer[..bytes_read]);
 
         let header = RTPHeader::deserialize(&mut data);
+        peer_manager.heard_from(header.ssrc);
+
+        // RED: the primary goes through like any other packet,
+        // the redundant copies only fill holes once we know where the primary landed
+        let mut redundant_blocks = Vec::new();
+
+        if header.payload_type == RED_PAYLOAD_TYPE {
+            let Some(mut blocks) = red::decode(data.freeze(), header.timestamp) else {
+                continue;
+            };
+
+            let Some(primary) = blocks.pop() else {
+                continue;
+            };
+
+            data = BytesMut::from(primary.data);
+            redundant_blocks = blocks;
+        }
 
         let play_out_time = calculate_playout_time(
             &peer_manager,
@@ -82,14 +168,217 @@ pub async fn rtp_audio_receiver(
             &header,
         );
 
-        let Some(sample) = peer_manager.pop_node(header.ssrc, header.timestamp) else {
+        // unknown peer, nothing was buffered
+        let Some(play_out_time) = play_out_time else {
             continue;
         };
 
-        let Some(context) = peer_manager.get_context(header.ssrc) else {
-            continue; // in case that the UI hasn't sent back the pointer to stream, just ignore
-        };
+        for block in redundant_blocks {
+            let offset = header.timestamp.wrapping_sub(block.timestamp);
+
+            let node = PlayoutBufferNode {
+                rtp_timestamp: block.timestamp,
+                playout_time: play_out_time.wrapping_sub(offset),
+                coded_data: Vec::with_capacity(1),
+            };
+
+            peer_manager.add_redundant_node_to_peer(
+                header.ssrc,
+                node,
+                header.sequence_number,
+                block.distance,
+                block.data,
+                media_clock_rate,
+            );
+        }
+
+        // every Opus packet is a whole frame, so these come out as soon as they're in order
+        while let Some(sample) = peer_manager.pop_next_frame(header.ssrc) {
+            let Some(context) = peer_manager.get_context(header.ssrc) else {
+                continue; // in case that the UI hasn't sent back the pointer to stream, just ignore
+            };
+
+            let Some(packet) = sample.node.coded_data.first() else {
+                continue;
+            };
+
+            // video lines itself up against this, see lip_sync.rs
+            if let Some(capture_time) = peer_manager.capture_time_ms(
+                header.ssrc,
+                sample.node.rtp_timestamp,
+                media_clock_rate,
+            ) {
+                lip_sync.observe(
+                    header.ssrc,
+                    duration_since.as_millis() as i64 - capture_time,
+                );
+            }
+
+            let missing = depayloaders
+                .entry(header.ssrc)
+                .or_default()
+                .missing_samples(sample.node.rtp_timestamp, &packet.data, packet.marker);
+
+            conceal_missing_audio(
+                sink.as_ref(),
+                context,
+                header.ssrc,
+                missing,
+                sample.node.playout_time,
+                &packet.data,
+            );
+
+            sink.receive_audio(
+                context,
+                &packet.data,
+                header.ssrc,
+                sample.node.playout_time,
+                packet_samples(&packet.data).unwrap_or(0),
+                AudioFrameKind::Normal,
+            );
+        }
+    }
+}
+
+/// Fills in `missing` samples right before `next_packet`.
+/// Everything but the last lost frame gets plain PLC, the last one can be rebuilt
+/// from the FEC data in the packet after it. If the sender didn't include any,
+/// Opus falls back to PLC by itself, so it's always worth asking.
+fn conceal_missing_audio(
+    sink: &dyn MediaSink,
+    context: *mut std::ffi::c_void,
+    ssrc: u32,
+    missing: u32,
+    next_playout_time: u32,
+    next_packet: &[u8],
+) {
+    if missing == 0 {
+        return;
+    }
+
+    // too long a gap, leave it silent. Probably a network hiccup, not a few lost packets
+    if missing > MAX_CONCEALMENT_SAMPLES {
+        return;
+    }
+
+    // assume the lost frames were the same length as the one that made it
+    let frame_samples = packet_samples(next_packet).unwrap_or(missing).max(1);
+
+    let mut remaining = missing;
+
+    while remaining > frame_samples {
+        sink.receive_audio(
+            context,
+            &[],
+            ssrc,
+            next_playout_time.wrapping_sub(remaining),
+            frame_samples,
+            AudioFrameKind::Missing,
+        );
+
+        remaining -= frame_samples;
+    }
+
+    sink.receive_audio(
+        context,
+        next_packet,
+        ssrc,
+        next_playout_time.wrapping_sub(remaining),
+        remaining,
+        AudioFrameKind::Fec,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// CELT 20 ms, one frame
+    const FRAME: [u8; 1] = [31 << 3];
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: Mutex<Vec<(u32, u32, AudioFrameKind, bool)>>,
+    }
+
+    impl MediaSink for Recorder {
+        fn receive_frame(
+            &self,
+            _context: *mut std::ffi::c_void,
+            _frame: &mut [u8],
+            _after_gap: bool,
+        ) {
+        }
+
+        fn receive_audio(
+            &self,
+            _context: *mut std::ffi::c_void,
+            data: &[u8],
+            _ssrc: u32,
+            playout_time: u32,
+            samples: u32,
+            kind: AudioFrameKind,
+        ) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push((playout_time, samples, kind, data.is_empty()));
+        }
+    }
+
+    fn conceal(missing: u32, next_playout_time: u32) -> Vec<(u32, u32, AudioFrameKind, bool)> {
+        let sink = Recorder::default();
+
+        conceal_missing_audio(
+            &sink,
+            std::ptr::null_mut(),
+            1,
+            missing,
+            next_playout_time,
+            &FRAME,
+        );
+
+        sink.calls.into_inner().unwrap()
+    }
+
+    #[test]
+    fn nothing_missing_nothing_concealed() {
+        assert!(conceal(0, 10_000).is_empty());
+    }
+
+    #[test]
+    fn the_last_lost_frame_comes_back_from_fec() {
+        assert_eq!(
+            conceal(960 * 3, 10_000),
+            vec![
+                (10_000 - 960 * 3, 960, AudioFrameKind::Missing, true),
+                (10_000 - 960 * 2, 960, AudioFrameKind::Missing, true),
+                (10_000 - 960, 960, AudioFrameKind::Fec, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn concealment_lines_up_across_wraparound() {
+        assert_eq!(
+            conceal(960 * 2, 480),
+            vec![
+                (
+                    480u32.wrapping_sub(960 * 2),
+                    960,
+                    AudioFrameKind::Missing,
+                    true
+                ),
+                (480u32.wrapping_sub(960), 960, AudioFrameKind::Fec, false),
+            ]
+        );
+    }
 
-        // TODO: send back to swift
+    #[test]
+    fn long_gaps_are_left_silent() {
+        assert_eq!(conceal(MAX_CONCEALMENT_SAMPLES, 10_000).len(), 6);
+        assert!(conceal(MAX_CONCEALMENT_SAMPLES + 1, 10_000).is_empty());
     }
 }
@@ -0,0 +1,551 @@
+/*
+    How the app gets media and session changes out of us: a table of function pointers
+    handed over once per session in rust_session_create. Nothing has to be exported under
+    a magic name, so any host (swift, a C test harness, ctypes) can embed the library.
+
+    `user_data` goes to the peer joined callbacks. Whatever they return is that peer's context,
+    and comes back with every frame from that peer instead.
+*/
+
+use std::{
+    ffi::{CString, c_void},
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::{
+    interop::{StreamType, audio::AudioFrameKind},
+    session_management::{
+        media_clock::{CallbackClock, MediaClock, MediaClockCallback, MonotonicClock},
+        media_sink::{MediaSink, SessionEvents},
+    },
+};
+
+/// One reassembled H.264 frame for the peer behind `context`. `frame_data` is only valid for the call.
+/// `after_gap` means frames before it were lost, anything but a keyframe will decode with artifacts
+pub type ReceiveFrameCallback = Option<
+    unsafe extern "C" fn(
+        context: *mut c_void,
+        frame_data: *mut c_void,
+        frame_data_length: usize,
+        after_gap: bool,
+    ),
+>;
+
+/// One Opus frame for the peer behind `context`, see AudioFrameKind for what to do with it.
+/// `playout_time` is when it should be heard, our wall clock in ms times 48 (so 48kHz units), wrapping
+pub type ReceiveAudioCallback = Option<
+    unsafe extern "C" fn(
+        context: *mut c_void,
+        data: *const u8,
+        length: usize,
+        ssrc: u32,
+        playout_time: u32,
+        samples: u32,
+        kind: AudioFrameKind,
+    ),
+>;
+
+/// A video peer joined, `addr` is their media address as a C string.
+/// Returns the `context` their frames get handed back with
+pub type VideoPeerJoinedCallback = Option<
+    unsafe extern "C" fn(
+        user_data: *mut c_void,
+        pps: *const u8,
+        pps_length: usize,
+        sps: *const u8,
+        sps_length: usize,
+        addr: *const u8,
+    ) -> *mut c_void,
+>;
+
+/// An audio peer joined. Returns the `context` their audio gets handed back with
+pub type AudioPeerJoinedCallback = Option<
+    unsafe extern "C" fn(
+        user_data: *mut c_void,
+        sample_rate: f64,
+        channels: u32,
+        ssrc: u32,
+        use_inband_fec: bool,
+    ) -> *mut c_void,
+>;
+
+/// A peer's gone, `context` is what their *_peer_joined returned. Frame callbacks that were
+/// already running can still be using it, so free it somewhere they'll be done by (a later main queue turn)
+pub type PeerLeftCallback = Option<
+    unsafe extern "C" fn(
+        user_data: *mut c_void,
+        ssrc: u32,
+        stream_type: StreamType,
+        context: *mut c_void,
+    ),
+>;
+
+/// Everything a session calls back into. Any of them can be null,
+/// whatever they'd have been handed just gets dropped
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SessionCallbacks {
+    pub user_data: *mut c_void,
+
+    pub receive_frame: ReceiveFrameCallback,
+    pub receive_audio: ReceiveAudioCallback,
+    pub video_peer_joined: VideoPeerJoinedCallback,
+    pub audio_peer_joined: AudioPeerJoinedCallback,
+    pub peer_left: PeerLeftCallback,
+
+    /// what the app stamps its media with, see media_clock.rs. Null for a monotonic clock
+    pub media_clock: MediaClockCallback,
+}
+
+impl SessionCallbacks {
+    pub fn clock(&self) -> Arc<dyn MediaClock> {
+        match self.media_clock {
+            Some(callback) => Arc::new(CallbackClock::new(callback)),
+            None => Arc::new(MonotonicClock::new()),
+        }
+    }
+}
+
+/// MediaSink/SessionEvents on top of the app's callbacks
+pub struct CallbackSink {
+    callbacks: SessionCallbacks,
+
+    /// the session was destroyed, the app may have freed everything we'd hand it
+    detached: AtomicBool,
+}
+
+// the app promised its callbacks can take calls from any thread when it registered them
+unsafe impl Send for CallbackSink {}
+unsafe impl Sync for CallbackSink {}
+
+impl CallbackSink {
+    pub fn new(callbacks: SessionCallbacks) -> Self {
+        Self {
+            callbacks,
+            detached: AtomicBool::new(false),
+        }
+    }
+
+    fn detached(&self) -> bool {
+        self.detached.load(Ordering::Acquire)
+    }
+}
+
+// the contexts are the app's own objects, we never look inside them
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+impl MediaSink for CallbackSink {
+    fn detach(&self) {
+        self.detached.store(true, Ordering::Release);
+    }
+
+    fn receive_frame(&self, context: *mut c_void, frame: &mut [u8], after_gap: bool) {
+        if self.detached() {
+            return;
+        }
+
+        let Some(receive_frame) = self.callbacks.receive_frame else {
+            return;
+        };
+
+        unsafe {
+            receive_frame(
+                context,
+                frame.as_mut_ptr() as *mut c_void,
+                frame.len(),
+                after_gap,
+            );
+        }
+    }
+
+    fn receive_audio(
+        &self,
+        context: *mut c_void,
+        data: &[u8],
+        ssrc: u32,
+        playout_time: u32,
+        samples: u32,
+        kind: AudioFrameKind,
+    ) {
+        if self.detached() {
+            return;
+        }
+
+        let Some(receive_audio) = self.callbacks.receive_audio else {
+            return;
+        };
+
+        // the app checks for null on Missing
+        let data_ptr = if data.is_empty() {
+            std::ptr::null()
+        } else {
+            data.as_ptr()
+        };
+
+        unsafe {
+            receive_audio(
+                context,
+                data_ptr,
+                data.len(),
+                ssrc,
+                playout_time,
+                samples,
+                kind,
+            );
+        }
+    }
+}
+
+// same as above, the context only gets handed back
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+impl SessionEvents for CallbackSink {
+    fn video_peer_joined(
+        &self,
+        _ssrc: u32,
+        media_addr: SocketAddr,
+        pps: &[u8],
+        sps: &[u8],
+    ) -> *mut c_void {
+        if self.detached() {
+            return std::ptr::null_mut();
+        }
+
+        let Some(video_peer_joined) = self.callbacks.video_peer_joined else {
+            return std::ptr::null_mut();
+        };
+
+        // an address never has a nul in it
+        let addr = CString::new(media_addr.to_string()).unwrap_or_default();
+
+        unsafe {
+            video_peer_joined(
+                self.callbacks.user_data,
+                pps.as_ptr(),
+                pps.len(),
+                sps.as_ptr(),
+                sps.len(),
+                addr.as_ptr() as *const u8,
+            )
+        }
+    }
+
+    fn audio_peer_joined(
+        &self,
+        ssrc: u32,
+        sample_rate: f64,
+        channels: u32,
+        use_inband_fec: bool,
+    ) -> *mut c_void {
+        if self.detached() {
+            return std::ptr::null_mut();
+        }
+
+        let Some(audio_peer_joined) = self.callbacks.audio_peer_joined else {
+            return std::ptr::null_mut();
+        };
+
+        unsafe {
+            audio_peer_joined(
+                self.callbacks.user_data,
+                sample_rate,
+                channels,
+                ssrc,
+                use_inband_fec,
+            )
+        }
+    }
+
+    fn peer_left(&self, ssrc: u32, stream_type: StreamType, context: *mut c_void) {
+        if self.detached() {
+            return;
+        }
+
+        let Some(peer_left) = self.callbacks.peer_left else {
+            return;
+        };
+
+        unsafe {
+            peer_left(self.callbacks.user_data, ssrc, stream_type, context);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ptr,
+        sync::Mutex,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        interop::{
+            error::RtpError, run_runtime_server, runtime, rust_leave_call, rust_send_audio_sample,
+            rust_send_opus_config, rust_session_create, rust_session_destroy,
+        },
+        session_management::session::Session,
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Event {
+        AudioPeerJoined { user_data: usize },
+        ReceiveAudio { context: usize },
+        PeerLeft { user_data: usize, context: usize },
+    }
+
+    /// the app's side, `decoder` is what it hands back as the peer's context
+    #[derive(Default)]
+    struct App {
+        events: Mutex<Vec<Event>>,
+        decoder: Decoder,
+    }
+
+    #[derive(Default)]
+    struct Decoder {
+        app: Mutex<usize>,
+    }
+
+    impl App {
+        fn user_data(&self) -> *mut c_void {
+            self as *const App as *mut c_void
+        }
+
+        fn decoder(&self) -> *mut c_void {
+            &self.decoder as *const Decoder as *mut c_void
+        }
+
+        fn record(&self, event: Event) {
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn events(&self) -> Vec<Event> {
+            self.events.lock().unwrap().clone()
+        }
+
+        fn heard(&self, what: fn(&Event) -> bool) -> bool {
+            self.events().iter().any(what)
+        }
+    }
+
+    unsafe extern "C" fn audio_peer_joined(
+        user_data: *mut c_void,
+        _sample_rate: f64,
+        _channels: u32,
+        _ssrc: u32,
+        _use_inband_fec: bool,
+    ) -> *mut c_void {
+        let app = unsafe { &*(user_data as *const App) };
+        app.record(Event::AudioPeerJoined {
+            user_data: user_data as usize,
+        });
+
+        *app.decoder.app.lock().unwrap() = user_data as usize;
+        app.decoder()
+    }
+
+    unsafe extern "C" fn receive_audio(
+        context: *mut c_void,
+        _data: *const u8,
+        _length: usize,
+        _ssrc: u32,
+        _playout_time: u32,
+        _samples: u32,
+        _kind: AudioFrameKind,
+    ) {
+        let decoder = unsafe { &*(context as *const Decoder) };
+        let app = unsafe { &*(*decoder.app.lock().unwrap() as *const App) };
+
+        app.record(Event::ReceiveAudio {
+            context: context as usize,
+        });
+    }
+
+    unsafe extern "C" fn peer_left(
+        user_data: *mut c_void,
+        _ssrc: u32,
+        _stream_type: StreamType,
+        context: *mut c_void,
+    ) {
+        let app = unsafe { &*(user_data as *const App) };
+        app.record(Event::PeerLeft {
+            user_data: user_data as usize,
+            context: context as usize,
+        });
+    }
+
+    fn create(host_addr: Option<&str>, app: &App) -> Box<Session> {
+        let room = "callback room";
+        let secret = "callback secret";
+        let (host_addr, host_addr_length) =
+            host_addr.map_or((ptr::null(), 0), |addr| (addr.as_ptr(), addr.len()));
+
+        let callbacks = SessionCallbacks {
+            user_data: app.user_data(),
+            receive_frame: None,
+            receive_audio: Some(receive_audio),
+            video_peer_joined: None,
+            audio_peer_joined: Some(audio_peer_joined),
+            peer_left: Some(peer_left),
+            media_clock: None,
+        };
+
+        let session = unsafe {
+            rust_session_create(
+                host_addr,
+                host_addr_length,
+                room.as_ptr(),
+                room.len(),
+                secret.as_ptr(),
+                secret.len(),
+                callbacks,
+            )
+        }
+        .expect("session");
+
+        // same order as the app
+        assert_eq!(
+            run_runtime_server(Some(&session), StreamType::Audio),
+            RtpError::Ok
+        );
+        rust_send_opus_config(Some(&session), 48_000.0, 1, true);
+
+        session
+    }
+
+    /// 20 ms of CELT, the contents don't matter
+    fn talk(session: &Session, timestamp: &mut u32) {
+        let packet = [31 << 3, 0xAA];
+
+        unsafe { rust_send_audio_sample(Some(session), packet.as_ptr(), packet.len(), *timestamp) };
+        *timestamp = timestamp.wrapping_add(960);
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    /// false if `done` never was
+    fn talk_until(session: &Session, timestamp: &mut u32, done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while Instant::now() < deadline {
+            if done() {
+                return true;
+            }
+
+            talk(session, timestamp);
+        }
+
+        false
+    }
+
+    #[test]
+    fn callbacks_get_their_context_until_the_call_is_left() {
+        let host_app = App::default();
+        let peer_app = App::default();
+
+        let host = create(None, &host_app);
+        let host_addr = runtime().block_on(host.signaling_addr()).unwrap();
+        let peer = create(Some(&host_addr.to_string()), &peer_app);
+
+        let mut timestamp = 0;
+        assert!(
+            talk_until(&peer, &mut timestamp, || {
+                host_app.heard(|event| matches!(event, Event::ReceiveAudio { .. }))
+            }),
+            "no audio came through, {:?}",
+            host_app.events()
+        );
+
+        rust_leave_call(Some(&host));
+        let events = host_app.events();
+
+        // the user data goes to the session events, what they returned comes back with the audio
+        assert!(matches!(events[0], Event::AudioPeerJoined { .. }));
+        for event in &events {
+            match *event {
+                Event::AudioPeerJoined { user_data } => {
+                    assert_eq!(user_data, host_app.user_data() as usize)
+                }
+                Event::ReceiveAudio { context } => {
+                    assert_eq!(context, host_app.decoder() as usize)
+                }
+                Event::PeerLeft { user_data, context } => {
+                    assert_eq!(user_data, host_app.user_data() as usize);
+                    assert_eq!(context, host_app.decoder() as usize);
+                }
+            }
+        }
+
+        // the peer keeps talking for a bit, none of it can reach us
+        for _ in 0..10 {
+            talk(&peer, &mut timestamp);
+        }
+        assert_eq!(host_app.events(), events);
+
+        rust_leave_call(Some(&peer));
+        rust_session_destroy(Some(peer));
+        rust_session_destroy(Some(host));
+
+        assert_eq!(host_app.events(), events);
+
+        // and the same again, destroyed without leaving first
+        let destroyed_app = App::default();
+        let talker_app = App::default();
+
+        let destroyed = create(None, &destroyed_app);
+        let destroyed_addr = runtime().block_on(destroyed.signaling_addr()).unwrap();
+        let talker = create(Some(&destroyed_addr.to_string()), &talker_app);
+
+        timestamp = 0;
+        assert!(
+            talk_until(&talker, &mut timestamp, || {
+                destroyed_app.heard(|event| matches!(event, Event::ReceiveAudio { .. }))
+            }),
+            "no audio came through, {:?}",
+            destroyed_app.events()
+        );
+
+        // no rust_leave_call, the session stops in the background from here
+        rust_session_destroy(Some(destroyed));
+        let events = destroyed_app.events();
+
+        // the app could've freed its user data and decoders by now
+        for _ in 0..10 {
+            talk(&talker, &mut timestamp);
+        }
+        assert_eq!(destroyed_app.events(), events);
+
+        rust_leave_call(Some(&talker));
+        rust_session_destroy(Some(talker));
+
+        assert_eq!(destroyed_app.events(), events);
+    }
+
+    #[test]
+    fn a_detached_sink_calls_nothing() {
+        let app = App::default();
+        let sink = CallbackSink::new(SessionCallbacks {
+            user_data: app.user_data(),
+            receive_frame: None,
+            receive_audio: Some(receive_audio),
+            video_peer_joined: None,
+            audio_peer_joined: Some(audio_peer_joined),
+            peer_left: Some(peer_left),
+            media_clock: None,
+        });
+
+        let context = sink.audio_peer_joined(1, 48_000.0, 1, true);
+        assert_eq!(app.events().len(), 1);
+
+        sink.detach();
+
+        assert!(sink.audio_peer_joined(2, 48_000.0, 1, true).is_null());
+        sink.receive_audio(context, &[0], 1, 0, 960, AudioFrameKind::Normal);
+        sink.peer_left(1, StreamType::Audio, context);
+
+        assert_eq!(app.events().len(), 1);
+    }
+}
@@ -0,0 +1,162 @@
+/*
+    Errors across the FFI.
+
+    Calls that can fail return an RtpError. The message behind the last one
+    on the calling thread is in rust_last_error_message.
+
+    Things that fail later on in a session's tasks (a socket dying, signaling falling over)
+    go to the session's error callback instead, if the app set one.
+*/
+
+use std::{
+    cell::RefCell,
+    ffi::{CString, c_char, c_void},
+    fmt,
+    sync::Mutex,
+};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtpError {
+    Ok,
+    NullPointer,
+    InvalidArgument,
+    StreamNotStarted,
+    StreamAlreadyStarted,
+    ChannelFull,
+    ChannelClosed,
+    Network,
+    Signaling,
+    /// someone tried to join without the room secret, or to speak for another participant
+    Security,
+}
+
+/// `message` is only valid for the duration of the call. Null clears the callback
+pub type ErrorCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, code: RtpError, message: *const c_char)>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub code: RtpError,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(code: RtpError, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn to_c_string(message: &str) -> CString {
+    // can't fail without the nul bytes
+    CString::new(message.replace('\0', "")).unwrap_or_default()
+}
+
+/// Turns a result into what an FFI function returns, stashing the message on failure
+pub(crate) fn ffi_result(result: Result<(), Error>) -> RtpError {
+    match result {
+        Ok(()) => RtpError::Ok,
+        Err(error) => {
+            set_last_error(&error);
+            error.code
+        }
+    }
+}
+
+pub(crate) fn set_last_error(error: &Error) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(to_c_string(&error.message)));
+}
+
+/// The message behind the last error returned on this thread, null if there wasn't one.
+/// Only valid until the next failing call on the same thread
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[derive(Clone, Copy)]
+struct RegisteredCallback {
+    callback: unsafe extern "C" fn(*mut c_void, RtpError, *const c_char),
+    user_data: *mut c_void,
+}
+
+// the app promised it can take calls from any thread when it registered
+unsafe impl Send for RegisteredCallback {}
+
+/// Where a session's background failures go
+#[derive(Default)]
+pub struct ErrorReporter {
+    callback: Mutex<Option<RegisteredCallback>>,
+}
+
+impl ErrorReporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_callback(&self, callback: ErrorCallback, user_data: *mut c_void) {
+        *self.callback.lock().unwrap() = callback.map(|callback| RegisteredCallback {
+            callback,
+            user_data,
+        });
+    }
+
+    pub fn report(&self, error: Error) {
+        eprintln!("{}", error);
+
+        // not holding the lock while the app runs, it might set another callback from in there
+        let registered = *self.callback.lock().unwrap();
+
+        if let Some(registered) = registered {
+            let message = to_c_string(&error.message);
+            unsafe { (registered.callback)(registered.user_data, error.code, message.as_ptr()) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// hangs up on the first error, like an app that tears the session down from the callback
+    unsafe extern "C" fn stop_listening(
+        user_data: *mut c_void,
+        code: RtpError,
+        _message: *const c_char,
+    ) {
+        let reporter = unsafe { &*(user_data as *const ErrorReporter) };
+
+        assert_eq!(code, RtpError::Network);
+        reporter.set_callback(None, std::ptr::null_mut());
+    }
+
+    #[test]
+    fn the_callback_can_change_the_callback() {
+        let reporter = ErrorReporter::new();
+        let user_data = &reporter as *const ErrorReporter as *mut c_void;
+
+        reporter.set_callback(Some(stop_listening), user_data);
+        reporter.report(Error::new(RtpError::Network, "socket closed"));
+
+        assert!(reporter.callback.lock().unwrap().is_none());
+    }
+}
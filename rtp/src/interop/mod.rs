@@ -1,35 +1,41 @@
 pub mod audio;
+pub mod callbacks;
+pub mod error;
 pub mod video;
 
 use bytes::Bytes;
-use local_ip_address::local_ip;
 
 use core::slice;
 use std::{
-    io::{self},
+    ffi::{CString, c_char, c_void},
     sync::{Arc, OnceLock},
+    time::Duration,
 };
 
-use tokio::{net::UdpSocket, runtime::Runtime, sync::mpsc};
+use tokio::runtime::Runtime;
 
 use crate::{
     interop::{
-        audio::{EncodedAudio, rtp_audio_receiver, rtp_audio_sender},
-        video::{EncodedFrame, ReleaseCallback, rtp_frame_receiver, rtp_frame_sender},
+        audio::EncodedAudio,
+        callbacks::{CallbackSink, SessionCallbacks},
+        error::{Error, ErrorCallback, RtpError, ffi_result, set_last_error},
+        video::{EncodedFrame, ReleaseCallback},
+    },
+    session_management::{
+        discovery::browse,
+        media_sink::MediaSink,
+        secure_channel::{RoomKey, generate_secret},
+        session::Session,
+        signaling_server::{CallEntry, H264Args, OpusArgs},
     },
-    packets::{RTPSession, rtcp::start_rtcp},
-    session_management::{peer_manager::PeerManager, signaling_server::run_signaling_server},
 };
 
 static RUNTIME: OnceLock<Runtime> = OnceLock::new();
 
-static FRAME_TX: OnceLock<mpsc::Sender<EncodedFrame>> = OnceLock::new();
-static AUDIO_TX: OnceLock<mpsc::Sender<EncodedAudio>> = OnceLock::new();
-
-const CHANNEL_BUFFER_SIZE: usize = 64;
+pub(crate) const CHANNEL_BUFFER_SIZE: usize = 64;
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StreamType {
     Audio,
     Video,
@@ -39,51 +45,374 @@ pub fn runtime() -> &'static Runtime {
     RUNTIME.get_or_init(|| Runtime::new().expect("Runtime creation failed. Loser"))
 }
 
+/// Joins the call `host_addr` (anyone's signaling address) is in.
+/// A null `host_addr` starts a new call instead.
+/// `room` names the call and `secret` is its room secret (see rust_generate_room_secret),
+/// everyone in it needs the same pair. Working out the key takes a moment.
+/// Use a generated secret, not a password someone picked: whoever answers the join
+/// (even a fake participant found over mDNS or the rendezvous server) can guess it offline.
+/// `callbacks` is where everything received goes, for the whole session.
+/// Null if any of them isn't UTF-8, or `room` or `secret` is null or empty,
+/// see rust_last_error_message
+///
+/// # Safety
+/// `host_addr`, `room` and `secret` have to point to that many readable bytes,
+/// or be null
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_session_create(
+    host_addr: *const u8,
+    host_addr_length: usize,
+    room: *const u8,
+    room_length: usize,
+    secret: *const u8,
+    secret_length: usize,
+    callbacks: SessionCallbacks,
+) -> Option<Box<Session>> {
+    let mut entry = CallEntry::Host;
+
+    if !host_addr.is_null() {
+        let host_addr = unsafe { utf8_argument(host_addr, host_addr_length, "host address")? };
+
+        entry = CallEntry::Peer(host_addr);
+    }
+
+    let room_key = unsafe { room_key(room, room_length, secret, secret_length)? };
+
+    Some(create_session(entry, room_key, callbacks))
+}
+
+/// Joins `room` on the rendezvous server at `rendezvous_addr` (see src/bin/rendezvous.rs),
+/// starting it if nobody's there yet. Otherwise the same as rust_session_create.
+/// The rendezvous server never sees `secret`, only who's in the room. It does decide who
+/// gets our join though, so `secret` should be a generated one here too.
+/// Null if any of them isn't UTF-8, or `room` or `secret` is empty
+///
+/// # Safety
+/// `rendezvous_addr`, `room` and `secret` have to point to that many readable bytes,
+/// or be null
 #[unsafe(no_mangle)]
-pub extern "C" fn rust_send_audio_sample(data: *const u8, len: usize, timestamp: u32) -> bool {
-    let tx = match AUDIO_TX.get() {
-        Some(tx) => tx,
-        None => {
-            eprintln!("Audio stream not initialized");
-            return false;
+pub unsafe extern "C" fn rust_session_create_in_room(
+    rendezvous_addr: *const u8,
+    rendezvous_addr_length: usize,
+    room: *const u8,
+    room_length: usize,
+    secret: *const u8,
+    secret_length: usize,
+    callbacks: SessionCallbacks,
+) -> Option<Box<Session>> {
+    let rendezvous_addr = unsafe {
+        utf8_argument(
+            rendezvous_addr,
+            rendezvous_addr_length,
+            "rendezvous address",
+        )?
+    };
+    let room_key = unsafe { room_key(room, room_length, secret, secret_length)? };
+    let room = unsafe { utf8_argument(room, room_length, "room")? };
+
+    Some(create_session(
+        CallEntry::Room {
+            rendezvous_addr,
+            room,
+        },
+        room_key,
+        callbacks,
+    ))
+}
+
+fn create_session(
+    entry: CallEntry,
+    room_key: RoomKey,
+    callbacks: SessionCallbacks,
+) -> Box<Session> {
+    let sink = Arc::new(CallbackSink::new(callbacks));
+
+    Box::new(Session::new(
+        runtime().handle().clone(),
+        entry,
+        room_key,
+        Arc::clone(&sink) as Arc<dyn MediaSink>,
+        sink,
+        callbacks.clock(),
+    ))
+}
+
+/// None (and the last error set) for a null or empty room or secret, there are no open calls
+///
+/// # Safety
+/// `room` and `secret` have to point to that many readable bytes, or be null
+unsafe fn room_key(
+    room: *const u8,
+    room_length: usize,
+    secret: *const u8,
+    secret_length: usize,
+) -> Option<RoomKey> {
+    let room = unsafe { utf8_argument(room, room_length, "room")? };
+    let secret = unsafe { utf8_argument(secret, secret_length, "secret")? };
+
+    match RoomKey::derive(&room, &secret) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            set_last_error(&Error::new(RtpError::InvalidArgument, e.to_string()));
+            None
         }
+    }
+}
+
+/// A random secret for a new room, show it to whoever should join.
+/// Random enough that it can't be guessed offline, which a human-chosen one can be.
+/// Free it with rust_string_free
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_generate_room_secret() -> *mut c_char {
+    CString::new(generate_secret())
+        .expect("secrets are only letters, digits and dashes")
+        .into_raw()
+}
+
+/// Every call that takes a session checks it isn't null. It still has to be one
+/// rust_session_create handed out that hasn't been through rust_session_destroy yet
+fn null_session() -> Error {
+    Error::new(RtpError::NullPointer, "session is null")
+}
+
+/// None (and the last error set) if it's null or not UTF-8
+///
+/// # Safety
+/// `data` has to point to `length` readable bytes, or be null
+unsafe fn utf8_argument(data: *const u8, length: usize, what: &str) -> Option<String> {
+    if data.is_null() {
+        set_last_error(&Error::new(
+            RtpError::InvalidArgument,
+            format!("{} is null", what),
+        ));
+        return None;
+    }
+
+    let bytes = unsafe { slice::from_raw_parts(data, length) };
+
+    let Ok(string) = str::from_utf8(bytes) else {
+        set_last_error(&Error::new(
+            RtpError::InvalidArgument,
+            format!("{} isn't valid UTF-8", what),
+        ));
+        return None;
     };
 
-    // Okay with copying here, we'd copy anyways swift side creating a pointer.
-    let slice = unsafe { slice::from_raw_parts(data, len) };
-    let data = Bytes::copy_from_slice(slice);
+    Some(string.to_string())
+}
+
+/// Where failures in the session's background tasks go (socket errors, signaling dying).
+/// Called from any thread. `callback` can be null to stop hearing about them
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_session_set_error_callback(
+    session: Option<&Session>,
+    callback: ErrorCallback,
+    user_data: *mut c_void,
+) {
+    let Some(session) = session else {
+        return;
+    };
 
-    let sample = EncodedAudio { data, timestamp };
+    session.errors().set_callback(callback, user_data);
+}
+
+/// Says goodbye (RTCP BYE and signaling) and stops everything, blocking until it's done.
+/// The session still needs rust_session_destroy after
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_leave_call(session: Option<&Session>) {
+    let Some(session) = session else {
+        return;
+    };
+
+    runtime().block_on(session.leave());
+}
+
+/// The session's streams as SDP, for pointing gstreamer or ffplay at them.
+/// Null on failure, see rust_last_error_message. Free it with rust_string_free
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_session_description(session: Option<&Session>) -> *mut c_char {
+    let Some(session) = session else {
+        set_last_error(&null_session());
+        return std::ptr::null_mut();
+    };
 
-    match tx.try_send(sample) {
-        Ok(_) => true,
-        Err(mpsc::error::TrySendError::Full(_)) => {
-            eprintln!("Warning: frame dropped - channel full");
-            false
+    let description = runtime()
+        .block_on(session.session_description())
+        .and_then(|description| {
+            CString::new(description)
+                .map_err(|e| Error::new(RtpError::InvalidArgument, e.to_string()))
+        });
+
+    match description {
+        Ok(description) => description.into_raw(),
+        Err(e) => {
+            set_last_error(&e);
+            std::ptr::null_mut()
         }
-        Err(mpsc::error::TrySendError::Closed(_)) => {
-            eprintln!("Error: channel closed");
-            false
+    }
+}
+
+/// Lets people on the local network find this call under `room`, until it's left.
+/// See rust_discover_rooms for the other side
+///
+/// # Safety
+/// `room` has to point to `room_length` readable bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_session_advertise(
+    session: Option<&Session>,
+    room: *const u8,
+    room_length: usize,
+) -> RtpError {
+    let Some(session) = session else {
+        return ffi_result(Err(null_session()));
+    };
+
+    let Some(room) = (unsafe { utf8_argument(room, room_length, "room") }) else {
+        return RtpError::InvalidArgument;
+    };
+
+    session.advertise(room);
+    RtpError::Ok
+}
+
+/// One advertised call, both strings are rust's
+#[repr(C)]
+pub struct NearbyRoom {
+    pub room: *mut c_char,
+    /// what to hand rust_session_create
+    pub signaling_addr: *mut c_char,
+}
+
+#[repr(C)]
+pub struct NearbyRooms {
+    pub rooms: *mut NearbyRoom,
+    pub count: usize,
+}
+
+/// Every call advertised on the local network, listening for `wait_ms` before answering.
+/// Blocks, so keep it off the main thread. Empty (null `rooms`) if browsing failed,
+/// see rust_last_error_message. Free it with rust_nearby_rooms_free
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_discover_rooms(wait_ms: u32) -> NearbyRooms {
+    let found = runtime().block_on(browse(Duration::from_millis(wait_ms.into())));
+
+    let found = match found {
+        Ok(found) => found,
+        Err(e) => {
+            set_last_error(&Error::new(RtpError::Network, e.to_string()));
+            return NearbyRooms {
+                rooms: std::ptr::null_mut(),
+                count: 0,
+            };
         }
+    };
+
+    // a room name with a NUL in it can't go over as a C string
+    let rooms: Box<[NearbyRoom]> = found
+        .into_iter()
+        .filter_map(|found| {
+            let room = CString::new(found.room).ok()?;
+            let signaling_addr = CString::new(found.signaling_addr.to_string()).ok()?;
+
+            Some(NearbyRoom {
+                room: room.into_raw(),
+                signaling_addr: signaling_addr.into_raw(),
+            })
+        })
+        .collect();
+
+    let count = rooms.len();
+    NearbyRooms {
+        rooms: Box::into_raw(rooms) as *mut NearbyRoom,
+        count,
     }
 }
 
+/// # Safety
+/// `rooms` has to be from rust_discover_rooms, and not freed already
 #[unsafe(no_mangle)]
-pub extern "C" fn rust_send_frame(
+pub unsafe extern "C" fn rust_nearby_rooms_free(rooms: NearbyRooms) {
+    if rooms.rooms.is_null() {
+        return;
+    }
+
+    let rooms =
+        unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(rooms.rooms, rooms.count)) };
+
+    for room in rooms.iter() {
+        unsafe {
+            drop(CString::from_raw(room.room));
+            drop(CString::from_raw(room.signaling_addr));
+        }
+    }
+}
+
+/// Frees a string we handed out
+///
+/// # Safety
+/// `string` has to be from rust_session_description or rust_generate_room_secret (or null),
+/// and not freed already
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Frees the session. If it hasn't left the call yet, it leaves in the background.
+/// `session` can't be used after this
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_session_destroy(session: Option<Box<Session>>) {
+    drop(session);
+}
+
+/// `data` is one encoded Opus packet, `timestamp` is in 48kHz units (RFC 7587)
+///
+/// # Safety
+/// `data` has to point to `len` readable bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_send_audio_sample(
+    session: Option<&Session>,
     data: *const u8,
     len: usize,
-    context: *mut std::ffi::c_void,
-    release_callback: ReleaseCallback,
     timestamp: u32,
-) -> bool {
-    let tx = match FRAME_TX.get() {
-        Some(tx) => tx,
-        None => {
-            eprintln!("Video stream not initialized");
-            return false;
-        }
+) -> RtpError {
+    let Some(session) = session else {
+        return ffi_result(Err(null_session()));
     };
 
+    if data.is_null() {
+        return ffi_result(Err(Error::new(RtpError::NullPointer, "audio data is null")));
+    }
+
+    // Okay with copying here, we'd copy anyways swift side creating a pointer.
+    let slice = unsafe { slice::from_raw_parts(data, len) };
+    let data = Bytes::copy_from_slice(slice);
+
+    ffi_result(session.send_audio(EncodedAudio { data, timestamp }))
+}
+
+/// Most earlier frames (RFC 2198 RED) to repeat in each audio packet when peers report loss,
+/// 0 turns it off. Anything over 2 is 2
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_set_audio_redundancy(session: Option<&Session>, max_depth: u8) {
+    let Some(session) = session else {
+        return;
+    };
+
+    session.set_audio_redundancy(max_depth);
+}
+
+/// `release_callback` gets `context` back once we're done with `data`, even on failure
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_send_frame(
+    session: Option<&Session>,
+    data: *const u8,
+    len: usize,
+    context: *mut c_void,
+    release_callback: ReleaseCallback,
+    timestamp: u32,
+) -> RtpError {
     // zero copy
     let frame = EncodedFrame {
         data,
@@ -93,110 +422,107 @@ pub extern "C" fn rust_send_frame(
         timestamp,
     };
 
-    match tx.try_send(frame) {
-        Ok(_) => true,
-        Err(mpsc::error::TrySendError::Full(_)) => {
-            eprintln!("Warning: frame dropped - channel full");
-            false
-        }
-        Err(mpsc::error::TrySendError::Closed(_)) => {
-            eprintln!("Error: channel closed");
-            false
-        }
+    if data.is_null() {
+        return ffi_result(Err(Error::new(RtpError::NullPointer, "frame data is null")));
     }
-}
 
-#[unsafe(no_mangle)]
-pub extern "C" fn run_runtime_server(stream: StreamType) {
-    runtime().spawn(async move {
-        if let Err(e) = network_loop_server(stream).await {
-            eprintln!(
-                "Something terrible happened. Not you though. You are amazing. Always: {}",
-                e
-            );
-        }
-    });
-}
-
-async fn network_loop_server(stream_type: StreamType) -> io::Result<()> {
-    /*
-       TODO: Handle a reconnection
-       Some cases:
-       -   Small network timeout (ip address and SSRC are the same)
-           Honestly, not much needs to be done. Just wait out the network timeout
-           SHOULD fix itself eventually
-
-       -   Switch networks (IP address changes)
-           Handle a full restart, meanwhile hopefully clients can remove the old peer
-           Completely stop the backend and restart.
+    // dropping the frame hands it back through release_callback
+    let Some(session) = session else {
+        return ffi_result(Err(null_session()));
+    };
 
-       -   Just disconnecting arruptly, no reconnection
-           Clients need to detect a timeout period, and remove you
-           RTCP delay since LSR might be useful!
-    */
+    ffi_result(session.send_frame(frame))
+}
 
-    let local_ip = local_ip().unwrap();
-    println!("{local_ip}");
+#[unsafe(no_mangle)]
+pub extern "C" fn run_runtime_server(session: Option<&Session>, stream: StreamType) -> RtpError {
+    let Some(session) = session else {
+        return ffi_result(Err(null_session()));
+    };
 
-    let socket = UdpSocket::bind(local_ip.to_string() + ":0").await?;
-    let socket = Arc::new(socket);
+    ffi_result(session.start_stream(stream))
+}
 
-    // RTCP: Sending to another peer's address is just their RTP address +1
-    let rtcp_port = socket.local_addr()?.port() + 1;
-    let rtcp_socket = UdpSocket::bind(format!("{}:{}", local_ip, rtcp_port)).await?;
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_send_opus_config(
+    session: Option<&Session>,
+    sample_rate: f64,
+    channels: u32,
+    use_inband_fec: bool,
+) {
+    let Some(session) = session else {
+        return;
+    };
 
-    // Session management objects
-    // we'll be using these throughout the program.
-    let rtp_session = RTPSession::new(socket.local_addr()?);
-    let peer_manager = Arc::new(PeerManager::new(rtp_session, stream_type));
+    session.set_opus_args(OpusArgs {
+        sample_rate,
+        channels,
+        use_inband_fec,
+    });
+}
 
-    println!("{:?}, {}", stream_type, peer_manager.rtp_session.ssrc);
+/// # Safety
+/// `pps` and `sps` have to point to `pps_length` and `sps_length` readable bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_send_h264_config(
+    session: Option<&Session>,
+    pps: *const u8,
+    pps_length: usize,
+    sps: *const u8,
+    sps_length: usize,
+) -> RtpError {
+    let Some(session) = session else {
+        return ffi_result(Err(null_session()));
+    };
 
-    // Signaling server thread
-    let peer_manager_clone = Arc::clone(&peer_manager);
-    runtime().spawn(async move {
-        if let Err(e) = run_signaling_server(peer_manager_clone, stream_type).await {
-            eprintln!("Signaling server error: {}", e);
-        }
-    });
+    if pps.is_null() || sps.is_null() {
+        return ffi_result(Err(Error::new(RtpError::NullPointer, "PPS or SPS is null")));
+    }
 
-    // RTCP Sender and receiver threads
-    let peer_manager_clone = Arc::clone(&peer_manager);
-    runtime().spawn(async move { start_rtcp(rtcp_socket, peer_manager_clone, stream_type).await });
+    let pps = unsafe { slice::from_raw_parts(pps, pps_length) };
 
-    // Video and Audio sender and receiver threads
-    let sender_socket = Arc::clone(&socket);
-    let sender_peers = Arc::clone(&peer_manager);
-    match stream_type {
-        StreamType::Video => {
-            let (tx, rx) = mpsc::channel::<EncodedFrame>(CHANNEL_BUFFER_SIZE);
+    let pps = Bytes::copy_from_slice(pps);
 
-            FRAME_TX.set(tx).map_err(|_| {
-                eprintln!("{:?} stream already initialized", stream_type);
-                return io::Error::new(io::ErrorKind::AlreadyExists, "video stream already in use");
-            })?;
+    let sps = unsafe { slice::from_raw_parts(sps, sps_length) };
 
-            runtime().spawn(async move {
-                rtp_frame_sender(sender_socket, sender_peers, rx).await;
-            });
+    let sps = Bytes::copy_from_slice(sps);
 
-            rtp_frame_receiver(socket, peer_manager, 90_000).await
-        }
+    session.set_h264_args(H264Args { sps, pps });
 
-        StreamType::Audio => {
-            let (tx, rx) = mpsc::channel::<EncodedAudio>(CHANNEL_BUFFER_SIZE);
+    RtpError::Ok
+}
 
-            AUDIO_TX.set(tx).map_err(|_| {
-                eprintln!("{:?} stream already initialized", stream_type);
-                return io::Error::new(io::ErrorKind::AlreadyExists, "audio stream already in use");
-            })?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_null_session_is_an_error_not_a_crash() {
+        let data = [0u8; 4];
+
+        assert_eq!(
+            run_runtime_server(None, StreamType::Audio),
+            RtpError::NullPointer
+        );
+        assert_eq!(
+            unsafe { rust_send_audio_sample(None, data.as_ptr(), data.len(), 0) },
+            RtpError::NullPointer
+        );
+        assert!(rust_session_description(None).is_null());
+
+        rust_leave_call(None);
+        rust_send_opus_config(None, 48_000.0, 1, true);
+        rust_set_audio_redundancy(None, 0);
+    }
 
-            runtime().spawn(async move {
-                rtp_audio_sender(sender_socket, sender_peers, rx).await;
-            });
+    #[test]
+    fn checked_in_header_is_up_to_date() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/rtpmacos.h"));
+        let checked_in = include_str!("../../libs/rtpmacos.h");
 
-            // TODO:
-            rtp_audio_receiver(socket, peer_manager, 48_000).await
-        }
+        assert!(
+            generated == checked_in,
+            "libs/rtpmacos.h is out of date, run `make header`"
+        );
     }
 }
@@ -1,24 +1,23 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{io, sync::Arc};
 
 use bytes::{BufMut, Bytes, BytesMut};
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{Instant, sleep_until};
 
 use crate::packets::rtp::h264::{get_fragments, get_nal_units, rtp_to_avcc_h264};
 use crate::packets::rtp::rtp::RTPHeader;
 use crate::session_management::delay_calculator::calculate_playout_time;
+use crate::session_management::lip_sync::LipSync;
+use crate::session_management::media_sink::MediaSink;
+use crate::session_management::network_monitor::SwappableSocket;
 use crate::session_management::peer_manager::PeerManager;
 
 //static FRAME_OUTPUT: OnceLock<Arc<PeerManager>> = OnceLock::new();
 
-unsafe extern "C" {
-    fn swift_receive_frame(
-        context: *mut std::ffi::c_void,
-        frameData: *mut std::ffi::c_void,
-        frameDataLength: usize,
-    );
-}
-
 pub type ReleaseCallback = extern "C" fn(*mut std::ffi::c_void);
 
 pub struct EncodedFrame {
@@ -38,18 +37,101 @@ impl Drop for EncodedFrame {
 // sometimes reasonable men do unreasonable things
 unsafe impl Send for EncodedFrame {}
 
+/// A depacketized frame waiting for its turn to go to the decoder,
+/// held back so it lines up with the participant's audio
+struct ReadyFrame {
+    ssrc: u32,
+    context: *mut std::ffi::c_void,
+    data: BytesMut,
+    after_gap: bool,
+    deliver_at: Instant,
+}
+
+unsafe impl Send for ReadyFrame {}
+
+/// A ReadyFrame in the render loop's heap, earliest `deliver_at` on top.
+/// `order` breaks ties so frames due at the same time still go out in arrival order
+struct ScheduledFrame {
+    frame: ReadyFrame,
+    order: u64,
+}
+
+impl ScheduledFrame {
+    fn key(&self) -> (Instant, u64) {
+        (self.frame.deliver_at, self.order)
+    }
+}
+
+impl PartialEq for ScheduledFrame {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for ScheduledFrame {}
+
+impl PartialOrd for ScheduledFrame {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScheduledFrame {
+    // reversed, BinaryHeap is a max-heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+/// Hands frames to the sink once their lip sync delay is up.
+/// Every peer has its own delay, so frames are scheduled by `deliver_at` instead of FIFO,
+/// a peer held back by a second doesn't hold back everyone else.
+/// Within one SSRC frames never overtake each other, even if its delay shrinks.
+async fn video_render_loop(mut rx: mpsc::Receiver<ReadyFrame>, sink: Arc<dyn MediaSink>) {
+    let mut scheduled: BinaryHeap<ScheduledFrame> = BinaryHeap::new();
+    let mut last_deliver_at: HashMap<u32, Instant> = HashMap::new();
+    let mut order = 0u64;
+
+    loop {
+        let next_due = scheduled.peek().map(|next| next.frame.deliver_at);
+
+        tokio::select! {
+            received = rx.recv() => {
+                let Some(mut frame) = received else {
+                    break; // the receiver stopped, whatever's left won't be shown
+                };
+
+                // decode order within a peer matters more than its exact delay
+                let last = last_deliver_at.entry(frame.ssrc).or_insert(frame.deliver_at);
+                frame.deliver_at = frame.deliver_at.max(*last);
+                *last = frame.deliver_at;
+
+                scheduled.push(ScheduledFrame { frame, order });
+                order += 1;
+            }
+            _ = sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                let Some(ScheduledFrame { mut frame, .. }) = scheduled.pop() else {
+                    continue;
+                };
+
+                sink.receive_frame(frame.context, &mut frame.data, frame.after_gap);
+            }
+        }
+    }
+}
+
 pub async fn rtp_frame_sender(
-    socket: Arc<UdpSocket>,
+    socket: Arc<SwappableSocket>,
     peer_manager: Arc<PeerManager>,
     mut rx: mpsc::Receiver<EncodedFrame>,
 ) {
     loop {
         let frame = match rx.recv().await {
             Some(f) => f,
-            None => continue,
+            None => break, // the session's gone, anything left in the channel was released with it
         };
 
-        let peers = peer_manager.get_peers();
+        let peers = peer_manager.get_receivers();
 
         if peers.is_empty() {
             continue;
@@ -86,14 +168,34 @@ pub async fn rtp_frame_sender(
 }
 
 pub async fn rtp_frame_receiver(
-    socket: Arc<UdpSocket>,
+    socket: Arc<SwappableSocket>,
     peer_manager: Arc<PeerManager>,
     media_clock_rate: u32,
+    sink: Arc<dyn MediaSink>,
+    lip_sync: Arc<LipSync>,
 ) -> io::Result<()> {
-    let mut buffer = [0u8; 1500];
-
     // let _ = FRAME_OUTPUT.set(Arc::clone(&peer_manager));
 
+    let (render_tx, render_rx) = mpsc::channel::<ReadyFrame>(crate::interop::CHANNEL_BUFFER_SIZE);
+
+    // one future, so stopping the receiver stops the render loop with it
+    let (_, result) = tokio::join!(
+        video_render_loop(render_rx, sink),
+        receive_frames(socket, peer_manager, media_clock_rate, lip_sync, render_tx),
+    );
+
+    result
+}
+
+async fn receive_frames(
+    socket: Arc<SwappableSocket>,
+    peer_manager: Arc<PeerManager>,
+    media_clock_rate: u32,
+    lip_sync: Arc<LipSync>,
+    render_tx: mpsc::Sender<ReadyFrame>,
+) -> io::Result<()> {
+    let mut buffer = [0u8; 1500];
+
     loop {
         let (bytes_read, _) = socket.recv_from(&mut buffer).await?;
 
@@ -121,6 +223,7 @@ pub async fn rtp_frame_receiver(
         data.put_slice(&buffer[..bytes_read]);
 
         let header = RTPHeader::deserialize(&mut data);
+        peer_manager.heard_from(header.ssrc);
 
         let play_out_time = calculate_playout_time(
             &peer_manager,
@@ -130,34 +233,171 @@ pub async fn rtp_frame_receiver(
             &header,
         );
 
+        // unknown peer, nothing was buffered
+        if play_out_time.is_none() {
+            continue;
+        }
+
         // Send to swift
-        if let Some(play_out_time) = play_out_time && header.marker {
-            let Some(frame) = peer_manager.pop_node(header.ssrc, header.timestamp) else {
-                continue;
-            };
+        // the playout buffer decides when a frame is complete, not the marker bit.
+        // one packet can complete several frames (ex: a late fragment of an older one)
+        while let Some(frame) = peer_manager.pop_next_frame(header.ssrc) {
+            // how long this frame took from their camera to here, before any lip sync delay
+            if let Some(capture_time) = peer_manager.capture_time_ms(
+                header.ssrc,
+                frame.node.rtp_timestamp,
+                media_clock_rate,
+            ) {
+                lip_sync.observe(
+                    header.ssrc,
+                    duration_since.as_millis() as i64 - capture_time,
+                );
+            }
 
             let frame_bytes: Vec<Bytes> = frame
+                .node
                 .coded_data
                 .into_iter()
                 .map(|frame| frame.data)
                 .collect();
 
-            let mut frame_data = rtp_to_avcc_h264(frame_bytes);
-            let frame_data_length = frame_data.len();
+            let frame_data = rtp_to_avcc_h264(frame_bytes);
 
             let Some(context) = peer_manager.get_context(header.ssrc) else {
                 continue; // in case that the UI hasn't sent back the pointer to stream, just ignore
             };
 
-            unsafe {
-                swift_receive_frame(
-                    context,
-                    frame_data.as_mut_ptr() as *mut std::ffi::c_void,
-                    frame_data_length,
-                );
+            let ready_frame = ReadyFrame {
+                ssrc: header.ssrc,
+                context,
+                data: frame_data,
+                // the decoder would smear until the next keyframe, the app can wait for one instead
+                after_gap: frame.after_gap,
+                deliver_at: Instant::now() + lip_sync.video_delay(header.ssrc),
+            };
+
+            // never wait on the renderer here, it would stop socket reads for every peer
+            match render_tx.try_send(ready_frame) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => peer_manager.record_render_drop(header.ssrc),
+                Err(TrySendError::Closed(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "video render loop stopped",
+                    ));
+                }
             }
         }
 
         //println!("{}: {}", addr.to_string(), bytes_read);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::interop::audio::AudioFrameKind;
+
+    /// Which context got a frame, and when
+    #[derive(Default)]
+    struct Recorder {
+        frames: Mutex<Vec<(usize, Instant)>>,
+    }
+
+    impl MediaSink for Recorder {
+        fn receive_frame(
+            &self,
+            context: *mut std::ffi::c_void,
+            _frame: &mut [u8],
+            _after_gap: bool,
+        ) {
+            self.frames
+                .lock()
+                .unwrap()
+                .push((context as usize, Instant::now()));
+        }
+
+        fn receive_audio(
+            &self,
+            _context: *mut std::ffi::c_void,
+            _data: &[u8],
+            _ssrc: u32,
+            _playout_time: u32,
+            _samples: u32,
+            _kind: AudioFrameKind,
+        ) {
+        }
+    }
+
+    /// the context doubles as the frame's id
+    fn frame(ssrc: u32, id: usize, delay: Duration) -> ReadyFrame {
+        ReadyFrame {
+            ssrc,
+            context: id as *mut std::ffi::c_void,
+            data: BytesMut::new(),
+            after_gap: false,
+            deliver_at: Instant::now() + delay,
+        }
+    }
+
+    fn delivered(sink: &Recorder) -> Vec<usize> {
+        sink.frames
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_delayed_peer_doesnt_hold_back_the_others() {
+        const HELD_BACK: Duration = Duration::from_millis(500);
+
+        let sink = Arc::new(Recorder::default());
+        let (tx, rx) = mpsc::channel(crate::interop::CHANNEL_BUFFER_SIZE);
+        let render = tokio::spawn(video_render_loop(rx, sink.clone()));
+
+        let start = Instant::now();
+
+        // the lip synced peer's frame is queued first
+        tx.send(frame(1, 1, HELD_BACK)).await.unwrap();
+        tx.send(frame(2, 2, Duration::ZERO)).await.unwrap();
+        tx.send(frame(2, 3, Duration::ZERO)).await.unwrap();
+
+        tokio::time::sleep(HELD_BACK / 5).await;
+        assert_eq!(delivered(&sink), vec![2, 3]);
+
+        let on_time = sink.frames.lock().unwrap()[1].1;
+        assert!(on_time - start < HELD_BACK / 5);
+
+        tokio::time::sleep(HELD_BACK).await;
+        assert_eq!(delivered(&sink), vec![2, 3, 1]);
+
+        let held_back = sink.frames.lock().unwrap()[2].1;
+        assert!(held_back - start >= HELD_BACK);
+
+        drop(tx);
+        render.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_shrinking_delay_keeps_a_peers_frames_in_order() {
+        let sink = Arc::new(Recorder::default());
+        let (tx, rx) = mpsc::channel(crate::interop::CHANNEL_BUFFER_SIZE);
+        let render = tokio::spawn(video_render_loop(rx, sink.clone()));
+
+        tx.send(frame(1, 1, Duration::from_millis(100)))
+            .await
+            .unwrap();
+        tx.send(frame(1, 2, Duration::ZERO)).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(delivered(&sink), vec![1, 2]);
+
+        drop(tx);
+        render.await.unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+/*
+   Just enough DNS (RFC 1035) for mDNS service discovery (RFC 6762, RFC 6763).
+
+       0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
+      +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+      |                      ID                       |
+      +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+      |QR|   Opcode  |AA|TC|RD|RA|   Z    |   RCODE   |
+      +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+      |        QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT     |
+      +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+
+   Then the questions, then every record. Answers, authority and additional records
+   all go in `records` here, mDNS doesn't much care which section something came in.
+   Only A, PTR, SRV and TXT are understood, anything else is kept as Other and skipped.
+   Names are written out in full, compression pointers are only followed on the way in.
+*/
+
+use std::net::Ipv4Addr;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+
+/// top bit of a record's class, "forget anything else you had for this name" (RFC 6762 10.2)
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// QR and AA, an authoritative response
+const RESPONSE_FLAGS: u16 = 0x8400;
+
+/// a name can't point back more times than this, stops pointer loops
+const MAX_POINTERS: usize = 16;
+
+/// most questions or records to make room for up front, the counts come from whoever sent the packet
+const MAX_RESERVED: usize = 64;
+
+pub struct Question {
+    pub name: String,
+    pub record_type: u16,
+}
+
+pub enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { port: u16, target: String },
+    Other,
+}
+
+pub struct Record {
+    pub name: String,
+    /// zero says the record's going away (RFC 6762 10.1)
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+pub struct DnsMessage {
+    pub response: bool,
+    pub questions: Vec<Question>,
+    pub records: Vec<Record>,
+}
+
+impl DnsMessage {
+    pub fn serialize(&self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(512);
+
+        buf.put_u16(0); // mDNS ids are always 0
+        buf.put_u16(if self.response { RESPONSE_FLAGS } else { 0 });
+        buf.put_u16(self.questions.len() as u16);
+        buf.put_u16(self.records.len() as u16);
+        buf.put_u16(0);
+        buf.put_u16(0);
+
+        for question in &self.questions {
+            put_name(&mut buf, &question.name);
+            buf.put_u16(question.record_type);
+            buf.put_u16(CLASS_IN);
+        }
+
+        for record in &self.records {
+            put_name(&mut buf, &record.name);
+
+            let mut data = BytesMut::new();
+            let (record_type, class) = match &record.data {
+                RecordData::A(ip) => {
+                    data.put_slice(&ip.octets());
+                    (TYPE_A, CLASS_IN | CACHE_FLUSH)
+                }
+                // shared, lots of people answer for the same service type
+                RecordData::Ptr(target) => {
+                    put_name(&mut data, target);
+                    (TYPE_PTR, CLASS_IN)
+                }
+                RecordData::Txt(entries) => {
+                    for entry in entries {
+                        data.put_u8(entry.len().min(255) as u8);
+                        data.put_slice(&entry.as_bytes()[..entry.len().min(255)]);
+                    }
+                    (TYPE_TXT, CLASS_IN | CACHE_FLUSH)
+                }
+                RecordData::Srv { port, target } => {
+                    data.put_u16(0); // priority
+                    data.put_u16(0); // weight
+                    data.put_u16(*port);
+                    put_name(&mut data, target);
+                    (TYPE_SRV, CLASS_IN | CACHE_FLUSH)
+                }
+                RecordData::Other => continue,
+            };
+
+            buf.put_u16(record_type);
+            buf.put_u16(class);
+            buf.put_u32(record.ttl);
+            buf.put_u16(data.len() as u16);
+            buf.put(data);
+        }
+
+        buf
+    }
+
+    /// None if it's cut short or a name doesn't make sense
+    pub fn deserialize(packet: &[u8]) -> Option<Self> {
+        let mut reader = packet;
+        if reader.remaining() < 12 {
+            return None;
+        }
+
+        reader.advance(2);
+        let flags = reader.get_u16();
+        let question_count = reader.get_u16();
+        let record_count =
+            reader.get_u16() as usize + reader.get_u16() as usize + reader.get_u16() as usize;
+
+        let mut questions = Vec::with_capacity((question_count as usize).min(MAX_RESERVED));
+        for _ in 0..question_count {
+            let name = read_name(packet, &mut reader)?;
+            if reader.remaining() < 4 {
+                return None;
+            }
+
+            let record_type = reader.get_u16();
+            reader.advance(2); // class, and the unicast-response bit
+            questions.push(Question { name, record_type });
+        }
+
+        let mut records = Vec::with_capacity(record_count.min(MAX_RESERVED));
+        for _ in 0..record_count {
+            let name = read_name(packet, &mut reader)?;
+            if reader.remaining() < 10 {
+                return None;
+            }
+
+            let record_type = reader.get_u16();
+            reader.advance(2); // class
+            let ttl = reader.get_u32();
+            let length = reader.get_u16() as usize;
+
+            if reader.remaining() < length {
+                return None;
+            }
+
+            // names in the data can point anywhere in the packet, so they read from there
+            let data_start = packet.len() - reader.remaining();
+            let mut data = &packet[data_start..data_start + length];
+            reader.advance(length);
+
+            let data = match record_type {
+                TYPE_A if length == 4 => {
+                    RecordData::A(Ipv4Addr::new(data[0], data[1], data[2], data[3]))
+                }
+                TYPE_PTR => RecordData::Ptr(read_name(packet, &mut data)?),
+                TYPE_TXT => {
+                    let mut entries = Vec::new();
+                    while data.has_remaining() {
+                        let entry_length = (data.get_u8() as usize).min(data.remaining());
+                        entries.push(String::from_utf8_lossy(&data[..entry_length]).into_owned());
+                        data.advance(entry_length);
+                    }
+                    RecordData::Txt(entries)
+                }
+                TYPE_SRV if length >= 6 => {
+                    data.advance(4); // priority, weight
+                    let port = data.get_u16();
+                    RecordData::Srv {
+                        port,
+                        target: read_name(packet, &mut data)?,
+                    }
+                }
+                _ => RecordData::Other,
+            };
+
+            records.push(Record { name, ttl, data });
+        }
+
+        Some(Self {
+            response: flags & 0x8000 != 0,
+            questions,
+            records,
+        })
+    }
+}
+
+fn put_name(buf: &mut BytesMut, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.put_u8(label.len() as u8);
+        buf.put_slice(label);
+    }
+
+    buf.put_u8(0);
+}
+
+/// Reads a name at `reader`, following compression pointers into `packet`
+fn read_name<'a>(packet: &'a [u8], reader: &mut &'a [u8]) -> Option<String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut cursor = *reader;
+    let mut pointers = 0;
+
+    loop {
+        if !cursor.has_remaining() {
+            return None;
+        }
+
+        let length = cursor.get_u8();
+
+        match length {
+            0 => break,
+            // the top two bits set, the rest is an offset to carry on from
+            0xC0..=0xFF => {
+                if !cursor.has_remaining() || pointers == MAX_POINTERS {
+                    return None;
+                }
+
+                let offset = ((length as usize & 0x3F) << 8) | cursor.get_u8() as usize;
+
+                // the name continues somewhere else, whatever's after the pointer isn't part of it
+                if pointers == 0 {
+                    *reader = cursor;
+                }
+                pointers += 1;
+
+                cursor = packet.get(offset..)?;
+            }
+            _ => {
+                let length = length as usize;
+                if cursor.remaining() < length {
+                    return None;
+                }
+
+                labels.push(String::from_utf8_lossy(&cursor[..length]).into_owned());
+                cursor.advance(length);
+            }
+        }
+    }
+
+    if pointers == 0 {
+        *reader = cursor;
+    }
+
+    Some(labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_round_trip() {
+        let message = DnsMessage {
+            response: true,
+            questions: vec![],
+            records: vec![
+                Record {
+                    name: "_rtpcall._tcp.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::Ptr("abc._rtpcall._tcp.local".to_string()),
+                },
+                Record {
+                    name: "abc._rtpcall._tcp.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::Srv {
+                        port: 4000,
+                        target: "abc.local".to_string(),
+                    },
+                },
+                Record {
+                    name: "abc._rtpcall._tcp.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::Txt(vec!["room=standup".to_string()]),
+                },
+                Record {
+                    name: "abc.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::A(Ipv4Addr::new(10, 0, 0, 1)),
+                },
+            ],
+        };
+
+        let parsed = DnsMessage::deserialize(&message.serialize()).unwrap();
+
+        assert!(parsed.response);
+        assert_eq!(parsed.records.len(), 4);
+        assert!(matches!(
+            &parsed.records[1].data,
+            RecordData::Srv { port: 4000, target } if target == "abc.local"
+        ));
+        assert!(matches!(
+            &parsed.records[2].data,
+            RecordData::Txt(entries) if entries == &["room=standup".to_string()]
+        ));
+        assert!(matches!(
+            parsed.records[3].data,
+            RecordData::A(ip) if ip == Ipv4Addr::new(10, 0, 0, 1)
+        ));
+    }
+
+    #[test]
+    fn follows_compressed_names() {
+        #[rustfmt::skip]
+        let packet = [
+            0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0,
+            // "_rtpcall._tcp.local", PTR, IN, TTL 120
+            8, b'_', b'r', b't', b'p', b'c', b'a', b'l', b'l',
+            4, b'_', b't', b'c', b'p', 5, b'l', b'o', b'c', b'a', b'l', 0,
+            0, 12, 0, 1, 0, 0, 0, 120,
+            // "abc" then a pointer back to the service name at offset 12
+            0, 6, 3, b'a', b'b', b'c', 0xC0, 12,
+        ];
+
+        let parsed = DnsMessage::deserialize(&packet).unwrap();
+
+        assert!(matches!(
+            &parsed.records[0].data,
+            RecordData::Ptr(target) if target == "abc._rtpcall._tcp.local"
+        ));
+
+        // pointing at itself forever is junk, not a hang
+        let mut looping = packet;
+        looping[packet.len() - 1] = (packet.len() - 2) as u8;
+        assert!(DnsMessage::deserialize(&looping).is_none());
+    }
+
+    #[test]
+    fn counts_past_the_end_are_junk() {
+        // says 65535 questions and answers each, has none
+        let packet = [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0];
+
+        assert!(DnsMessage::deserialize(&packet).is_none());
+    }
+}
@@ -2,9 +2,13 @@ use crate::packets::rtp::rtp::RTPHeader;
 use rand::Rng;
 use std::{
     net::SocketAddr,
-    sync::atomic::{AtomicU16, AtomicU32, Ordering},
+    sync::{
+        Mutex,
+        atomic::{AtomicU16, AtomicU32, Ordering},
+    },
 };
 
+pub mod dns;
 pub mod rtcp;
 pub mod rtp;
 
@@ -14,11 +18,19 @@ pub struct RTPSession {
     octets_sent: AtomicU32, // this is going to be same for every peer
 
     pub ssrc: u32,
-    pub local_addr: SocketAddr,
+
+    /// changes if we move networks, the SSRC doesn't
+    local_addr: Mutex<SocketAddr>,
+
+    /// RTP timestamp units per second, 48kHz for Opus, 90kHz for video
+    pub clock_rate: u32,
+
+    /// added to every media timestamp, RFC 3550 says the first one should be random
+    timestamp_offset: u32,
 }
 
 impl RTPSession {
-    pub fn new(local_addr: SocketAddr) -> Self {
+    pub fn new(local_addr: SocketAddr, clock_rate: u32) -> Self {
         let mut rng = rand::rng();
 
         Self {
@@ -26,10 +38,26 @@ impl RTPSession {
             current_sequence_num: AtomicU16::new(0),
             packets_generated: AtomicU32::new(0),
             ssrc: rng.next_u32(), // there is a non-zero chance that SSRCs can colide...
-            local_addr,
+            local_addr: Mutex::new(local_addr),
+            clock_rate,
+            timestamp_offset: rng.next_u32(),
         }
     }
 
+    pub fn local_addr(&self) -> SocketAddr {
+        *self.local_addr.lock().unwrap()
+    }
+
+    pub fn set_local_addr(&self, local_addr: SocketAddr) {
+        *self.local_addr.lock().unwrap() = local_addr;
+    }
+
+    /// Media clock time (see media_clock.rs) to what goes on the wire,
+    /// sender reports have to go through this too or they won't line up with the packets
+    pub fn rtp_timestamp(&self, media_time: u32) -> u32 {
+        media_time.wrapping_add(self.timestamp_offset)
+    }
+
     pub fn get_packet(&self, is_last_unit: bool, timestamp: u32, packet_length: u32) -> RTPHeader {
         self.current_sequence_num.fetch_add(1, Ordering::Relaxed);
         self.packets_generated.fetch_add(1, Ordering::Relaxed);
@@ -42,7 +70,7 @@ impl RTPSession {
             marker: is_last_unit,
             payload_type: 0,
             sequence_number: self.current_sequence_num.load(Ordering::Relaxed),
-            timestamp: timestamp,
+            timestamp: self.rtp_timestamp(timestamp),
             ssrc: self.ssrc,
             // csrc:
         }
@@ -56,3 +84,33 @@ impl RTPSession {
         self.octets_sent.load(Ordering::Relaxed)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_start_at_a_random_timestamp() {
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let first = RTPSession::new(addr, 90_000);
+        let second = RTPSession::new(addr, 90_000);
+
+        // 1 in 2^32 of a false failure
+        assert_ne!(first.rtp_timestamp(0), second.rtp_timestamp(0));
+    }
+
+    #[test]
+    fn packets_and_sender_reports_share_the_offset() {
+        let session = RTPSession::new("127.0.0.1:5000".parse().unwrap(), 48_000);
+
+        // ten seconds in on a 48kHz clock
+        let media_time = 480_000;
+
+        let header = session.get_packet(true, media_time, 100);
+        assert_eq!(header.timestamp, session.rtp_timestamp(media_time));
+
+        // an offset near the top still wraps instead of panicking
+        let header = session.get_packet(true, u32::MAX, 100);
+        assert_eq!(header.timestamp, session.rtp_timestamp(0).wrapping_sub(1));
+    }
+}
@@ -0,0 +1,53 @@
+/*
+   BYE, RFC 3550 6.6. We're leaving, stop expecting these sources.
+   (the optional reason isn't sent, and gets skipped over on the way in)
+
+       0                   1                   2                   3
+       0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+      +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+      |V=2|P|    SC   |   PT=BYE=203  |             length            |
+      +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+      |                           SSRC/CSRC                           |
+      +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+      :                              ...                              :
+      +=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+
+*/
+
+use bytes::{Buf, BufMut, BytesMut};
+
+pub struct Goodbye {
+    pub sources: Vec<u32>,
+}
+
+impl Goodbye {
+    pub fn serialize(&self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(self.length() as usize);
+
+        for source in &self.sources {
+            buf.put_u32(*source);
+        }
+
+        buf
+    }
+
+    /// `length` is the whole body, anything past the sources is the reason
+    pub fn deserialize(packet: &mut BytesMut, source_count: u8, length: u16) -> Self {
+        let length = (length as usize).min(packet.remaining());
+        let mut body = packet.split_to(length);
+
+        let mut sources = Vec::with_capacity(source_count as usize);
+        for _ in 0..source_count {
+            if body.remaining() < 4 {
+                break;
+            }
+
+            sources.push(body.get_u32());
+        }
+
+        Goodbye { sources }
+    }
+
+    pub fn length(&self) -> u16 {
+        (self.sources.len() * 4) as u16
+    }
+}
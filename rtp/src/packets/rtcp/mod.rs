@@ -1,54 +1,78 @@
+pub mod goodbye;
 pub mod reception_report;
 pub mod rtcp_header;
 pub mod sender_report;
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::SystemTime;
 
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use rand::RngExt;
 use tokio::io;
-use tokio::net::UdpSocket;
 use tokio::time::{Duration, sleep};
+use tokio_util::sync::CancellationToken;
 
-use crate::interop::StreamType;
+use crate::interop::error::{Error, ErrorReporter, RtpError};
+use crate::packets::rtcp::goodbye::Goodbye;
 use crate::packets::rtcp::rtcp_header::{PacketType, RTCPHeader};
 use crate::packets::rtcp::sender_report::SenderReport;
-use crate::{interop::runtime, session_management::peer_manager::PeerManager};
-
-unsafe extern "C" {
-    fn swift_send_cmclocktime() -> f64;
-}
+use crate::session_management::media_clock::MediaClock;
+use crate::session_management::network_monitor::SwappableSocket;
+use crate::session_management::peer_manager::PeerManager;
 
+/// Runs until `shutdown`, then says BYE to everyone on the way out.
+/// Failures go to `errors`. If receiving fails, sending still carries on until `shutdown`
 pub async fn start_rtcp(
-    socket: UdpSocket,
+    socket: Arc<SwappableSocket>,
     peer_manager: Arc<PeerManager>,
-    stream_type: StreamType,
+    clock: Arc<dyn MediaClock>,
+    errors: Arc<ErrorReporter>,
+    shutdown: CancellationToken,
 ) {
-    let socket = Arc::new(socket);
-
-    let socket_clone = Arc::clone(&socket);
-    let peer_manager_clone = Arc::clone(&peer_manager);
-    runtime().spawn(async move {
-        rtcp_sender(socket_clone, peer_manager_clone, stream_type).await;
-    });
-
-    if let Err(e) = rtcp_receiver(socket, peer_manager).await {
-        eprintln!("Something wrong with RTCP socket. Check: {}", e)
+    let receiver = async {
+        tokio::select! {
+            received = rtcp_receiver(Arc::clone(&socket), Arc::clone(&peer_manager)) => {
+                if let Err(e) = received {
+                    errors.report(Error::new(
+                        RtpError::Network,
+                        format!("RTCP socket stopped receiving: {}", e),
+                    ));
+                }
+            }
+            _ = shutdown.cancelled() => {}
+        }
     };
+
+    // joined rather than spawned, whoever owns this future owns both halves
+    tokio::join!(
+        rtcp_sender(
+            Arc::clone(&socket),
+            Arc::clone(&peer_manager),
+            clock,
+            Arc::clone(&errors),
+            shutdown.clone()
+        ),
+        receiver,
+    );
 }
 
+/// seconds between reports, before the randomization (RFC 3550 6.2 says at least 5)
+const REPORT_INTERVAL: f64 = 5.0;
+
+/// reporting intervals without a word before a peer counts as gone (RFC 3550 6.3.5)
+const INACTIVE_INTERVALS: f64 = 5.0;
+
 async fn rtcp_sender(
-    socket: Arc<UdpSocket>,
+    socket: Arc<SwappableSocket>,
     peer_manager: Arc<PeerManager>,
-    stream_type: StreamType,
+    clock: Arc<dyn MediaClock>,
+    errors: Arc<ErrorReporter>,
+    shutdown: CancellationToken,
 ) {
     let mut first_packet = true;
 
-    let clock_rate: f64 = match stream_type {
-        StreamType::Audio => 0.,
-        StreamType::Video => 90000.,
-    };
+    let clock_rate = peer_manager.rtp_session.clock_rate;
 
     loop {
         // RTCP bandwidith = 5% bit rate of a single stream of audio or video data
@@ -58,7 +82,7 @@ async fn rtcp_sender(
         // The interval is how long to wait between sending RTCP packets
         // When more than 25% of the participants are senders:
         // Interval = average RTCP size * total number of members / RTCP bandwidth
-        let mut interval = 5.0; // i'm just defaulting to 5 for now.
+        let mut interval = REPORT_INTERVAL; // i'm just defaulting to 5 for now.
 
         // choose the minimum interval if the calculated interval is less
         // if interval < 5.0 {
@@ -78,7 +102,17 @@ async fn rtcp_sender(
         }
 
         // wait for packet time
-        sleep(Duration::from_secs_f64(interval)).await;
+        tokio::select! {
+            _ = sleep(Duration::from_secs_f64(interval)) => {}
+            _ = shutdown.cancelled() => {
+                send_goodbye(&socket, &peer_manager, &errors).await;
+                return;
+            }
+        }
+
+        // nothing from them in a while, they probably crashed or lost their connection
+        let inactive_timeout = Duration::from_secs_f64(INACTIVE_INTERVALS * REPORT_INTERVAL);
+        peer_manager.remove_inactive(inactive_timeout);
 
         let peers = peer_manager.get_peers();
 
@@ -95,7 +129,9 @@ async fn rtcp_sender(
         let sender_report = SenderReport {
             ssrc: peer_manager.local_ssrc(),
             ntp_time: ntp,
-            rtp_time: unsafe { (swift_send_cmclocktime() * clock_rate) as u32 },
+            rtp_time: peer_manager
+                .rtp_session
+                .rtp_timestamp(clock.rtp_time(clock_rate)),
             packet_count: peer_manager.rtp_session.get_num_packets_generated(),
             octet_count: peer_manager.rtp_session.get_num_octets_sent(),
             reports: peer_manager.get_reception_reports(),
@@ -114,19 +150,56 @@ async fn rtcp_sender(
         packet.put(header.serialize());
         packet.put(sender_report.serialize());
 
-        for addr in peers {
-            let rtcp_port = addr.port() + 1;
-            let peer_ip = format!("{}:{}", addr.ip(), rtcp_port);
+        send_to_peers(&socket, &peers, &packet, &errors).await;
+    }
+}
 
-            match socket.send_to(&packet, peer_ip).await {
-                Ok(_) => {}
-                Err(e) => eprintln!("Failed to send RTCP to {}: {}", addr, e),
-            }
+async fn send_goodbye(
+    socket: &SwappableSocket,
+    peer_manager: &PeerManager,
+    errors: &ErrorReporter,
+) {
+    let goodbye = Goodbye {
+        sources: vec![peer_manager.local_ssrc()],
+    };
+
+    let header = RTCPHeader {
+        padding: false,
+        packet_type: PacketType::Goodbye,
+        count: goodbye.sources.len() as u8,
+        length: goodbye.length(),
+    };
+
+    let mut packet = BytesMut::with_capacity(4 + goodbye.length() as usize);
+    packet.put(header.serialize());
+    packet.put(goodbye.serialize());
+
+    send_to_peers(socket, &peer_manager.get_peers(), &packet, errors).await;
+}
+
+async fn send_to_peers(
+    socket: &SwappableSocket,
+    peers: &[SocketAddr],
+    packet: &[u8],
+    errors: &ErrorReporter,
+) {
+    for addr in peers {
+        let rtcp_port = addr.port() + 1;
+        let peer_ip = format!("{}:{}", addr.ip(), rtcp_port);
+
+        if let Err(e) = socket.send_to(packet, peer_ip).await {
+            errors.report(Error::new(
+                RtpError::Network,
+                format!("Failed to send RTCP to {}: {}", addr, e),
+            ));
         }
     }
 }
 
-async fn rtcp_receiver(socket: Arc<UdpSocket>, peer_manager: Arc<PeerManager>) -> io::Result<()> {
+async fn rtcp_receiver(
+    socket: Arc<SwappableSocket>,
+    peer_manager: Arc<PeerManager>,
+) -> io::Result<()> {
     /*
        TODO:
        while packet
@@ -147,7 +220,8 @@ async fn rtcp_receiver(socket: Arc<UdpSocket>, peer_manager: Arc<PeerManager>) -
         let mut packet = BytesMut::with_capacity(bytes_read);
         packet.put(&buffer[..bytes_read]);
 
-        while packet.len() > 0 {
+        // anything shorter than a header is junk
+        while packet.len() >= 4 {
             let rtcp_header = RTCPHeader::deserialize(&mut packet);
 
             match rtcp_header.packet_type {
@@ -157,15 +231,33 @@ async fn rtcp_receiver(socket: Arc<UdpSocket>, peer_manager: Arc<PeerManager>) -
                     let last_sr_timestamp = (sender_report.ntp_time >> 16 & 0xFFFFFFFF) as u32;
 
                     peer_manager.update_last_sr_timestamp(sender_report.ssrc, last_sr_timestamp);
+                    peer_manager.update_sender_clock(
+                        sender_report.ssrc,
+                        sender_report.ntp_time,
+                        sender_report.rtp_time,
+                    );
 
                     for report in sender_report.reports {
-                        println!(
-                            "{}: Jitter {}, {}",
-                            report.reportee_ssrc, report.jitter, report.extended_sequence_number
-                        )
+                        // how our own stream is doing on their end, audio redundancy follows it
+                        if report.reportee_ssrc == peer_manager.local_ssrc() {
+                            peer_manager
+                                .update_reported_loss(sender_report.ssrc, report.fraction_lost);
+                        }
                     }
                 }
-                _ => {}
+                PacketType::Goodbye => {
+                    let goodbye =
+                        Goodbye::deserialize(&mut packet, rtcp_header.count, rtcp_header.length);
+
+                    for source in goodbye.sources {
+                        peer_manager.remove_peer(source);
+                    }
+                }
+                _ => {
+                    // skip it, otherwise we'd read its body as the next header
+                    let length = (rtcp_header.length as usize).min(packet.remaining());
+                    packet.advance(length);
+                }
             }
         }
     }
@@ -4,6 +4,9 @@ use crate::packets::RTPSession;
 
 const AVCC_HEADER_LENGTH: usize = 4;
 
+/// dynamic, RFC 6184 doesn't get a static one. Same as what ffmpeg and gstreamer default to
+pub const H264_PAYLOAD_TYPE: u8 = 96;
+
 pub fn get_fragments(
     payload: &[u8],
     rtp_session: &RTPSession,
@@ -21,7 +24,8 @@ pub fn get_fragments(
     let nalu_type = payload[0] & 0x1F;
 
     if payload.len() <= max_fragment_size {
-        let rtp_header = rtp_session.get_packet(is_last_unit, timestamp, payload.len() as u32);
+        let mut rtp_header = rtp_session.get_packet(is_last_unit, timestamp, payload.len() as u32);
+        rtp_header.payload_type = H264_PAYLOAD_TYPE;
 
         let mut out = rtp_header.serialize();
         out.extend_from_slice(payload);
@@ -33,13 +37,13 @@ pub fn get_fragments(
     while nalu_data_remaining > 0 {
         let current_fragment_size = std::cmp::min(max_fragment_size, nalu_data_remaining);
 
-        let rtp_header = rtp_session
-            .get_packet(
-                is_last_unit && max_fragment_size >= nalu_data_remaining, // VERY last one
-                timestamp,
-                current_fragment_size as u32 + 2,
-            )
-            .serialize(); // this will move the sequence number by 1
+        let mut rtp_header = rtp_session.get_packet(
+            is_last_unit && max_fragment_size >= nalu_data_remaining, // VERY last one
+            timestamp,
+            current_fragment_size as u32 + 2,
+        ); // this will move the sequence number by 1
+        rtp_header.payload_type = H264_PAYLOAD_TYPE;
+        let rtp_header = rtp_header.serialize();
 
         let mut out = BytesMut::with_capacity(2 + current_fragment_size + rtp_header.len());
 
@@ -148,6 +152,15 @@ pub fn get_nal_units(data: &[u8]) -> Vec<&[u8]> {
     nal_units
 }
 
+/// Whether an RTP payload (header already stripped) starts and/or ends a NAL unit.
+/// Single NAL unit packets do both, FU-A fragments carry it in the S and E bits.
+pub fn nal_boundaries(payload: &[u8]) -> (bool, bool) {
+    match payload {
+        [b0, b1, ..] if b0 & 0x1F == 28 => (b1 & 0x80 != 0, b1 & 0x40 != 0),
+        _ => (true, true),
+    }
+}
+
 pub fn rtp_to_avcc_h264(packets: Vec<Bytes>) -> BytesMut {
     let mut payload = BytesMut::with_capacity(packets.iter().map(|p| p.len() + 4).sum());
     let mut fua_buffer = BytesMut::new();
@@ -1,2 +1,4 @@
 pub mod h264;
+pub mod opus;
+pub mod red;
 pub mod rtp;
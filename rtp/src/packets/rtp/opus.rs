@@ -0,0 +1,229 @@
+/*
+    RTP payload format for Opus, RFC 7587:
+    https://www.rfc-editor.org/rfc/rfc7587
+
+    One Opus packet per RTP packet, no payload header.
+    The RTP clock is always 48kHz, whatever rate the encoder actually runs at.
+*/
+
+use crate::session_management::peer_manager::compare_timestamps;
+use std::cmp::Ordering;
+
+pub const OPUS_CLOCK_RATE: u32 = 48_000;
+
+/// dynamic, whatever everybody else uses
+pub const OPUS_PAYLOAD_TYPE: u8 = 111;
+
+/// Number of 48kHz samples an Opus packet covers, read off its TOC byte (RFC 6716, 3.1)
+///
+///  0 1 2 3 4 5 6 7
+/// +-+-+-+-+-+-+-+-+
+/// | config  |s| c |
+/// +-+-+-+-+-+-+-+-+
+pub fn packet_samples(packet: &[u8]) -> Option<u32> {
+    let toc = *packet.first()?;
+    let config = (toc >> 3) as usize;
+
+    let frame_samples = match config {
+        0..=11 => [480, 960, 1920, 2880][config % 4], // SILK, 10/20/40/60 ms
+        12..=15 => [480, 960][config % 2],            // Hybrid, 10/20 ms
+        _ => [120, 240, 480, 960][config % 4],        // CELT, 2.5/5/10/20 ms
+    };
+
+    let frame_count = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3F) as u32, // code 3, count is in the next byte
+    };
+
+    let samples = frame_samples * frame_count;
+
+    // a packet has at least one frame and never more than 120 ms of them (RFC 6716, 3.2.5)
+    if samples == 0 || samples > 5760 {
+        return None;
+    }
+
+    Some(samples)
+}
+
+/// Keeps track of where the next packet should land,
+/// so the first packet after silence (DTX or the mic starting up) gets the marker bit.
+pub struct OpusPayloader {
+    next_timestamp: Option<u32>,
+}
+
+impl OpusPayloader {
+    pub fn new() -> Self {
+        Self {
+            next_timestamp: None,
+        }
+    }
+
+    /// `timestamp` has to be in 48kHz units already
+    pub fn starts_talkspurt(&mut self, timestamp: u32, packet: &[u8]) -> bool {
+        let talkspurt = match self.next_timestamp {
+            None => true,
+            Some(expected) => compare_timestamps(timestamp, expected) == Ordering::Greater,
+        };
+
+        self.next_timestamp = packet_samples(packet).map(|samples| timestamp.wrapping_add(samples));
+
+        talkspurt
+    }
+}
+
+impl Default for OpusPayloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// past this much missing audio, concealment just sounds worse than silence (120 ms)
+pub const MAX_CONCEALMENT_SAMPLES: u32 = 5760;
+
+/// Receive side counterpart, works out how much audio went missing before each packet
+pub struct OpusDepayloader {
+    next_timestamp: Option<u32>,
+}
+
+impl OpusDepayloader {
+    pub fn new() -> Self {
+        Self {
+            next_timestamp: None,
+        }
+    }
+
+    /// Samples (48kHz) lost between the last packet and this one.
+    /// A talkspurt start (marker bit) jumps over silence on purpose, so nothing's missing then.
+    pub fn missing_samples(&mut self, timestamp: u32, packet: &[u8], talkspurt: bool) -> u32 {
+        let missing = match self.next_timestamp {
+            Some(expected)
+                if !talkspurt && compare_timestamps(timestamp, expected) == Ordering::Greater =>
+            {
+                timestamp.wrapping_sub(expected)
+            }
+            _ => 0,
+        };
+
+        self.next_timestamp = packet_samples(packet).map(|samples| timestamp.wrapping_add(samples));
+
+        missing
+    }
+}
+
+impl Default for OpusDepayloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CELT 20 ms frames, `code` is the frame count code
+    fn toc(code: u8) -> u8 {
+        (31 << 3) | code
+    }
+
+    #[test]
+    fn frame_count_codes() {
+        assert_eq!(packet_samples(&[toc(0), 0xAA]), Some(960));
+        assert_eq!(packet_samples(&[toc(1), 0xAA]), Some(1920));
+        assert_eq!(packet_samples(&[toc(2), 0x01, 0xAA]), Some(1920));
+        assert_eq!(packet_samples(&[toc(3), 3, 0xAA]), Some(2880));
+
+        // SILK 60 ms, and the count byte's top bits (VBR, padding) don't count
+        assert_eq!(packet_samples(&[(3 << 3) | 3, 0xC2]), Some(5760));
+    }
+
+    #[test]
+    fn malformed_packets_have_no_length() {
+        assert_eq!(packet_samples(&[]), None);
+
+        // code 3 without its count byte
+        assert_eq!(packet_samples(&[toc(3)]), None);
+
+        // zero frames
+        assert_eq!(packet_samples(&[toc(3), 0]), None);
+
+        // 7 * 20 ms, over the 120 ms a packet can hold
+        assert_eq!(packet_samples(&[toc(3), 7]), None);
+    }
+
+    #[test]
+    fn talkspurts_start_after_silence() {
+        let frame = [toc(0)];
+        let mut payloader = OpusPayloader::new();
+
+        assert!(payloader.starts_talkspurt(1000, &frame));
+        assert!(!payloader.starts_talkspurt(1960, &frame));
+        assert!(!payloader.starts_talkspurt(2920, &frame));
+
+        // DTX skipped a few frames
+        assert!(payloader.starts_talkspurt(2920 + 960 * 5, &frame));
+
+        // late or repeated timestamps aren't a new talkspurt
+        assert!(!payloader.starts_talkspurt(2920 + 960 * 5, &frame));
+    }
+
+    #[test]
+    fn talkspurts_across_wraparound() {
+        let frame = [toc(0)];
+        let mut payloader = OpusPayloader::new();
+
+        assert!(payloader.starts_talkspurt(u32::MAX - 959, &frame));
+        assert!(!payloader.starts_talkspurt(0, &frame));
+        assert!(payloader.starts_talkspurt(960 * 3, &frame));
+    }
+
+    #[test]
+    fn lost_packets_show_up_as_missing_samples() {
+        let frame = [toc(0)];
+        let mut depayloader = OpusDepayloader::new();
+
+        assert_eq!(depayloader.missing_samples(0, &frame, false), 0);
+        assert_eq!(depayloader.missing_samples(960, &frame, false), 0);
+
+        // two frames never made it
+        assert_eq!(depayloader.missing_samples(960 * 4, &frame, false), 960 * 2);
+
+        // a duplicate or a straggler isn't a gap
+        assert_eq!(depayloader.missing_samples(960 * 2, &frame, false), 0);
+    }
+
+    #[test]
+    fn talkspurts_arent_missing_anything() {
+        let frame = [toc(0)];
+        let mut depayloader = OpusDepayloader::new();
+
+        depayloader.missing_samples(0, &frame, true);
+        assert_eq!(depayloader.missing_samples(960 * 50, &frame, true), 0);
+        assert_eq!(depayloader.missing_samples(960 * 52, &frame, false), 960);
+    }
+
+    #[test]
+    fn missing_samples_across_wraparound() {
+        let frame = [toc(0)];
+        let mut depayloader = OpusDepayloader::new();
+
+        depayloader.missing_samples(u32::MAX - 959, &frame, false);
+        assert_eq!(depayloader.missing_samples(0, &frame, false), 0);
+
+        let mut depayloader = OpusDepayloader::new();
+
+        depayloader.missing_samples(u32::MAX - 959, &frame, false);
+        assert_eq!(depayloader.missing_samples(960, &frame, false), 960);
+    }
+
+    #[test]
+    fn unreadable_packets_dont_make_up_a_gap() {
+        let mut depayloader = OpusDepayloader::new();
+
+        depayloader.missing_samples(0, &[toc(0)], false);
+        depayloader.missing_samples(960, &[], false);
+
+        // no idea where the empty one ended, so nothing to blame on the network
+        assert_eq!(depayloader.missing_samples(960 * 5, &[toc(0)], false), 0);
+    }
+}
@@ -0,0 +1,278 @@
+/*
+    RTP payload for redundant audio data (RED), RFC 2198:
+    https://www.rfc-editor.org/rfc/rfc2198
+
+    Every packet carries the current frame plus copies of the last few,
+    so a single lost packet can be filled in from the one after it.
+
+    redundant block header:
+     0                   1                   2                   3
+     0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    |F|   block PT  |  timestamp offset         |   block length    |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+
+    primary block header (always last):
+     0 1 2 3 4 5 6 7
+    +-+-+-+-+-+-+-+-+
+    |0|   Block PT  |
+    +-+-+-+-+-+-+-+-+
+
+    then the block data, oldest redundant block first, primary last.
+*/
+
+use std::collections::VecDeque;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+pub const RED_PAYLOAD_TYPE: u8 = 63;
+
+/// RFC 2198 never needs more than a couple, past this the packets just get fat
+pub const MAX_REDUNDANCY_DEPTH: u8 = 2;
+
+const MAX_TIMESTAMP_OFFSET: u32 = (1 << 14) - 1;
+const MAX_BLOCK_LENGTH: usize = (1 << 10) - 1;
+
+pub struct RedBlock {
+    pub payload_type: u8,
+    pub timestamp: u32,
+
+    /// how many packets before the primary this block was originally sent in, 0 for the primary
+    pub distance: u16,
+    pub data: Bytes,
+}
+
+/// How much redundancy to send for the loss our peers are reporting.
+/// `fraction_lost` is the 8 bit fixed point value out of a reception report.
+pub fn depth_for_loss(fraction_lost: u8, max_depth: u8) -> u8 {
+    let depth = match fraction_lost {
+        0..=5 => 0,  // under ~2%, FEC handles it
+        6..=25 => 1, // under ~10%
+        _ => 2,
+    };
+
+    depth.min(max_depth).min(MAX_REDUNDANCY_DEPTH)
+}
+
+pub struct RedEncoder {
+    /// the last few frames sent, newest at the back
+    history: VecDeque<(u32, Bytes)>,
+}
+
+impl RedEncoder {
+    pub fn new() -> Self {
+        Self {
+            history: VecDeque::with_capacity(MAX_REDUNDANCY_DEPTH as usize + 1),
+        }
+    }
+
+    /// Builds a RED payload with up to `depth` earlier frames in front of `primary`.
+    /// The frame is remembered either way, so turning redundancy back on has something to send.
+    pub fn encode(
+        &mut self,
+        payload_type: u8,
+        timestamp: u32,
+        primary: &Bytes,
+        depth: u8,
+    ) -> Bytes {
+        let redundant: Vec<&(u32, Bytes)> = self
+            .history
+            .iter()
+            .rev()
+            .take(depth as usize)
+            // stop at the first one that doesn't fit, the receiver counts blocks back from the primary
+            .take_while(|(previous, data)| {
+                timestamp.wrapping_sub(*previous) <= MAX_TIMESTAMP_OFFSET
+                    && data.len() <= MAX_BLOCK_LENGTH
+            })
+            .collect();
+
+        let mut payload = BytesMut::with_capacity(
+            1 + primary.len()
+                + redundant
+                    .iter()
+                    .map(|(_, data)| 4 + data.len())
+                    .sum::<usize>(),
+        );
+
+        // oldest first
+        for (previous, data) in redundant.iter().rev() {
+            let offset = timestamp.wrapping_sub(*previous);
+
+            payload.put_u8(0x80 | payload_type);
+            payload.put_u8((offset >> 6) as u8);
+            payload.put_u8((((offset & 0x3F) << 2) as u8) | ((data.len() >> 8) as u8 & 0x3));
+            payload.put_u8(data.len() as u8);
+        }
+
+        payload.put_u8(payload_type);
+
+        for (_, data) in redundant.iter().rev() {
+            payload.put_slice(data);
+        }
+        payload.put_slice(primary);
+
+        self.history.push_back((timestamp, primary.clone()));
+        if self.history.len() > MAX_REDUNDANCY_DEPTH as usize {
+            self.history.pop_front();
+        }
+
+        payload.freeze()
+    }
+}
+
+impl Default for RedEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits a RED payload back into its blocks, oldest first, primary last.
+/// None if the headers don't add up.
+pub fn decode(mut payload: Bytes, timestamp: u32) -> Option<Vec<RedBlock>> {
+    let mut headers = Vec::new();
+
+    loop {
+        if !payload.has_remaining() {
+            return None;
+        }
+
+        let b0 = payload.get_u8();
+        let payload_type = b0 & 0x7F;
+
+        // F bit clear, that's the primary
+        if b0 & 0x80 == 0 {
+            headers.push((payload_type, 0u32, None));
+            break;
+        }
+
+        if payload.remaining() < 3 {
+            return None;
+        }
+
+        let b1 = payload.get_u8() as u32;
+        let b2 = payload.get_u8() as u32;
+        let b3 = payload.get_u8() as usize;
+
+        let offset = (b1 << 6) | (b2 >> 2);
+        let length = ((b2 as usize & 0x3) << 8) | b3;
+
+        headers.push((payload_type, offset, Some(length)));
+    }
+
+    let redundant_count = headers.len() - 1;
+    let mut blocks = Vec::with_capacity(headers.len());
+
+    for (index, (payload_type, offset, length)) in headers.into_iter().enumerate() {
+        let length = length.unwrap_or(payload.remaining());
+
+        if payload.remaining() < length {
+            return None;
+        }
+
+        blocks.push(RedBlock {
+            payload_type,
+            timestamp: timestamp.wrapping_sub(offset),
+            distance: (redundant_count - index) as u16,
+            data: payload.split_to(length),
+        });
+    }
+
+    Some(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPUS: u8 = 111;
+    const FRAME: u32 = 960;
+
+    fn frame(n: u8) -> Bytes {
+        Bytes::from(vec![n; n as usize + 1])
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let mut encoder = RedEncoder::new();
+
+        // across a timestamp wrap
+        let start = u32::MAX - FRAME;
+        encoder.encode(OPUS, start, &frame(1), 2);
+        encoder.encode(OPUS, start.wrapping_add(FRAME), &frame(2), 2);
+        let payload = encoder.encode(OPUS, start.wrapping_add(2 * FRAME), &frame(3), 2);
+
+        let blocks = decode(payload, start.wrapping_add(2 * FRAME)).unwrap();
+        assert_eq!(blocks.len(), 3);
+
+        for (block, n) in blocks.iter().zip(1..) {
+            assert_eq!(block.payload_type, OPUS);
+            assert_eq!(block.data, frame(n));
+            assert_eq!(block.distance, 3 - n as u16);
+            assert_eq!(block.timestamp, start.wrapping_add((n as u32 - 1) * FRAME));
+        }
+    }
+
+    #[test]
+    fn depth_is_capped() {
+        let mut encoder = RedEncoder::new();
+        for n in 0..4 {
+            encoder.encode(OPUS, n as u32 * FRAME, &frame(n), 0);
+        }
+
+        // only MAX_REDUNDANCY_DEPTH frames are kept
+        let deep = encoder.encode(OPUS, 4 * FRAME, &frame(4), 5);
+        assert_eq!(decode(deep, 4 * FRAME).unwrap().len(), 3);
+
+        let shallow = encoder.encode(OPUS, 5 * FRAME, &frame(5), 1);
+        assert_eq!(decode(shallow, 5 * FRAME).unwrap().len(), 2);
+
+        // just the primary header in front
+        let none = encoder.encode(OPUS, 6 * FRAME, &frame(6), 0);
+        assert_eq!(none.len(), 1 + frame(6).len());
+        assert_eq!(decode(none, 6 * FRAME).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn blocks_too_old_for_the_offset_are_left_out() {
+        let mut encoder = RedEncoder::new();
+
+        encoder.encode(OPUS, 0, &frame(1), 0);
+        encoder.encode(OPUS, 16_000, &frame(2), 0);
+
+        // 1000 back fits in 14 bits, 17000 back doesn't
+        let payload = encoder.encode(OPUS, 17_000, &frame(3), 2);
+        let blocks = decode(payload, 17_000).unwrap();
+
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].timestamp, 16_000);
+        assert_eq!(blocks[0].distance, 1);
+
+        // way past it, nothing redundant at all
+        let payload = encoder.encode(OPUS, 17_000 + MAX_TIMESTAMP_OFFSET + 1, &frame(4), 2);
+        assert_eq!(
+            decode(payload, 17_000 + MAX_TIMESTAMP_OFFSET + 1)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn malformed_headers_are_refused() {
+        // nothing at all
+        assert!(decode(Bytes::new(), 0).is_none());
+
+        // a redundant header with nothing after it
+        assert!(decode(Bytes::from_static(&[0x80 | OPUS]), 0).is_none());
+
+        // a redundant header cut short
+        assert!(decode(Bytes::from_static(&[0x80 | OPUS, 0, 0]), 0).is_none());
+
+        // headers but never a primary
+        assert!(decode(Bytes::from_static(&[0x80 | OPUS, 0, 0, 1]), 0).is_none());
+
+        // says 10 bytes of redundant data, only 2 follow
+        assert!(decode(Bytes::from_static(&[0x80 | OPUS, 0, 0, 10, OPUS, 1, 2]), 0).is_none());
+    }
+}
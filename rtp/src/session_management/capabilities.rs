@@ -0,0 +1,275 @@
+/*
+    What a participant can decode, advertised in their Join.
+
+    Everyone sends a list of codecs they take, with the highest H.264 profile and level
+    they decode and which extras (STAP-A, NACK, RTX, FEC, RED) they understand. Two
+    participants agree on the intersection: codecs both take, the lower of the two
+    profiles, features both sides have. Whatever we send a peer has to fit in that,
+    a stream that doesn't just doesn't go to them.
+
+    Anything we don't recognise (a newer codec or feature) parses as Unknown and never matches.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::session_management::signaling_protocol::StreamTypeWithArgs;
+
+/// the highest VideoToolbox decodes, High 5.2
+const OUR_H264_PROFILE_LEVEL: &str = "640034";
+
+/// what RFC 6184 says to assume when there's no profile-level-id, Baseline 3.0
+const DEFAULT_H264_PROFILE_LEVEL: &str = "42001e";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// packetization-mode 1 (RFC 6184), handed to the decoder as AVCC
+    H264,
+    Opus,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Feature {
+    /// H.264 aggregation packets (RFC 6184 5.7.1)
+    StapA,
+    /// generic NACK feedback (RFC 4585)
+    Nack,
+    /// retransmissions on their own SSRC (RFC 4588)
+    Rtx,
+    /// Opus in-band FEC (RFC 7587)
+    InbandFec,
+    /// redundant audio (RFC 2198)
+    Red,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CodecCapability {
+    pub codec: Codec,
+
+    /// H.264 only, the highest profile and level they decode, written like SDP's (RFC 6184 8.1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_level_id: Option<String>,
+
+    #[serde(default)]
+    pub features: Vec<Feature>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    pub codecs: Vec<CodecCapability>,
+}
+
+impl Capabilities {
+    /// What this build decodes
+    pub fn ours(use_inband_fec: bool) -> Self {
+        let mut audio_features = vec![Feature::Red];
+        if use_inband_fec {
+            audio_features.push(Feature::InbandFec);
+        }
+
+        Self {
+            codecs: vec![
+                CodecCapability {
+                    codec: Codec::H264,
+                    profile_level_id: Some(OUR_H264_PROFILE_LEVEL.to_string()),
+                    // no STAP-A, rtp_to_avcc_h264 drops aggregation packets
+                    features: vec![],
+                },
+                CodecCapability {
+                    codec: Codec::Opus,
+                    profile_level_id: None,
+                    features: audio_features,
+                },
+            ],
+        }
+    }
+
+    pub fn codec(&self, codec: Codec) -> Option<&CodecCapability> {
+        self.codecs
+            .iter()
+            .find(|capability| capability.codec == codec)
+    }
+
+    pub fn supports(&self, codec: Codec, feature: Feature) -> bool {
+        self.codec(codec)
+            .is_some_and(|capability| capability.features.contains(&feature))
+    }
+
+    /// What both sides can decode
+    pub fn intersect(&self, other: &Capabilities) -> Capabilities {
+        let codecs = self
+            .codecs
+            .iter()
+            .filter(|ours| ours.codec != Codec::Unknown)
+            .filter_map(|ours| {
+                let theirs = other.codec(ours.codec)?;
+
+                let profile_level_id = match ours.codec {
+                    Codec::H264 => Some(lower_profile_level(
+                        ours.profile_level_id.as_deref(),
+                        theirs.profile_level_id.as_deref(),
+                    )?),
+                    _ => None,
+                };
+
+                let features = ours
+                    .features
+                    .iter()
+                    .filter(|feature| **feature != Feature::Unknown)
+                    .filter(|feature| theirs.features.contains(feature))
+                    .copied()
+                    .collect();
+
+                Some(CodecCapability {
+                    codec: ours.codec,
+                    profile_level_id,
+                    features,
+                })
+            })
+            .collect();
+
+        Capabilities { codecs }
+    }
+
+    /// Whether a stream with this config can be decoded with these capabilities
+    pub fn decodes(&self, stream_type: &StreamTypeWithArgs) -> bool {
+        match stream_type {
+            StreamTypeWithArgs::Audio { .. } => self.codec(Codec::Opus).is_some(),
+            StreamTypeWithArgs::Video { sps, .. } => {
+                let Some(h264) = self.codec(Codec::H264) else {
+                    return false;
+                };
+
+                // profile_idc, constraint flags and level_idc, right after the NAL header
+                let Some(stream) = sps.get(1..4) else {
+                    return true; // can't tell, let the decoder have a go
+                };
+
+                let limit = h264
+                    .profile_level_id
+                    .as_deref()
+                    .unwrap_or(DEFAULT_H264_PROFILE_LEVEL);
+
+                parse_profile_level(limit)
+                    .is_some_and(|limit| fits_within([stream[0], stream[1], stream[2]], limit))
+            }
+        }
+    }
+}
+
+/// "42e01f" -> [profile_idc, constraint flags, level_idc]
+fn parse_profile_level(profile_level_id: &str) -> Option<[u8; 3]> {
+    if profile_level_id.len() != 6 {
+        return None;
+    }
+
+    let byte = |i: usize| u8::from_str_radix(profile_level_id.get(i..i + 2)?, 16).ok();
+    Some([byte(0)?, byte(2)?, byte(4)?])
+}
+
+/// Baseline < Main < High, each decoder takes the ones below it.
+/// Not quite true for full Baseline (FMO, ASO), but nobody we talk to sends it
+fn profile_rank(profile_idc: u8) -> Option<u8> {
+    match profile_idc {
+        66 => Some(0),
+        77 => Some(1),
+        100 => Some(2),
+        _ => None,
+    }
+}
+
+/// whether a stream at `stream`'s profile and level can go to a decoder that takes up to `limit`
+fn fits_within(stream: [u8; 3], limit: [u8; 3]) -> bool {
+    let profile_fits = match (profile_rank(stream[0]), profile_rank(limit[0])) {
+        (Some(stream), Some(limit)) => stream <= limit,
+        _ => stream[0] == limit[0],
+    };
+
+    profile_fits && stream[2] <= limit[2]
+}
+
+/// The lower of two H.264 limits, None if they've no profile in common
+fn lower_profile_level(ours: Option<&str>, theirs: Option<&str>) -> Option<String> {
+    let ours = parse_profile_level(ours.unwrap_or(DEFAULT_H264_PROFILE_LEVEL))?;
+    let theirs = parse_profile_level(theirs.unwrap_or(DEFAULT_H264_PROFILE_LEVEL))?;
+
+    let lower = if fits_within(ours, theirs) {
+        ours
+    } else if fits_within(theirs, ours) {
+        theirs
+    } else {
+        // ex: High 3.0 and Main 4.0 meet at Main 3.0
+        let (profile, constraints) = match (profile_rank(ours[0]), profile_rank(theirs[0])) {
+            (Some(our_rank), Some(their_rank)) if our_rank <= their_rank => (ours[0], ours[1]),
+            (Some(_), Some(_)) => (theirs[0], theirs[1]),
+            _ => return None,
+        };
+
+        [profile, constraints, ours[2].min(theirs[2])]
+    };
+
+    Some(format!("{:02x}{:02x}{:02x}", lower[0], lower[1], lower[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(sps: Vec<u8>) -> StreamTypeWithArgs {
+        StreamTypeWithArgs::Video { pps: vec![], sps }
+    }
+
+    #[test]
+    fn peers_agree_on_what_both_decode() {
+        // a small client: Opus without FEC, H.264 Baseline 3.1, and NACK
+        let theirs = Capabilities {
+            codecs: vec![
+                CodecCapability {
+                    codec: Codec::H264,
+                    profile_level_id: Some("42e01f".to_string()),
+                    features: vec![Feature::Nack, Feature::StapA],
+                },
+                CodecCapability {
+                    codec: Codec::Opus,
+                    profile_level_id: None,
+                    features: vec![Feature::Red, Feature::Nack],
+                },
+            ],
+        };
+
+        let agreed = Capabilities::ours(true).intersect(&theirs);
+
+        let h264 = agreed.codec(Codec::H264).unwrap();
+        assert_eq!(h264.profile_level_id.as_deref(), Some("42e01f"));
+        assert!(h264.features.is_empty());
+
+        assert!(agreed.supports(Codec::Opus, Feature::Red));
+        assert!(!agreed.supports(Codec::Opus, Feature::InbandFec));
+
+        // High 4.0 from VideoToolbox is too much for them, Baseline 3.1 is fine
+        assert!(!agreed.decodes(&video(vec![0x67, 0x64, 0x00, 0x28])));
+        assert!(agreed.decodes(&video(vec![0x67, 0x42, 0xe0, 0x1f])));
+    }
+
+    #[test]
+    fn unknown_codecs_are_left_out() {
+        let json = r#"{"codecs": [
+            {"codec": "vp9", "features": ["nack", "hologram"]},
+            {"codec": "opus", "features": ["red", "dred"]}
+        ]}"#;
+
+        let theirs: Capabilities = serde_json::from_str(json).unwrap();
+        let agreed = Capabilities::ours(false).intersect(&theirs);
+
+        assert!(agreed.codec(Codec::H264).is_none());
+        assert!(!agreed.decodes(&video(vec![0x67, 0x42, 0xe0, 0x1f])));
+
+        let opus = agreed.codec(Codec::Opus).unwrap();
+        assert_eq!(opus.features, vec![Feature::Red]);
+    }
+}
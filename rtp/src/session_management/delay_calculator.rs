@@ -33,7 +33,10 @@ impl PeerDelay {
             return 0;
         }
 
-        self.delay_estimate = (31 * self.delay_estimate + difference) / 32;
+        // (31 * estimate + difference) / 32, but as a step towards `difference` so it
+        // survives transit times anywhere in the u32 range (the timestamps start at random)
+        let step = difference.wrapping_sub(self.delay_estimate) as i32 / 32;
+        self.delay_estimate = self.delay_estimate.wrapping_add(step as u32);
 
         let divergence = self.active_delay.wrapping_sub(self.delay_estimate) as i32;
 
@@ -67,6 +70,10 @@ impl DelayCalculator {
             .insert(ssrc, PeerDelay::new(self.skew_threshold));
     }
 
+    pub fn remove_peer(&self, ssrc: u32) {
+        self.peer_delay.remove(&ssrc);
+    }
+
     pub fn adjust_skew(&self, ssrc: u32, difference: u32) -> i32 {
         let adjustment = self
             .peer_delay
@@ -96,9 +103,11 @@ pub fn calculate_playout_time(
     // M = T * R + offset
     // don't worry that we're cutting off the bits
     // the method described in Perkin's book uses modulo arithmetic
-    let arrival_time = arrival_time.as_millis() as u32 * (media_clock_rate / 1000);
+    // (wrapping, a plain multiply overflows and panics in debug builds)
+    let arrival_time = (arrival_time.as_millis() as u32).wrapping_mul(media_clock_rate / 1000);
 
     // d(n) = Arrival Time of Packet - Header Timestamp
+    // this is also the transit time the jitter calculation needs
     let difference = arrival_time.wrapping_sub(rtp_header.timestamp);
 
     // offset = Min(d(n-w)...d(n))
@@ -117,9 +126,15 @@ pub fn calculate_playout_time(
     // we calculate the base playout time every packet, but if an existing playoutbuffernode with
     // the same RTP timestamp exists already, the struct is essentially discarded
 
-    let fragment = Fragment::new(rtp_header.sequence_number, data.freeze());
+    let fragment = Fragment::new(rtp_header.sequence_number, rtp_header.marker, data.freeze());
 
-    peer_manager.add_playout_node_to_peer(rtp_header.ssrc, node, fragment);
+    peer_manager.add_playout_node_to_peer(
+        rtp_header.ssrc,
+        node,
+        fragment,
+        arrival_time,
+        media_clock_rate,
+    );
 
     // TODO: Something with this!!
     let adjustment = peer_manager
@@ -128,3 +143,40 @@ pub fn calculate_playout_time(
 
     Some(base_playout_time)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transit_times_near_the_top_dont_overflow() {
+        let mut delay = PeerDelay::new(480);
+
+        assert_eq!(delay.adjust_skew(u32::MAX - 1000), 0);
+
+        // the estimate creeps across the wrap towards the new transit time
+        for _ in 0..200 {
+            delay.adjust_skew(1000);
+        }
+
+        assert!(delay.delay_estimate <= 1000 && delay.delay_estimate > 900);
+    }
+
+    #[test]
+    fn drifting_past_the_threshold_adjusts_the_delay() {
+        let adjustments = |transit: u32| {
+            let mut delay = PeerDelay::new(480);
+            delay.adjust_skew(10_000);
+
+            (0..200)
+                .map(|_| delay.adjust_skew(transit))
+                .find(|adjustment| *adjustment != 0)
+        };
+
+        assert_eq!(adjustments(9_000), Some(480));
+        assert_eq!(adjustments(11_000), Some(-480));
+
+        // inside the threshold nothing moves
+        assert_eq!(adjustments(9_600), None);
+    }
+}
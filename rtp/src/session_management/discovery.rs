@@ -0,0 +1,470 @@
+/*
+    Finding calls on the local network, so joining is picking a room instead of typing an address.
+
+    A session that advertises answers mDNS (RFC 6762) queries for _rtpcall._tcp.local with
+    the usual DNS-SD records (RFC 6763):
+    - PTR from the service type to our instance, named after our CNAME so it's unique
+    - SRV with our signaling port, TXT with the room name and protocol version
+    - A with the signaling listener's address
+
+    It also announces itself when it starts, and again whenever the listener moves (we
+    changed networks). Before moving or shutting down it sends the same records with a TTL
+    of 0, so other caches drop the old room right away.
+
+    Browsing sends one query and collects whatever answers come back in the time given.
+    Rooms from builds speaking another signaling version are left out, joining them would
+    only fail.
+
+    Only IPv4, like the signaling listener.
+*/
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use local_ip_address::local_ip;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io,
+    net::UdpSocket,
+    time::{Instant, interval, sleep, timeout_at},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    packets::dns::{
+        DnsMessage, Question, Record, RecordData, TYPE_ANY, TYPE_PTR, TYPE_SRV, TYPE_TXT,
+    },
+    session_management::{signaling_protocol::PROTOCOL_VERSION, signaling_server::SignalingServer},
+};
+
+const SERVICE: &str = "_rtpcall._tcp.local";
+
+/// where mDNS lives (RFC 6762 3)
+const MDNS_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// how long others can cache our records, the RFC's suggestion for SRV and friends
+const RECORD_TTL: u32 = 120;
+
+/// how often to check the listener hasn't moved
+const ADDRESS_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A call somebody nearby is advertising
+#[derive(Debug, Clone, PartialEq)]
+pub struct NearbyRoom {
+    pub room: String,
+    /// what to hand rust_session_create
+    pub signaling_addr: SocketAddr,
+}
+
+/// What we answer with
+struct Advertisement {
+    /// our CNAME, so two people calling their room the same thing don't clash
+    instance: String,
+    room: String,
+    signaling_addr: SocketAddrV4,
+}
+
+impl Advertisement {
+    fn instance_name(&self) -> String {
+        format!("{}.{}", self.instance, SERVICE)
+    }
+
+    fn host_name(&self) -> String {
+        format!("{}.local", self.instance)
+    }
+
+    fn response(&self, ttl: u32) -> DnsMessage {
+        let record = |name: String, data: RecordData| Record { name, ttl, data };
+
+        DnsMessage {
+            response: true,
+            questions: vec![],
+            records: vec![
+                record(SERVICE.to_string(), RecordData::Ptr(self.instance_name())),
+                record(
+                    self.instance_name(),
+                    RecordData::Srv {
+                        port: self.signaling_addr.port(),
+                        target: self.host_name(),
+                    },
+                ),
+                record(
+                    self.instance_name(),
+                    RecordData::Txt(vec![
+                        format!("room={}", self.room),
+                        format!("v={}", PROTOCOL_VERSION),
+                    ]),
+                ),
+                record(self.host_name(), RecordData::A(*self.signaling_addr.ip())),
+            ],
+        }
+    }
+
+    /// someone's browsing for calls, or asking about us in particular
+    fn answers(&self, query: &DnsMessage) -> bool {
+        !query.response
+            && query.questions.iter().any(|question| {
+                let name = question.name.to_ascii_lowercase();
+
+                (name == SERVICE && matches!(question.record_type, TYPE_PTR | TYPE_ANY))
+                    || (name == self.instance_name().to_ascii_lowercase()
+                        && matches!(question.record_type, TYPE_SRV | TYPE_TXT | TYPE_ANY))
+            })
+    }
+}
+
+/// Advertises the signaling listener as `room` until `shutdown`.
+/// Follows the listener when we change networks, saying goodbye to the old address first
+pub async fn advertise(signaling: Arc<SignalingServer>, room: String, shutdown: CancellationToken) {
+    loop {
+        let signaling_addr = match signaling.signaling_addr().await {
+            Ok(SocketAddr::V4(addr)) => addr,
+            Ok(SocketAddr::V6(addr)) => {
+                eprintln!("Can't advertise {} over mDNS, only IPv4", addr);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Nothing to advertise, {}", e);
+                return;
+            }
+        };
+
+        let advertisement = Advertisement {
+            instance: signaling.cname().to_string(),
+            room: room.clone(),
+            signaling_addr,
+        };
+
+        let moved = async {
+            loop {
+                sleep(ADDRESS_CHECK_INTERVAL).await;
+
+                let current = signaling.signaling_addr().await.ok();
+                if current != Some(SocketAddr::V4(signaling_addr)) {
+                    return;
+                }
+            }
+        };
+
+        let stop = async {
+            tokio::select! {
+                _ = moved => {}
+                _ = shutdown.cancelled() => {}
+            }
+        };
+
+        if let Err(e) = advertise_on(MDNS_GROUP, &advertisement, stop).await {
+            eprintln!("Stopped advertising {}, {}", room, e);
+
+            tokio::select! {
+                _ = sleep(ADDRESS_CHECK_INTERVAL) => {}
+                _ = shutdown.cancelled() => {}
+            }
+        }
+
+        if shutdown.is_cancelled() {
+            return;
+        }
+    }
+}
+
+/// Announces, then answers queries until `stop` or the socket fails.
+/// Stopping sends a goodbye (RFC 6762 10.1) so nobody keeps the room cached for RECORD_TTL
+async fn advertise_on(
+    group: SocketAddrV4,
+    advertisement: &Advertisement,
+    stop: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let socket = multicast_socket(group, *advertisement.signaling_addr.ip())?;
+    let response = advertisement.response(RECORD_TTL).serialize();
+
+    // announced twice, a second apart (RFC 6762 8.3), answering queries in between
+    let mut announce = interval(Duration::from_secs(1));
+    let mut announcements = 0;
+
+    let mut buffer = [0u8; 1500];
+
+    tokio::pin!(stop);
+
+    loop {
+        tokio::select! {
+            _ = &mut stop => {
+                let goodbye = advertisement.response(0).serialize();
+                socket.send_to(&goodbye, group).await?;
+
+                return Ok(());
+            }
+            _ = announce.tick(), if announcements < 2 => {
+                socket.send_to(&response, group).await?;
+                announcements += 1;
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let (bytes_read, _) = received?;
+
+                let Some(query) = DnsMessage::deserialize(&buffer[..bytes_read]) else {
+                    continue;
+                };
+
+                if advertisement.answers(&query) {
+                    socket.send_to(&response, group).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Every room advertised nearby, collected for `wait`
+pub async fn browse(wait: Duration) -> io::Result<Vec<NearbyRoom>> {
+    let interface = match local_ip() {
+        Ok(IpAddr::V4(ip)) => ip,
+        _ => Ipv4Addr::UNSPECIFIED,
+    };
+
+    browse_on(MDNS_GROUP, interface, wait).await
+}
+
+async fn browse_on(
+    group: SocketAddrV4,
+    interface: Ipv4Addr,
+    wait: Duration,
+) -> io::Result<Vec<NearbyRoom>> {
+    let socket = multicast_socket(group, interface)?;
+
+    let query = DnsMessage {
+        response: false,
+        questions: vec![Question {
+            name: SERVICE.to_string(),
+            record_type: TYPE_PTR,
+        }],
+        records: vec![],
+    };
+    socket.send_to(&query.serialize(), group).await?;
+
+    // by instance, someone answering twice is still one room
+    let mut rooms = HashMap::new();
+    let mut buffer = [0u8; 1500];
+    let deadline = Instant::now() + wait;
+
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (bytes_read, from) = received?;
+
+        let Some(response) = DnsMessage::deserialize(&buffer[..bytes_read]) else {
+            continue;
+        };
+
+        if response.response {
+            rooms.extend(rooms_in(&response, from.ip()));
+        }
+    }
+
+    let mut rooms: Vec<NearbyRoom> = rooms.into_values().collect();
+    rooms.sort_by(|a, b| a.room.cmp(&b.room));
+
+    Ok(rooms)
+}
+
+/// Pieces rooms together from one response's records, by instance name.
+/// Without an A record for the host, it's wherever the response came from
+fn rooms_in(response: &DnsMessage, from: IpAddr) -> Vec<(String, NearbyRoom)> {
+    let suffix = format!(".{}", SERVICE);
+
+    let address_of = |host: &str| {
+        response
+            .records
+            .iter()
+            .find_map(|record| match record.data {
+                RecordData::A(ip) if record.name.eq_ignore_ascii_case(host) => Some(IpAddr::V4(ip)),
+                _ => None,
+            })
+    };
+
+    let txt_of = |instance: &str| {
+        response
+            .records
+            .iter()
+            .find_map(|record| match &record.data {
+                RecordData::Txt(entries) if record.name.eq_ignore_ascii_case(instance) => {
+                    Some(entries.clone())
+                }
+                _ => None,
+            })
+    };
+
+    response
+        .records
+        .iter()
+        .filter(|record| record.ttl > 0 && record.name.to_ascii_lowercase().ends_with(&suffix))
+        .filter_map(|record| {
+            let RecordData::Srv { port, target } = &record.data else {
+                return None;
+            };
+
+            let txt = txt_of(&record.name)?;
+            let entry = |key: &str| {
+                txt.iter()
+                    .find_map(|entry| entry.strip_prefix(key)?.strip_prefix('='))
+            };
+
+            if entry("v")? != PROTOCOL_VERSION.to_string() {
+                return None;
+            }
+
+            let room = NearbyRoom {
+                room: entry("room")?.to_string(),
+                signaling_addr: SocketAddr::new(address_of(target).unwrap_or(from), *port),
+            };
+
+            Some((record.name.to_ascii_lowercase(), room))
+        })
+        .collect()
+}
+
+/// Bound to the group's port alongside anyone else on it (the system's mDNS responder for one),
+/// sending and receiving multicast on `interface`
+fn multicast_socket(group: SocketAddrV4, interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port()).into())?;
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?; // so browsing finds calls on this machine too
+    socket.set_multicast_ttl_v4(255)?; // RFC 6762 11
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// not 5353, so the tests don't talk to whatever's running on the machine
+    fn test_group() -> SocketAddrV4 {
+        let port = 20000 + rand::random::<u16>() % 20000;
+        SocketAddrV4::new(*MDNS_GROUP.ip(), port)
+    }
+
+    #[tokio::test]
+    async fn browsing_finds_advertised_rooms() {
+        let group = test_group();
+        let loopback = Ipv4Addr::LOCALHOST;
+
+        let standup = Advertisement {
+            instance: "0123456789abcdef".to_string(),
+            room: "standup".to_string(),
+            signaling_addr: SocketAddrV4::new(loopback, 4000),
+        };
+        let retro = Advertisement {
+            instance: "fedcba9876543210".to_string(),
+            room: "retro".to_string(),
+            signaling_addr: SocketAddrV4::new(loopback, 5000),
+        };
+
+        let advertising = async {
+            tokio::join!(
+                advertise_on(group, &standup, std::future::pending()),
+                advertise_on(group, &retro, std::future::pending())
+            )
+        };
+
+        let rooms = tokio::select! {
+            _ = advertising => panic!("advertising stopped"),
+            rooms = async {
+                // let both sockets get bound first
+                sleep(Duration::from_millis(100)).await;
+                browse_on(group, loopback, Duration::from_millis(500)).await
+            } => rooms.unwrap(),
+        };
+
+        assert_eq!(
+            rooms,
+            vec![
+                NearbyRoom {
+                    room: "retro".to_string(),
+                    signaling_addr: "127.0.0.1:5000".parse().unwrap(),
+                },
+                NearbyRoom {
+                    room: "standup".to_string(),
+                    signaling_addr: "127.0.0.1:4000".parse().unwrap(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn other_versions_are_left_out() {
+        let advertisement = Advertisement {
+            instance: "0123456789abcdef".to_string(),
+            room: "standup".to_string(),
+            signaling_addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4000),
+        };
+
+        let mut response = advertisement.response(RECORD_TTL);
+        let from = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert_eq!(rooms_in(&response, from).len(), 1);
+
+        for record in &mut response.records {
+            if let RecordData::Txt(entries) = &mut record.data {
+                entries[1] = format!("v={}", PROTOCOL_VERSION + 1);
+            }
+        }
+        assert!(rooms_in(&response, from).is_empty());
+
+        // and saying goodbye isn't a room either
+        let goodbye = advertisement.response(0);
+        assert!(rooms_in(&goodbye, from).is_empty());
+    }
+
+    fn longest_ttl(message: &[u8]) -> Option<u32> {
+        let message = DnsMessage::deserialize(message).unwrap();
+        message.records.iter().map(|record| record.ttl).max()
+    }
+
+    #[tokio::test]
+    async fn stopping_says_goodbye() {
+        let group = test_group();
+        let loopback = Ipv4Addr::LOCALHOST;
+
+        let advertisement = Advertisement {
+            instance: "0123456789abcdef".to_string(),
+            room: "standup".to_string(),
+            signaling_addr: SocketAddrV4::new(loopback, 4000),
+        };
+
+        let listener = multicast_socket(group, loopback).unwrap();
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let listening = async {
+            let mut buffer = [0u8; 1500];
+
+            let (bytes_read, _) = listener.recv_from(&mut buffer).await.unwrap();
+            assert_eq!(longest_ttl(&buffer[..bytes_read]), Some(RECORD_TTL));
+
+            stop_tx.send(()).unwrap();
+
+            let (bytes_read, _) = listener.recv_from(&mut buffer).await.unwrap();
+            longest_ttl(&buffer[..bytes_read])
+        };
+
+        let stop = async {
+            let _ = stop_rx.await;
+        };
+
+        let (advertised, last_heard) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(advertise_on(group, &advertisement, stop), listening)
+        })
+        .await
+        .unwrap();
+
+        advertised.unwrap();
+        assert_eq!(last_heard, Some(0));
+    }
+}
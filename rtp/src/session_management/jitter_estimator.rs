@@ -0,0 +1,104 @@
+/*
+    Interarrival jitter, RFC 3550 Appendix A.8:
+    https://www.rfc-editor.org/rfc/rfc3550#appendix-A.8
+
+    transit = arrival time - RTP timestamp (both in RTP timestamp units)
+    D(i-1, i) = transit(i) - transit(i-1)
+    J(i) = J(i-1) + (|D(i-1, i)| - J(i-1)) / 16
+*/
+
+#[derive(Default)]
+pub struct JitterEstimator {
+    /// jitter scaled up by 16, so the /16 doesn't throw away the fractional bits
+    scaled_jitter: u32,
+
+    /// transit time of the previous packet, None until the first one arrives
+    last_transit: Option<u32>,
+}
+
+impl JitterEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `transit` is the packet's arrival time minus its RTP timestamp, in media clock units.
+    /// Wraps just like the timestamps do, only the difference between packets matters.
+    pub fn update(&mut self, transit: u32) {
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit.wrapping_sub(last_transit) as i32).unsigned_abs();
+
+            // integer form from the RFC: J += |D| - ((J + 8) >> 4)
+            let decay = self.scaled_jitter.saturating_add(8) >> 4;
+            self.scaled_jitter = self.scaled_jitter.saturating_add(d) - decay;
+        }
+
+        self.last_transit = Some(transit);
+    }
+
+    /// Jitter in RTP timestamp units, as it goes in a reception report
+    pub fn jitter(&self) -> u32 {
+        self.scaled_jitter >> 4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_trace(trace: &[(u32, u32)]) -> Vec<u32> {
+        let mut estimator = JitterEstimator::new();
+
+        trace
+            .iter()
+            .map(|(timestamp, arrival)| {
+                estimator.update(arrival.wrapping_sub(*timestamp));
+                estimator.jitter()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn constant_transit_has_no_jitter() {
+        // 20ms Opus packets at 48kHz, every one takes exactly as long to arrive
+        let trace: Vec<(u32, u32)> = (0..50).map(|i| (i * 960, i * 960 + 4321)).collect();
+
+        assert!(run_trace(&trace).iter().all(|&jitter| jitter == 0));
+    }
+
+    #[test]
+    fn single_step_is_a_sixteenth() {
+        assert_eq!(run_trace(&[(0, 0), (3000, 3160)]), vec![0, 10]);
+    }
+
+    #[test]
+    fn known_video_trace() {
+        // 30fps at 90kHz, (RTP timestamp, arrival), two late frames.
+        // expected values from the floating point formula, truncated
+        let trace = [
+            (0, 1000),
+            (3000, 4000),
+            (6000, 7450),
+            (9000, 10000),
+            (12000, 13900),
+            (15000, 16000),
+            (18000, 19000),
+            (21000, 22000),
+        ];
+
+        assert_eq!(run_trace(&trace), vec![0, 0, 28, 54, 107, 156, 147, 137]);
+    }
+
+    #[test]
+    fn transit_wraps_around() {
+        // arrival clock wraps past u32::MAX halfway through, transit stays constant
+        let start = u32::MAX - 2000;
+        let trace = [
+            (0, start),
+            (960, start.wrapping_add(960)),
+            (1920, start.wrapping_add(1920)),
+            (2880, start.wrapping_add(2880 + 160)),
+        ];
+
+        assert_eq!(run_trace(&trace), vec![0, 0, 0, 10]);
+    }
+}
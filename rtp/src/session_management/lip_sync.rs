@@ -0,0 +1,261 @@
+/*
+    Audio/video synchronization across the two separate streams of a participant.
+
+    Each stream knows how to turn one of its RTP timestamps into the sender's wall clock,
+    from the NTP/RTP pair in the sender's last SR. So for every frame we can work out
+    how long it took from capture on their end to playout on ours:
+
+        delay = local playout time - sender capture time
+
+    Both streams share the sender's clock and ours, so any clock offset cancels out
+    when we compare the two. If audio is running later than video, video gets held back
+    by the difference. (If video is later, there's not much to do. We don't delay audio.)
+*/
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+
+use crate::interop::StreamType;
+
+/// don't chase anything bigger than this, something's broken (or an SR is stale)
+const MAX_SYNC_DELAY_MS: i64 = 1000;
+
+/// seconds between the NTP epoch (1900) and the unix one (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Converts a 64 bit NTP timestamp to milliseconds since the unix epoch
+pub fn ntp_to_unix_ms(ntp: u64) -> i64 {
+    let seconds = (ntp >> 32) as i64 - NTP_UNIX_OFFSET as i64;
+    let fraction_ms = ((ntp & 0xFFFFFFFF) * 1000) >> 32;
+
+    seconds * 1000 + fraction_ms as i64
+}
+
+/// The remote sender's NTP/RTP pair from their last SR, ties their RTP clock to their wall clock
+#[derive(Debug, Clone, Copy)]
+pub struct SenderClock {
+    pub ntp_time: u64,
+    pub rtp_time: u32,
+}
+
+impl SenderClock {
+    /// When (sender wall clock, unix ms) the frame with `rtp_timestamp` was captured
+    pub fn capture_time_ms(&self, rtp_timestamp: u32, media_clock_rate: u32) -> i64 {
+        // signed, the timestamp can be a little before the SR's
+        let elapsed = rtp_timestamp.wrapping_sub(self.rtp_time) as i32 as i64;
+
+        ntp_to_unix_ms(self.ntp_time) + elapsed * 1000 / media_clock_rate as i64
+    }
+}
+
+#[derive(Default)]
+struct ParticipantDelays {
+    audio_ssrc: Option<u32>,
+    video_ssrc: Option<u32>,
+
+    /// smoothed capture-to-playout delay (ms) of each stream
+    audio_delay: Option<i64>,
+    video_delay: Option<i64>,
+}
+
+impl ParticipantDelays {
+    fn observe(delay: &mut Option<i64>, sample: i64) {
+        *delay = Some(match *delay {
+            None => sample,
+            Some(current) => current + (sample - current) / 16,
+        });
+    }
+
+    fn extra_video_delay(&self) -> Duration {
+        let (Some(audio), Some(video)) = (self.audio_delay, self.video_delay) else {
+            return Duration::ZERO;
+        };
+
+        let extra = (audio - video).clamp(0, MAX_SYNC_DELAY_MS);
+        Duration::from_millis(extra as u64)
+    }
+}
+
+pub struct LipSync {
+    /// which participant (CNAME) a stream belongs to
+    ssrc_to_cname: DashMap<u32, String>,
+    participants: DashMap<String, ParticipantDelays>,
+}
+
+impl Default for LipSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LipSync {
+    pub fn new() -> Self {
+        Self {
+            ssrc_to_cname: DashMap::new(),
+            participants: DashMap::new(),
+        }
+    }
+
+    /// Ties a stream to its participant. Audio and video from the same CNAME get synced.
+    pub fn associate(&self, ssrc: u32, cname: &str, stream_type: StreamType) {
+        // older builds don't send one, nothing to pair them up with
+        if cname.is_empty() {
+            return;
+        }
+
+        self.ssrc_to_cname.insert(ssrc, cname.to_string());
+
+        let mut participant = self.participants.entry(cname.to_string()).or_default();
+        match stream_type {
+            StreamType::Audio => participant.audio_ssrc = Some(ssrc),
+            StreamType::Video => participant.video_ssrc = Some(ssrc),
+        }
+    }
+
+    /// A frame from `ssrc` was played out `delay_ms` after the sender captured it
+    pub fn observe(&self, ssrc: u32, delay_ms: i64) {
+        let Some(cname) = self.ssrc_to_cname.get(&ssrc) else {
+            return;
+        };
+
+        let Some(mut participant) = self.participants.get_mut(cname.value()) else {
+            return;
+        };
+
+        if participant.audio_ssrc == Some(ssrc) {
+            ParticipantDelays::observe(&mut participant.audio_delay, delay_ms);
+        } else if participant.video_ssrc == Some(ssrc) {
+            ParticipantDelays::observe(&mut participant.video_delay, delay_ms);
+        }
+    }
+
+    /// How much longer to hold video frames from `video_ssrc` so they line up with the audio
+    pub fn video_delay(&self, video_ssrc: u32) -> Duration {
+        let Some(cname) = self.ssrc_to_cname.get(&video_ssrc) else {
+            return Duration::ZERO;
+        };
+
+        self.participants
+            .get(cname.value())
+            .map_or(Duration::ZERO, |participant| {
+                participant.extra_video_delay()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2023-11-14T22:13:20Z as unix ms
+    const UNIX_MS: i64 = 1_700_000_000_000;
+    const VIDEO_RATE: u32 = 90_000;
+
+    fn ntp(unix_seconds: u64, fraction: u32) -> u64 {
+        ((unix_seconds + NTP_UNIX_OFFSET) << 32) | fraction as u64
+    }
+
+    #[test]
+    fn ntp_converts_to_unix_ms() {
+        assert_eq!(ntp_to_unix_ms(ntp(0, 0)), 0);
+        assert_eq!(ntp_to_unix_ms(ntp(1_700_000_000, 0)), UNIX_MS);
+
+        // half a second
+        assert_eq!(
+            ntp_to_unix_ms(ntp(1_700_000_000, 0x8000_0000)),
+            UNIX_MS + 500
+        );
+    }
+
+    #[test]
+    fn rtp_timestamps_map_through_the_sender_report() {
+        let clock = SenderClock {
+            ntp_time: ntp(1_700_000_000, 0),
+            rtp_time: 10_000,
+        };
+
+        assert_eq!(clock.capture_time_ms(10_000, VIDEO_RATE), UNIX_MS);
+        assert_eq!(
+            clock.capture_time_ms(10_000 + VIDEO_RATE, VIDEO_RATE),
+            UNIX_MS + 1000
+        );
+
+        // a frame from just before the SR
+        assert_eq!(
+            clock.capture_time_ms(10_000 - 9000, VIDEO_RATE),
+            UNIX_MS - 100
+        );
+    }
+
+    #[test]
+    fn rtp_timestamps_map_across_wraparound() {
+        let clock = SenderClock {
+            ntp_time: ntp(1_700_000_000, 0),
+            rtp_time: u32::MAX - 4499,
+        };
+
+        assert_eq!(clock.capture_time_ms(4500, VIDEO_RATE), UNIX_MS + 100);
+
+        // and the other way, the SR came after the wrap
+        let clock = SenderClock {
+            ntp_time: ntp(1_700_000_000, 0),
+            rtp_time: 4500,
+        };
+
+        assert_eq!(
+            clock.capture_time_ms(u32::MAX - 4499, VIDEO_RATE),
+            UNIX_MS - 100
+        );
+    }
+
+    fn participant(lip_sync: &LipSync, cname: &str, audio: u32, video: u32) {
+        lip_sync.associate(audio, cname, StreamType::Audio);
+        lip_sync.associate(video, cname, StreamType::Video);
+    }
+
+    #[test]
+    fn video_waits_for_late_audio() {
+        let lip_sync = LipSync::new();
+        participant(&lip_sync, "them", 1, 2);
+
+        // nothing to compare yet
+        lip_sync.observe(2, 100);
+        assert_eq!(lip_sync.video_delay(2), Duration::ZERO);
+
+        lip_sync.observe(1, 150);
+        assert_eq!(lip_sync.video_delay(2), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn late_video_isnt_held_back_further() {
+        let lip_sync = LipSync::new();
+        participant(&lip_sync, "them", 1, 2);
+
+        lip_sync.observe(1, 80);
+        lip_sync.observe(2, 120);
+
+        assert_eq!(lip_sync.video_delay(2), Duration::ZERO);
+    }
+
+    #[test]
+    fn the_delay_is_capped_and_per_participant() {
+        let lip_sync = LipSync::new();
+        participant(&lip_sync, "far", 1, 2);
+        participant(&lip_sync, "near", 3, 4);
+
+        lip_sync.observe(1, 5000);
+        lip_sync.observe(2, 0);
+        lip_sync.observe(3, 40);
+        lip_sync.observe(4, 20);
+
+        assert_eq!(
+            lip_sync.video_delay(2),
+            Duration::from_millis(MAX_SYNC_DELAY_MS as u64)
+        );
+        assert_eq!(lip_sync.video_delay(4), Duration::from_millis(20));
+
+        // never associated
+        assert_eq!(lip_sync.video_delay(5), Duration::ZERO);
+    }
+}
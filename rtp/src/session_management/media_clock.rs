@@ -0,0 +1,110 @@
+/*
+    The clock RTP timestamps are read off of.
+
+    A sender report pairs "now" in NTP with "now" in RTP timestamp units, so the
+    receiving end can map our timestamps back onto a wall clock (see lip_sync.rs).
+    For that to mean anything, the RTP side has to come from the same clock the
+    media timestamps were taken from. On macOS that's the host time clock the capture
+    pipeline stamps buffers with, so swift hands us that (SessionCallbacks::media_clock).
+    Anywhere else (tests, linux) a plain monotonic clock does the job.
+*/
+
+use std::time::Instant;
+
+/// Returns the current time in seconds, swift passes `swift_send_cmclocktime`
+pub type MediaClockCallback = Option<extern "C" fn() -> f64>;
+
+pub trait MediaClock: Send + Sync {
+    /// Current time in seconds. Only differences matter, the epoch can be anything
+    fn now(&self) -> f64;
+
+    /// Current time in RTP timestamp units, wrapping like the timestamps do
+    fn rtp_time(&self, clock_rate: u32) -> u32 {
+        (self.now() * clock_rate as f64) as u64 as u32
+    }
+}
+
+/// Seconds since the clock was created
+pub struct MonotonicClock {
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MediaClock for MonotonicClock {
+    fn now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+}
+
+/// Whatever clock the app is stamping its media with (CMClockGetHostTimeClock for swift)
+pub struct CallbackClock {
+    callback: extern "C" fn() -> f64,
+}
+
+impl CallbackClock {
+    pub fn new(callback: extern "C" fn() -> f64) -> Self {
+        Self { callback }
+    }
+}
+
+impl MediaClock for CallbackClock {
+    fn now(&self) -> f64 {
+        (self.callback)()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// always says `seconds`
+    struct FixedClock(f64);
+
+    impl MediaClock for FixedClock {
+        fn now(&self) -> f64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn seconds_map_onto_the_clock_rate() {
+        assert_eq!(FixedClock(0.0).rtp_time(90_000), 0);
+        assert_eq!(FixedClock(1.5).rtp_time(48_000), 72_000);
+        assert_eq!(FixedClock(2.0).rtp_time(90_000), 180_000);
+    }
+
+    #[test]
+    fn rtp_time_wraps_like_timestamps() {
+        // 2^32 ticks of a 90kHz clock is a bit over 13 hours
+        let wrap = (1u64 << 32) as f64 / 90_000.0;
+
+        assert_eq!(FixedClock(wrap + 1.0).rtp_time(90_000), 90_000);
+        assert_eq!(FixedClock(wrap - 1.0).rtp_time(90_000), u32::MAX - 89_999);
+    }
+
+    #[test]
+    fn the_monotonic_clock_only_goes_forward() {
+        let clock = MonotonicClock::new();
+
+        let first = clock.now();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let second = clock.now();
+
+        assert!(first >= 0.0);
+        assert!(second - first >= 0.005);
+        assert!(clock.rtp_time(48_000) >= (second * 48_000.0) as u32);
+    }
+}
@@ -0,0 +1,104 @@
+/*
+    Where received media and session changes go.
+
+    The core never calls into the app directly, it talks to these traits instead.
+    The app's implementation is its SessionCallbacks (interop/callbacks.rs),
+    anything else (tests, linux tools) can hand Session::new its own.
+
+    Contexts are opaque to us. Whatever `SessionEvents` hands back for a peer gets stored
+    with it and passed back with every frame from that peer.
+*/
+
+use std::{ffi::c_void, net::SocketAddr};
+
+use crate::interop::{StreamType, audio::AudioFrameKind};
+
+/// Gets the decoded-order media out of the playout buffers
+pub trait MediaSink: Send + Sync {
+    /// One whole H.264 access unit, AVCC (length prefixed) NAL units.
+    /// `after_gap` if frames before it never made it out of the playout buffer
+    fn receive_frame(&self, context: *mut c_void, frame: &mut [u8], after_gap: bool);
+
+    /// One Opus packet, or a request to conceal `samples` of audio (see AudioFrameKind)
+    fn receive_audio(
+        &self,
+        context: *mut c_void,
+        data: &[u8],
+        ssrc: u32,
+        playout_time: u32,
+        samples: u32,
+        kind: AudioFrameKind,
+    );
+
+    /// The session's being dropped and the app may free whatever it handed us right after,
+    /// so nothing can reach it from here on. Called before the session's tasks have stopped.
+    /// CallbackSink is the session's SessionEvents too, and those go quiet with it
+    fn detach(&self) {}
+}
+
+/// Things signaling finds out about other participants
+pub trait SessionEvents: Send + Sync {
+    /// A peer is sending video with these parameter sets. Returns the context to decode it with
+    fn video_peer_joined(
+        &self,
+        ssrc: u32,
+        media_addr: SocketAddr,
+        pps: &[u8],
+        sps: &[u8],
+    ) -> *mut c_void;
+
+    /// A peer is sending Opus. Returns the context to decode it with
+    fn audio_peer_joined(
+        &self,
+        ssrc: u32,
+        sample_rate: f64,
+        channels: u32,
+        use_inband_fec: bool,
+    ) -> *mut c_void;
+
+    /// A peer's gone (BYE, signaling, or nothing heard from them in a while).
+    /// `context` is whatever the matching *_peer_joined returned, it won't be handed out again
+    fn peer_left(&self, ssrc: u32, stream_type: StreamType, context: *mut c_void);
+}
+
+/// Drops everything, for when nobody's listening
+pub struct NullSink;
+
+impl MediaSink for NullSink {
+    fn receive_frame(&self, _context: *mut c_void, _frame: &mut [u8], _after_gap: bool) {}
+
+    fn receive_audio(
+        &self,
+        _context: *mut c_void,
+        _data: &[u8],
+        _ssrc: u32,
+        _playout_time: u32,
+        _samples: u32,
+        _kind: AudioFrameKind,
+    ) {
+    }
+}
+
+impl SessionEvents for NullSink {
+    fn video_peer_joined(
+        &self,
+        _ssrc: u32,
+        _media_addr: SocketAddr,
+        _pps: &[u8],
+        _sps: &[u8],
+    ) -> *mut c_void {
+        std::ptr::null_mut()
+    }
+
+    fn audio_peer_joined(
+        &self,
+        _ssrc: u32,
+        _sample_rate: f64,
+        _channels: u32,
+        _use_inband_fec: bool,
+    ) -> *mut c_void {
+        std::ptr::null_mut()
+    }
+
+    fn peer_left(&self, _ssrc: u32, _stream_type: StreamType, _context: *mut c_void) {}
+}
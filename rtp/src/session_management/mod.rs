@@ -1,3 +1,17 @@
+pub mod capabilities;
 pub mod delay_calculator;
+pub mod discovery;
+pub mod jitter_estimator;
+pub mod lip_sync;
+pub mod media_clock;
+pub mod media_sink;
+pub mod network_monitor;
 pub mod peer_manager;
+pub mod rendezvous;
+pub mod sdp;
+pub mod secure_channel;
+pub mod sequence_tracker;
+pub mod session;
+pub mod signaling_framing;
+pub mod signaling_protocol;
 pub mod signaling_server;
@@ -0,0 +1,304 @@
+/*
+    Noticing when the network changes under a call, and moving with it.
+
+    What can happen:
+    -   Small network timeout (ip address and SSRC are the same)
+        Nothing to do, the sockets are still good. Wait it out, the heartbeats and RTCP
+        timeouts only give up on us after several seconds of silence
+
+    -   Switch networks (IP address changes)
+        Our sockets are bound to an address that's gone. The session gets new ones on the
+        new address (listener first, then each stream's RTP/RTCP), then joins everyone again
+        with the same SSRCs and CNAME. Peers see a SSRC they already know and just update
+        where they send to, nobody gets a "peer left" for it
+
+    -   Just disconnecting abruptly, no reconnection
+        Peers stop hearing from us and time us out, see signaling_server.rs and rtcp/mod.rs
+
+    There's no portable way to be told about address changes, so we just poll local_ip().
+*/
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use local_ip_address::local_ip;
+use tokio::{
+    io,
+    net::{ToSocketAddrs, UdpSocket},
+    sync::watch,
+    time::interval,
+};
+
+use crate::{
+    interop::error::{Error, ErrorReporter, RtpError},
+    session_management::signaling_server::SignalingServer,
+};
+
+/// how often to check if our address changed
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Keeps `local_ip` up to date. The signaling listener moves before anyone watching hears about it,
+/// so streams can re-announce themselves as soon as they've moved too
+pub async fn monitor_network(
+    signaling: Arc<SignalingServer>,
+    local_ip_tx: watch::Sender<Option<IpAddr>>,
+    errors: Arc<ErrorReporter>,
+) {
+    let mut check = interval(NETWORK_CHECK_INTERVAL);
+
+    loop {
+        check.tick().await;
+
+        // no address at all is a network timeout, wait for one to come back
+        let Ok(ip) = local_ip() else {
+            continue;
+        };
+
+        if *local_ip_tx.borrow() == Some(ip) {
+            continue;
+        }
+
+        if let Err(e) = signaling.rebind(ip).await {
+            errors.report(Error::new(
+                RtpError::Network,
+                format!("Failed to move signaling to {}, {}", ip, e),
+            ));
+            continue;
+        }
+
+        local_ip_tx.send_replace(Some(ip));
+    }
+}
+
+/// A UDP socket that can be swapped for one on a new address while it's being used.
+/// Anyone waiting on recv_from moves over to the new one
+pub struct SwappableSocket {
+    current: watch::Sender<Arc<UdpSocket>>,
+}
+
+impl SwappableSocket {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            current: watch::Sender::new(Arc::new(socket)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.current.borrow().local_addr()
+    }
+
+    pub fn replace(&self, socket: UdpSocket) {
+        self.current.send_replace(Arc::new(socket));
+    }
+
+    pub async fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let socket = Arc::clone(&self.current.borrow());
+        socket.send_to(buf, addr).await
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut changes = self.current.subscribe();
+
+        loop {
+            let socket = Arc::clone(&changes.borrow_and_update());
+
+            tokio::select! {
+                received = socket.recv_from(buf) => return received,
+
+                // the sender lives in self, this can't fail
+                _ = changes.changed() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::c_void,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{
+        net::{
+            TcpStream,
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+        },
+        runtime::Handle,
+    };
+
+    use super::*;
+    use crate::{
+        interop::{StreamType, error::ErrorReporter},
+        packets::RTPSession,
+        session_management::{
+            capabilities::Capabilities,
+            lip_sync::LipSync,
+            media_sink::SessionEvents,
+            peer_manager::PeerManager,
+            secure_channel::{self, Identity, RoomKey, SecureReader, SecureWriter},
+            session::SessionTasks,
+            signaling_protocol::{
+                MediaSection, ParticipantArgs, SignalingMessage, StreamTypeWithArgs,
+            },
+            signaling_server::CallEntry,
+        },
+    };
+
+    #[derive(Default)]
+    struct Arrivals {
+        joined: AtomicUsize,
+        left: AtomicUsize,
+    }
+
+    impl SessionEvents for Arrivals {
+        fn video_peer_joined(
+            &self,
+            _ssrc: u32,
+            _media_addr: SocketAddr,
+            _pps: &[u8],
+            _sps: &[u8],
+        ) -> *mut c_void {
+            std::ptr::null_mut()
+        }
+
+        fn audio_peer_joined(
+            &self,
+            _ssrc: u32,
+            _sample_rate: f64,
+            _channels: u32,
+            _use_inband_fec: bool,
+        ) -> *mut c_void {
+            self.joined.fetch_add(1, Ordering::SeqCst);
+            std::ptr::null_mut()
+        }
+
+        fn peer_left(&self, _ssrc: u32, _stream_type: StreamType, _context: *mut c_void) {
+            self.left.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Joins the server at `addr` as `identity`, sending audio from `rtp_address`
+    async fn join(
+        addr: SocketAddr,
+        key: &RoomKey,
+        identity: &Identity,
+        rtp_address: &str,
+    ) -> (SecureReader<OwnedReadHalf>, SecureWriter<OwnedWriteHalf>) {
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let (mut reader, mut writer) = secure_channel::connect(reader, writer, key, identity)
+            .await
+            .unwrap();
+
+        let join = ParticipantArgs {
+            signaling_address: "127.0.0.1:4000".to_string(),
+            cname: identity.cname().to_string(),
+            media: vec![MediaSection {
+                local_rtp_address: rtp_address.to_string(),
+                ssrc: 7,
+                stream_type: StreamTypeWithArgs::Audio {
+                    sample_rate: 48000.0,
+                    channels: 1,
+                    use_inband_fec: false,
+                    red: false,
+                },
+            }],
+            capabilities: Capabilities::ours(false),
+        };
+        writer.send(&SignalingMessage::Join(join)).await.unwrap();
+
+        assert!(matches!(
+            reader.receive().await.unwrap(),
+            Some(SignalingMessage::JoinAck { .. })
+        ));
+
+        (reader, writer)
+    }
+
+    /// what `peer_manager` sends to, once it's been handed `expected`
+    async fn peers_become(peer_manager: &PeerManager, expected: &str) -> Vec<SocketAddr> {
+        let expected: SocketAddr = expected.parse().unwrap();
+
+        for _ in 0..100 {
+            if peer_manager.get_peers() == vec![expected] {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        peer_manager.get_peers()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejoining_from_a_new_address_moves_the_peer() {
+        let key = RoomKey::derive("test room", "test secret").unwrap();
+        let arrivals = Arc::new(Arrivals::default());
+
+        let server = Arc::new(SignalingServer::new(
+            CallEntry::Host,
+            key.clone(),
+            Arc::clone(&arrivals) as Arc<dyn SessionEvents>,
+            Arc::new(LipSync::new()),
+            Arc::new(ErrorReporter::new()),
+            SessionTasks::new(Handle::current()),
+        ));
+        let peer_manager = Arc::new(PeerManager::new(
+            RTPSession::new("127.0.0.1:5000".parse().unwrap(), 48000),
+            StreamType::Audio,
+            Arc::clone(&arrivals) as Arc<dyn SessionEvents>,
+        ));
+
+        tokio::spawn(
+            Arc::clone(&server).run_signaling_server(Arc::clone(&peer_manager), StreamType::Audio),
+        );
+        let addr = server.signaling_addr().await.unwrap();
+
+        let peer = Identity::generate();
+        let _before = join(addr, &key, &peer, "127.0.0.1:6000").await;
+        assert_eq!(
+            peers_become(&peer_manager, "127.0.0.1:6000").await,
+            vec!["127.0.0.1:6000".parse().unwrap()]
+        );
+
+        // they moved networks, same key, same SSRC, new everything else
+        let _after = join(addr, &key, &peer, "127.0.0.1:7000").await;
+        assert_eq!(
+            peers_become(&peer_manager, "127.0.0.1:7000").await,
+            vec!["127.0.0.1:7000".parse().unwrap()]
+        );
+
+        // same peer to the app, nobody left and nobody new showed up
+        assert_eq!(arrivals.joined.load(Ordering::SeqCst), 1);
+        assert_eq!(arrivals.left.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn receivers_follow_the_socket() {
+        let socket = Arc::new(SwappableSocket::new(
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ));
+
+        let receiving = {
+            let socket = Arc::clone(&socket);
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 16];
+                let (read, _) = socket.recv_from(&mut buffer).await.unwrap();
+                buffer[..read].to_vec()
+            })
+        };
+
+        // make sure it's waiting on the old one first
+        tokio::task::yield_now().await;
+
+        socket.replace(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let moved_to = socket.local_addr().unwrap();
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.send_to(b"moved", moved_to).await.unwrap();
+
+        assert_eq!(receiving.await.unwrap(), b"moved");
+    }
+}
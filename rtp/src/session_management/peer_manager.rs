@@ -1,15 +1,34 @@
 use bytes::Bytes;
 use dashmap::DashMap;
-use std::time::Instant;
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{collections::VecDeque, net::SocketAddr};
 
 use crate::interop::StreamType;
 use crate::packets::RTPSession;
 use crate::packets::rtcp::reception_report::ReceptionReport;
+use crate::packets::rtp::h264::nal_boundaries;
 use crate::session_management::delay_calculator::DelayCalculator;
+use crate::session_management::jitter_estimator::JitterEstimator;
+use crate::session_management::lip_sync::SenderClock;
+use crate::session_management::media_sink::SessionEvents;
+use crate::session_management::sequence_tracker::{SequenceStatus, SequenceTracker};
 
 static WINDOW_SIZE: usize = 50;
-static MAX_DROPOUT: u16 = 3000;
+
+/// most frames a peer's playout buffer will hold before the oldest is evicted
+static MAX_PLAYOUT_BUFFER_NODES: usize = 100;
+
+/// how long (in ms) a frame can sit past its playout time before it's considered stale
+static MAX_PLAYOUT_AGE_MS: u32 = 1000;
+
+/// Wraparound-safe RTP timestamp ordering (serial number arithmetic).
+/// Only correct while the two timestamps are less than 2^31 apart,
+/// which the eviction policy guarantees for the playout buffer.
+pub fn compare_timestamps(a: u32, b: u32) -> Ordering {
+    (a.wrapping_sub(b) as i32).cmp(&0)
+}
 
 pub struct PlayoutBufferNode {
     pub rtp_timestamp: u32,
@@ -17,16 +36,49 @@ pub struct PlayoutBufferNode {
     pub coded_data: Vec<Fragment>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// the frame outlived MAX_PLAYOUT_AGE_MS, usually because its marker packet never arrived
+    Stale,
+
+    /// the buffer hit MAX_PLAYOUT_BUFFER_NODES and the oldest frame had to go
+    Capacity,
+
+    /// the frame was due, still missing packets, and a newer frame was ready to go
+    Incomplete,
+}
+
+/// A frame handed out of the playout buffer, in decode order.
+pub struct ReleasedFrame {
+    pub node: PlayoutBufferNode,
+
+    /// packets or whole frames before this one were lost or dropped,
+    /// so the decoder should expect a discontinuity
+    pub after_gap: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlayoutStatistics {
+    pub frames_buffered: usize,
+    pub evicted_stale: u32,
+    pub evicted_capacity: u32,
+    pub evicted_incomplete: u32,
+    pub frames_recovered: u32,
+    pub render_dropped: u32,
+}
+
 pub struct Fragment {
     pub extended_sequence_num: u32,
     pub sequence_num: u16,
+    pub marker: bool,
     pub data: Bytes,
 }
 
 impl Fragment {
-    pub fn new(sequence_num: u16, data: Bytes) -> Self {
+    pub fn new(sequence_num: u16, marker: bool, data: Bytes) -> Self {
         Self {
             sequence_num,
+            marker,
             data,
             extended_sequence_num: 0,
         }
@@ -34,21 +86,12 @@ impl Fragment {
 }
 
 pub struct Peer {
-    ///  variance in arrival time
-    jitter: u32,
+    ///  variance in arrival time, RTP timestamp units (RFC 3550 A.8)
+    jitter: JitterEstimator,
 
-    /// highest sequence number currently received from this peer         
-    max_sequence_number: Option<u16>,
-
-    /// first sequence number received         
-    initial_sequence_number: Option<u16>,
-
-    /// number of packets received from this peer,
-    /// can differ from max-initial when packets are lost
-    packets_received: u32,
-
-    /// number of times the sequence number has rolled over from max u16 value          
-    wrap_around_count: u32,
+    /// sequence number validation and loss statistics (RFC 3550 A.1),
+    /// None until the first packet from this peer shows up
+    sequence: Option<SequenceTracker>,
 
     /// the swift context that will be receiving and decoding the payload
     swift_peer_model: *mut std::ffi::c_void,
@@ -60,48 +103,93 @@ pub struct Peer {
     min_window: u32,
 
     /// buffer where frames with the same timestamp are grouped together
+    /// kept sorted by RTP timestamp (wraparound-safe), oldest first
     playout_buffer: Vec<PlayoutBufferNode>,
 
+    /// frames dropped from the playout buffer without ever being played out
+    evicted_stale: u32,
+    evicted_capacity: u32,
+    evicted_incomplete: u32,
+
+    /// frames that never arrived themselves, but were filled in from a later packet's redundant copy
+    frames_recovered: u32,
+
+    /// frames that made it out of the playout buffer but were dropped because the renderer was backed up
+    render_dropped: u32,
+
+    /// what kind of media this peer is sending us, decides what a "complete" frame is
+    stream_type: StreamType,
+
+    /// extended sequence number of the last packet in the last released frame
+    last_released_sequence_num: Option<u32>,
+
+    /// RTP timestamp of the last released frame, anything at or before it is too late
+    last_released_timestamp: Option<u32>,
+
+    /// frames were evicted since the last release
+    gap_pending: bool,
+
+    /// arrival time (media clock units) of the most recent packet
+    last_arrival: u32,
+
     /// middle 32 bytes of the NTP timestamp as received of the last SR from this peer
     last_sr_timestamp: u32,
 
     /// Time since the last SR has been received
     delay_since_last_sr: Option<Instant>,
 
-    /// the expected number of packets received when the last SR was sent
-    expected_prior: u32,
+    /// the peer's NTP/RTP timestamp pair from its last SR
+    sender_clock: Option<SenderClock>,
+
+    /// fraction of OUR packets this peer says it lost, from its latest reception report
+    reported_fraction_lost: u8,
+
+    /// this peer can take RED (RFC 2198) audio
+    accepts_red: bool,
 
-    /// the received number of packets when the last SR was sent
-    received_prior: u32,
+    /// this peer can decode what we send, otherwise they only get RTCP
+    decodes_our_stream: bool,
+
+    /// last time any RTP or RTCP showed up from them, or when they joined
+    last_heard: Instant,
 }
 
 impl Peer {
-    pub fn new(swift_peer_model: *mut std::ffi::c_void) -> Self {
+    pub fn new(swift_peer_model: *mut std::ffi::c_void, stream_type: StreamType) -> Self {
         Self {
-            jitter: 0,
+            jitter: JitterEstimator::new(),
             delay_since_last_sr: None,
             last_sr_timestamp: 0,
-            packets_received: 0,
-            wrap_around_count: 0,
-            max_sequence_number: None,
-            initial_sequence_number: None,
+            sequence: None,
             window: VecDeque::new(),
             min_window: u32::MAX,
-            playout_buffer: Vec::with_capacity(100),
+            playout_buffer: Vec::with_capacity(MAX_PLAYOUT_BUFFER_NODES),
+            evicted_stale: 0,
+            evicted_capacity: 0,
+            evicted_incomplete: 0,
+            frames_recovered: 0,
+            render_dropped: 0,
+            stream_type,
+            last_released_sequence_num: None,
+            last_released_timestamp: None,
+            gap_pending: false,
+            last_arrival: 0,
             swift_peer_model,
-            expected_prior: 0,
-            received_prior: 0,
+            sender_clock: None,
+            reported_fraction_lost: 0,
+            accepts_red: false,
+            decodes_our_stream: true,
+            last_heard: Instant::now(),
         }
     }
 
     /// Determines the min arrival time along in a window,
-    /// along with incrementing the packet count and recalculating the jitter
+    /// along with recalculating the jitter.
+    /// `difference` is the packet's transit time: arrival - RTP timestamp
     fn set_and_get_min_window(&mut self, difference: u32) -> u32 {
-        self.packets_received += 1;
+        self.jitter.update(difference);
 
         self.window.push_front(difference);
-        let d = difference.wrapping_sub(self.window[0]) as i32;
-        self.jitter = self.jitter + (d.abs() as u32 - self.jitter) / 16;
 
         if self.window.len() > WINDOW_SIZE {
             self.window.pop_back();
@@ -117,87 +205,265 @@ impl Peer {
 
         self.min_window = min;
 
-        return self.min_window;
+        self.min_window
     }
 
-    pub fn add_node(&mut self, mut playout_buffer_node: PlayoutBufferNode, mut fragment: Fragment) {
-        // accounting for wraparound
-        if let Some(max_sequence_number) = self.max_sequence_number {
-            let delta = fragment.sequence_num - max_sequence_number;
+    fn record_eviction(&mut self, reason: EvictionReason) {
+        match reason {
+            EvictionReason::Stale => self.evicted_stale += 1,
+            EvictionReason::Capacity => self.evicted_capacity += 1,
+            EvictionReason::Incomplete => self.evicted_incomplete += 1,
+        }
 
-            if delta < MAX_DROPOUT {
-                if fragment.sequence_num < max_sequence_number {
-                    self.wrap_around_count += 1;
-                }
-                self.max_sequence_number = Some(fragment.sequence_num);
-            } else if delta <= 65535 - 100 {
-                // sequence number made a large jump
-            } else {
-                // misordered packet.
+        self.gap_pending = true;
+    }
+
+    /// Drops every frame whose playout time is more than `max_age` behind `now`.
+    /// Both are in media clock units. The buffer is ordered by timestamp,
+    /// so stale frames are always at the front.
+    fn evict_stale(&mut self, now: u32, max_age: u32) {
+        let stale = self
+            .playout_buffer
+            .iter()
+            .take_while(|node| now.wrapping_sub(node.playout_time) as i32 > max_age as i32)
+            .count();
+
+        self.playout_buffer.drain(..stale);
+
+        for _ in 0..stale {
+            self.record_eviction(EvictionReason::Stale);
+        }
+    }
+
+    pub fn add_node(
+        &mut self,
+        mut playout_buffer_node: PlayoutBufferNode,
+        mut fragment: Fragment,
+        now: u32,
+        max_age: u32,
+    ) {
+        let sequence = self
+            .sequence
+            .get_or_insert_with(|| SequenceTracker::new(fragment.sequence_num));
+
+        match sequence.update(fragment.sequence_num) {
+            SequenceStatus::Valid => {}
+            SequenceStatus::Rejected => return,
+            SequenceStatus::Restarted => {
+                // the old stream's frames and sequence numbers mean nothing anymore
+                self.playout_buffer.clear();
+                self.last_released_sequence_num = None;
+                self.last_released_timestamp = None;
+                self.gap_pending = true;
+                self.jitter = JitterEstimator::new();
             }
-        } else {
-            // this is just to initalize it, usually the first frame
-            // bad network conditions shouldn't need to be handled here
-            self.max_sequence_number = Some(fragment.sequence_num);
-            self.initial_sequence_number = Some(fragment.sequence_num);
         }
 
-        // use extended timestamp for ordering
-        fragment.extended_sequence_num =
-            fragment.sequence_num as u32 + (65536 * self.wrap_around_count);
+        // use extended sequence number for ordering, accounts for wraparound
+        fragment.extended_sequence_num = sequence.extend(fragment.sequence_num);
 
         let timestamp = playout_buffer_node.rtp_timestamp;
 
+        self.last_arrival = now;
+        self.evict_stale(now, max_age);
+
+        // the frame this belongs to has already been played out (or skipped)
+        if let Some(released) = self.last_released_timestamp
+            && compare_timestamps(timestamp, released) != Ordering::Greater
+        {
+            return;
+        }
+
         match self
             .playout_buffer
-            .binary_search_by_key(&timestamp, |node| node.rtp_timestamp)
+            .binary_search_by(|node| compare_timestamps(node.rtp_timestamp, timestamp))
         {
             Ok(index) => {
                 let coded_data = &mut self.playout_buffer[index].coded_data;
 
-                let index = coded_data
+                // duplicates are dropped, they'd break the contiguity check
+                if let Err(index) = coded_data
                     .binary_search_by_key(&fragment.extended_sequence_num, |frag| {
                         frag.extended_sequence_num
                     })
-                    .unwrap_or_else(|i| i);
-
-                coded_data.insert(index, fragment);
+                {
+                    coded_data.insert(index, fragment);
+                }
             }
             Err(index) => {
                 playout_buffer_node.coded_data.push(fragment);
-                self.playout_buffer.insert(index, playout_buffer_node);
+                self.insert_node(index, playout_buffer_node, now, max_age);
             }
         }
     }
 
-    fn update_last_sr_timestamp(&mut self, last_sr_timestamp: u32) {
-        self.last_sr_timestamp = last_sr_timestamp;
-        self.delay_since_last_sr = Some(Instant::now());
-        self.expected_prior = self.expected_num_packets();
-        self.received_prior = self.packets_received
+    /// Puts a new frame into the buffer at `index`, keeping it within MAX_PLAYOUT_BUFFER_NODES.
+    /// Returns false if the frame was already too old to bother with
+    fn insert_node(
+        &mut self,
+        index: usize,
+        playout_buffer_node: PlayoutBufferNode,
+        now: u32,
+        max_age: u32,
+    ) -> bool {
+        // a late packet for a frame that's already been played out or evicted,
+        // don't bring it back just to evict it again
+        if now.wrapping_sub(playout_buffer_node.playout_time) as i32 > max_age as i32 {
+            return false;
+        }
+
+        self.playout_buffer.insert(index, playout_buffer_node);
+
+        if self.playout_buffer.len() > MAX_PLAYOUT_BUFFER_NODES {
+            self.playout_buffer.remove(0);
+            self.record_eviction(EvictionReason::Capacity);
+        }
+
+        true
     }
 
-    fn max_extended_sequence_num(&self) -> u32 {
-        let max_sequence = self.max_sequence_number.unwrap_or(0);
-        max_sequence as u32 + (65536 * self.wrap_around_count)
+    /// Fills in a frame from a redundant copy carried by a later packet.
+    /// `distance` is how many packets before `primary_sequence_num` it was originally sent in,
+    /// so it slots into the buffer as if the original had arrived. If the original did, nothing happens.
+    pub fn add_redundant_node(
+        &mut self,
+        mut playout_buffer_node: PlayoutBufferNode,
+        primary_sequence_num: u16,
+        distance: u16,
+        data: Bytes,
+        max_age: u32,
+    ) {
+        let Some(sequence) = &self.sequence else {
+            return;
+        };
+
+        let timestamp = playout_buffer_node.rtp_timestamp;
+
+        if let Some(released) = self.last_released_timestamp
+            && compare_timestamps(timestamp, released) != Ordering::Greater
+        {
+            return;
+        }
+
+        let Err(index) = self
+            .playout_buffer
+            .binary_search_by(|node| compare_timestamps(node.rtp_timestamp, timestamp))
+        else {
+            return;
+        };
+
+        let sequence_num = primary_sequence_num.wrapping_sub(distance);
+        let mut fragment = Fragment::new(sequence_num, false, data);
+        fragment.extended_sequence_num = sequence.extend(sequence_num);
+
+        playout_buffer_node.coded_data.push(fragment);
+
+        // the primary that carried it just arrived, so its arrival time is now
+        if self.insert_node(index, playout_buffer_node, self.last_arrival, max_age) {
+            self.frames_recovered += 1;
+        }
     }
 
-    fn expected_num_packets(&self) -> u32 {
-        // I'm actually cheating a bit here,
-        // according to Perkin's, you should use the last received sequence number, not highest one
-        self.max_extended_sequence_num() - self.initial_sequence_number.unwrap_or(0) as u32
+    /// A frame is complete once it has every packet from its first to its last,
+    /// without relying on the marker bit alone:
+    /// -   no holes in the sequence numbers
+    /// -   it starts and ends on a NAL unit boundary (FU-A start and end bits)
+    /// -   its end is known, either the marker packet or the next timestamp has begun
+    ///
+    /// Audio sends one packet per frame, so any packet is a complete frame.
+    fn is_complete(&self, index: usize) -> bool {
+        let node = &self.playout_buffer[index];
+
+        let (Some(first), Some(last)) = (node.coded_data.first(), node.coded_data.last()) else {
+            return false;
+        };
+
+        match self.stream_type {
+            StreamType::Audio => true,
+            StreamType::Video => {
+                let contiguous = node.coded_data.windows(2).all(|pair| {
+                    pair[1].extended_sequence_num == pair[0].extended_sequence_num.wrapping_add(1)
+                });
+
+                let (starts_nal, _) = nal_boundaries(&first.data);
+                let (_, ends_nal) = nal_boundaries(&last.data);
+
+                let next_timestamp_begun = self
+                    .playout_buffer
+                    .get(index + 1)
+                    .and_then(|next| next.coded_data.first())
+                    .is_some_and(|next| {
+                        next.extended_sequence_num == last.extended_sequence_num.wrapping_add(1)
+                    });
+
+                contiguous && starts_nal && ends_nal && (last.marker || next_timestamp_begun)
+            }
+        }
     }
 
-    fn calculate_fraction_lost(&self) -> u8 {
-        let expected_interval = self.expected_num_packets() - self.expected_prior;
-        let received_inteval = self.packets_received - self.received_prior;
-        let lost_inteval = expected_interval as i32 - received_inteval as i32;
+    /// Hands out the oldest frame if it's ready, in decode order.
+    ///
+    /// An incomplete frame holds up everything behind it until it's due.
+    /// After that, if a newer frame is complete, the incomplete one is dropped
+    /// and the newer frame goes out flagged with a gap.
+    fn pop_next_frame(&mut self) -> Option<ReleasedFrame> {
+        loop {
+            let head = self.playout_buffer.first()?;
+            let head_due =
+                compare_timestamps(self.last_arrival, head.playout_time) != Ordering::Less;
+
+            if self.is_complete(0) {
+                let first_sequence_num = head.coded_data[0].extended_sequence_num;
 
-        if expected_interval == 0 || lost_inteval <= 0 {
-            return 0;
+                let follows_last_release = self
+                    .last_released_sequence_num
+                    .is_none_or(|seq| first_sequence_num == seq.wrapping_add(1));
+
+                // the start of this frame might still be in flight, give it until it's due
+                if !follows_last_release && !head_due {
+                    return None;
+                }
+
+                let node = self.playout_buffer.remove(0);
+                let after_gap = self.gap_pending || !follows_last_release;
+
+                self.gap_pending = false;
+                self.last_released_timestamp = Some(node.rtp_timestamp);
+                self.last_released_sequence_num = node
+                    .coded_data
+                    .last()
+                    .map(|frag| frag.extended_sequence_num);
+
+                return Some(ReleasedFrame { node, after_gap });
+            }
+
+            let newer_frame_ready = (1..self.playout_buffer.len()).any(|i| self.is_complete(i));
+
+            if !head_due || !newer_frame_ready {
+                return None;
+            }
+
+            let skipped = self.playout_buffer.remove(0);
+            self.last_released_timestamp = Some(skipped.rtp_timestamp);
+            self.record_eviction(EvictionReason::Incomplete);
+        }
+    }
+
+    fn playout_statistics(&self) -> PlayoutStatistics {
+        PlayoutStatistics {
+            frames_buffered: self.playout_buffer.len(),
+            evicted_stale: self.evicted_stale,
+            evicted_capacity: self.evicted_capacity,
+            evicted_incomplete: self.evicted_incomplete,
+            frames_recovered: self.frames_recovered,
+            render_dropped: self.render_dropped,
         }
+    }
 
-        ((lost_inteval << 8) / expected_interval as i32) as u8
+    fn update_last_sr_timestamp(&mut self, last_sr_timestamp: u32) {
+        self.last_sr_timestamp = last_sr_timestamp;
+        self.delay_since_last_sr = Some(Instant::now());
+        self.last_heard = Instant::now();
     }
 }
 
@@ -214,6 +480,10 @@ pub struct PeerManager {
     peer_addresses: DashMap<u32, SocketAddr>,
     pub rtp_session: RTPSession,
     pub delay_calculator: DelayCalculator,
+    stream_type: StreamType,
+
+    /// told whenever a peer goes, however they went
+    events: Arc<dyn SessionEvents>,
 }
 
 impl PeerManager {
@@ -222,10 +492,18 @@ impl PeerManager {
     }
 
     pub fn local_rtp_addr(&self) -> SocketAddr {
-        self.rtp_session.local_addr
+        self.rtp_session.local_addr()
+    }
+
+    pub fn stream_type(&self) -> StreamType {
+        self.stream_type
     }
 
-    pub fn new(rtp_session: RTPSession, stream_type: StreamType) -> Self {
+    pub fn new(
+        rtp_session: RTPSession,
+        stream_type: StreamType,
+        events: Arc<dyn SessionEvents>,
+    ) -> Self {
         Self {
             peers: DashMap::new(),
             peer_addresses: DashMap::new(),
@@ -234,6 +512,8 @@ impl PeerManager {
                 StreamType::Audio => 0,
                 StreamType::Video => 3000,
             }),
+            stream_type,
+            events,
         }
     }
 
@@ -254,7 +534,7 @@ impl PeerManager {
         let peers = &self.peers;
 
         if !peers.contains_key(&ssrc) {
-            peers.insert(ssrc, Peer::new(swift_peer_model));
+            peers.insert(ssrc, Peer::new(swift_peer_model, self.stream_type));
             self.peer_addresses.insert(ssrc, addr);
             self.delay_calculator.add_peer(ssrc);
             true
@@ -263,6 +543,53 @@ impl PeerManager {
         }
     }
 
+    /// Points a peer we already know at a new address (they moved networks). False if we don't know them
+    pub fn set_peer_addr(&self, ssrc: u32, addr: SocketAddr) -> bool {
+        if !self.peers.contains_key(&ssrc) {
+            return false;
+        }
+
+        self.peer_addresses.insert(ssrc, addr);
+        true
+    }
+
+    /// They left (BYE, signaling, or went quiet). Whatever's still buffered for them goes too
+    pub fn remove_peer(&self, ssrc: u32) -> bool {
+        self.peer_addresses.remove(&ssrc);
+        self.delay_calculator.remove_peer(ssrc);
+
+        let Some((_, peer)) = self.peers.remove(&ssrc) else {
+            return false;
+        };
+
+        self.events
+            .peer_left(ssrc, self.stream_type, peer.swift_peer_model);
+
+        true
+    }
+
+    /// Some RTP or RTCP just came in from them
+    pub fn heard_from(&self, ssrc: u32) {
+        if let Some(mut peer) = self.peers.get_mut(&ssrc) {
+            peer.last_heard = Instant::now();
+        }
+    }
+
+    /// Removes everyone we haven't heard from in `timeout` (RFC 3550 6.3.5), returns who went
+    pub fn remove_inactive(&self, timeout: Duration) -> Vec<u32> {
+        let inactive: Vec<u32> = self
+            .peers
+            .iter()
+            .filter(|peer| peer.last_heard.elapsed() > timeout)
+            .map(|peer| *peer.key())
+            .collect();
+
+        inactive
+            .into_iter()
+            .filter(|ssrc| self.remove_peer(*ssrc))
+            .collect()
+    }
+
     pub fn peer_get_min_window(&self, ssrc: u32, difference: u32) -> Option<u32> {
         let peers = &self.peers;
 
@@ -273,11 +600,15 @@ impl PeerManager {
         }
     }
 
+    /// `now` is the packet's arrival time in media clock units,
+    /// used to age out frames that will never complete
     pub fn add_playout_node_to_peer(
         &self,
         ssrc: u32,
         playout_buffer_node: PlayoutBufferNode,
         fragment: Fragment,
+        now: u32,
+        media_clock_rate: u32,
     ) {
         let peers = &self.peers;
 
@@ -285,7 +616,31 @@ impl PeerManager {
             return;
         };
 
-        peer.add_node(playout_buffer_node, fragment);
+        let max_age = MAX_PLAYOUT_AGE_MS * (media_clock_rate / 1000);
+
+        peer.add_node(playout_buffer_node, fragment, now, max_age);
+    }
+
+    pub fn add_redundant_node_to_peer(
+        &self,
+        ssrc: u32,
+        playout_buffer_node: PlayoutBufferNode,
+        primary_sequence_num: u16,
+        distance: u16,
+        data: Bytes,
+        media_clock_rate: u32,
+    ) {
+        let max_age = MAX_PLAYOUT_AGE_MS * (media_clock_rate / 1000);
+
+        if let Some(mut peer) = self.peers.get_mut(&ssrc) {
+            peer.add_redundant_node(
+                playout_buffer_node,
+                primary_sequence_num,
+                distance,
+                data,
+                max_age,
+            );
+        }
     }
 
     pub fn get_peers(&self) -> Vec<SocketAddr> {
@@ -295,16 +650,35 @@ impl PeerManager {
             .collect()
     }
 
-    pub fn pop_node(&self, ssrc: u32, timestamp: u32) -> Option<PlayoutBufferNode> {
-        let mut peer = self.peers.get_mut(&ssrc)?;
+    /// Everyone our media goes to, `get_peers` minus whoever can't decode it
+    pub fn get_receivers(&self) -> Vec<SocketAddr> {
+        self.peer_addresses
+            .iter()
+            .filter(|entry| {
+                self.peers
+                    .get(entry.key())
+                    .is_none_or(|peer| peer.decodes_our_stream)
+            })
+            .map(|entry| *entry.value())
+            .collect()
+    }
 
-        let Some(index) = peer.playout_buffer.iter().position(|x| x.rtp_timestamp == timestamp) else {
-            return None;
-        };
+    /// Releases the next frame from a peer's playout buffer once it's complete.
+    /// Call it in a loop, one packet can complete several frames.
+    pub fn pop_next_frame(&self, ssrc: u32) -> Option<ReleasedFrame> {
+        self.peers.get_mut(&ssrc)?.pop_next_frame()
+    }
 
-        let node  = peer.playout_buffer.remove(index);
+    /// A released frame never reached the sink, so whatever comes next from this peer follows a gap
+    pub fn record_render_drop(&self, ssrc: u32) {
+        if let Some(mut peer) = self.peers.get_mut(&ssrc) {
+            peer.render_dropped += 1;
+            peer.gap_pending = true;
+        }
+    }
 
-        Some(node)
+    pub fn get_playout_statistics(&self, ssrc: u32) -> Option<PlayoutStatistics> {
+        self.peers.get(&ssrc).map(|peer| peer.playout_statistics())
     }
 
     pub fn update_last_sr_timestamp(&self, ssrc: u32, last_sr_timestamp: u32) {
@@ -313,18 +687,83 @@ impl PeerManager {
         }
     }
 
-    pub fn get_reception_reports(&self) -> Vec<ReceptionReport> {
+    pub fn update_sender_clock(&self, ssrc: u32, ntp_time: u64, rtp_time: u32) {
+        if let Some(mut peer) = self.peers.get_mut(&ssrc) {
+            peer.sender_clock = Some(SenderClock { ntp_time, rtp_time });
+        }
+    }
+
+    /// When the peer captured the frame with `rtp_timestamp`, on their wall clock (unix ms).
+    /// None until we've had an SR from them.
+    pub fn capture_time_ms(
+        &self,
+        ssrc: u32,
+        rtp_timestamp: u32,
+        media_clock_rate: u32,
+    ) -> Option<i64> {
+        let peer = self.peers.get(&ssrc)?;
+        let sender_clock = peer.sender_clock?;
+
+        Some(sender_clock.capture_time_ms(rtp_timestamp, media_clock_rate))
+    }
+
+    /// `reporter_ssrc` sent us a reception report about our own stream
+    pub fn update_reported_loss(&self, reporter_ssrc: u32, fraction_lost: u8) {
+        if let Some(mut peer) = self.peers.get_mut(&reporter_ssrc) {
+            peer.reported_fraction_lost = fraction_lost;
+        }
+    }
+
+    /// The worst loss any peer is reporting. We send the same packets to everyone,
+    /// so the worst link decides how much redundancy goes out.
+    pub fn max_reported_fraction_lost(&self) -> u8 {
         self.peers
             .iter()
-            .map(|peer| {
-                // this isn't even funny omg
-                // I don't want to talk about this
-                ReceptionReport {
-                    reportee_ssrc: *peer.key(),
-                    fraction_lost: peer.calculate_fraction_lost(),
-                    total_lost: peer.expected_num_packets() - peer.packets_received,
-                    extended_sequence_number: peer.max_extended_sequence_num(),
-                    jitter: peer.jitter,
+            .map(|peer| peer.reported_fraction_lost)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn set_peer_accepts_red(&self, ssrc: u32, accepts_red: bool) {
+        if let Some(mut peer) = self.peers.get_mut(&ssrc) {
+            peer.accepts_red = accepts_red;
+        }
+    }
+
+    pub fn set_peer_decodes_our_stream(&self, ssrc: u32, decodes: bool) {
+        if let Some(mut peer) = self.peers.get_mut(&ssrc) {
+            peer.decodes_our_stream = decodes;
+        }
+    }
+
+    /// RED only goes out when every peer we send to can unpack it
+    pub fn all_peers_accept_red(&self) -> bool {
+        self.peers
+            .iter()
+            .filter(|peer| peer.decodes_our_stream)
+            .all(|peer| peer.accepts_red)
+    }
+
+    /// Builds a report block for every peer we've heard from.
+    /// This moves the fraction lost interval forward, so only call it when sending a report.
+    pub fn get_reception_reports(&self) -> Vec<ReceptionReport> {
+        self.peers
+            .iter_mut()
+            .filter_map(|mut peer| {
+                let reportee_ssrc = *peer.key();
+                let sequence = peer.sequence.as_mut()?;
+
+                let fraction_lost = sequence.fraction_lost();
+                // 24 bit two's complement on the wire, serialize only keeps the low 3 bytes
+                let total_lost = sequence.cumulative_lost() as u32;
+                let extended_sequence_number = sequence.extended_max();
+
+                Some(ReceptionReport {
+                    reportee_ssrc,
+                    fraction_lost,
+                    total_lost,
+                    extended_sequence_number,
+                    jitter: peer.jitter.jitter(),
                     last_sr_timestamp: peer.last_sr_timestamp,
                     delay_since_last_sr: match peer.delay_since_last_sr {
                         None => 0,
@@ -334,8 +773,347 @@ impl PeerManager {
                             (seconds * 65536) as u32
                         }
                     },
-                }
+                })
             })
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::c_void, sync::Mutex};
+
+    /// remembers who left
+    #[derive(Default)]
+    struct Departures(Mutex<Vec<(u32, StreamType)>>);
+
+    impl SessionEvents for Departures {
+        fn video_peer_joined(
+            &self,
+            _ssrc: u32,
+            _media_addr: SocketAddr,
+            _pps: &[u8],
+            _sps: &[u8],
+        ) -> *mut c_void {
+            std::ptr::null_mut()
+        }
+
+        fn audio_peer_joined(
+            &self,
+            _ssrc: u32,
+            _sample_rate: f64,
+            _channels: u32,
+            _use_inband_fec: bool,
+        ) -> *mut c_void {
+            std::ptr::null_mut()
+        }
+
+        fn peer_left(&self, ssrc: u32, stream_type: StreamType, _context: *mut c_void) {
+            self.0.lock().unwrap().push((ssrc, stream_type));
+        }
+    }
+
+    #[test]
+    fn quiet_peers_are_removed_once() {
+        let departures = Arc::new(Departures::default());
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        let peer_manager = PeerManager::new(
+            RTPSession::new(addr, 48000),
+            StreamType::Audio,
+            Arc::clone(&departures) as Arc<dyn SessionEvents>,
+        );
+
+        peer_manager.add_peer(1, addr, std::ptr::null_mut());
+        peer_manager.add_peer(2, addr, std::ptr::null_mut());
+
+        std::thread::sleep(Duration::from_millis(20));
+        peer_manager.heard_from(2);
+
+        assert_eq!(
+            peer_manager.remove_inactive(Duration::from_millis(10)),
+            vec![1]
+        );
+        assert!(peer_manager.get_context(1).is_none());
+        assert!(peer_manager.get_context(2).is_some());
+
+        // already gone, shouldn't be announced twice
+        assert!(!peer_manager.remove_peer(1));
+
+        let departures = departures.0.lock().unwrap();
+        assert_eq!(departures.len(), 1);
+        assert!(matches!(departures[0], (1, StreamType::Audio)));
+    }
+
+    /// plenty, so nothing goes stale unless a test means it to
+    const MAX_AGE: u32 = 90_000;
+
+    /// single NAL unit packet, a non-IDR slice
+    const SLICE: &[u8] = &[0x41, 0x9a];
+
+    /// FU-A fragments of an IDR slice
+    const FU_START: &[u8] = &[0x7c, 0x85, 0];
+    const FU_MIDDLE: &[u8] = &[0x7c, 0x05, 0];
+    const FU_END: &[u8] = &[0x7c, 0x45, 0];
+
+    /// one packet arriving at `now`, due to play out at its own timestamp
+    fn packet(
+        peer: &mut Peer,
+        timestamp: u32,
+        sequence_num: u16,
+        marker: bool,
+        payload: &[u8],
+        now: u32,
+    ) {
+        let node = PlayoutBufferNode {
+            rtp_timestamp: timestamp,
+            playout_time: timestamp,
+            coded_data: Vec::new(),
+        };
+        let fragment = Fragment::new(sequence_num, marker, Bytes::copy_from_slice(payload));
+
+        peer.add_node(node, fragment, now, MAX_AGE);
+    }
+
+    /// A video peer past probation. Sequence number 1 (timestamp 3000) has already been played out
+    fn video_peer() -> Peer {
+        let mut peer = Peer::new(std::ptr::null_mut(), StreamType::Video);
+
+        // the first packet only starts probation
+        packet(&mut peer, 0, 0, true, SLICE, 0);
+        packet(&mut peer, 3000, 1, true, SLICE, 3000);
+        assert!(peer.pop_next_frame().is_some());
+
+        peer
+    }
+
+    fn sequence_nums(frame: &ReleasedFrame) -> Vec<u32> {
+        frame
+            .node
+            .coded_data
+            .iter()
+            .map(|fragment| fragment.extended_sequence_num)
+            .collect()
+    }
+
+    #[test]
+    fn timestamps_compare_across_wraparound() {
+        assert_eq!(compare_timestamps(5, 5), Ordering::Equal);
+        assert_eq!(compare_timestamps(3000, 0), Ordering::Greater);
+        assert_eq!(compare_timestamps(2, u32::MAX - 2), Ordering::Greater);
+        assert_eq!(compare_timestamps(u32::MAX - 2, 2), Ordering::Less);
+
+        // just under half the range apart is still in order
+        assert_eq!(compare_timestamps(0x7fff_ffff, 0), Ordering::Greater);
+    }
+
+    #[test]
+    fn frames_past_their_playout_time_are_evicted() {
+        let mut peer = video_peer();
+
+        // never completes
+        packet(&mut peer, 6000, 2, false, FU_START, 3000);
+        assert_eq!(peer.playout_statistics().frames_buffered, 1);
+
+        // still within MAX_AGE of its playout time
+        packet(&mut peer, 200_000, 3, true, SLICE, 6000 + MAX_AGE);
+        assert_eq!(peer.playout_statistics().evicted_stale, 0);
+
+        packet(&mut peer, 200_000, 4, true, SLICE, 6000 + MAX_AGE + 1);
+
+        let statistics = peer.playout_statistics();
+        assert_eq!(statistics.evicted_stale, 1);
+        assert_eq!(statistics.frames_buffered, 1);
+        assert_eq!(peer.playout_buffer[0].rtp_timestamp, 200_000);
+    }
+
+    #[test]
+    fn a_full_buffer_evicts_the_oldest_frame() {
+        const FRAME: u32 = 960;
+        const EXTRA: usize = 5;
+
+        // the timestamps wrap partway through
+        let start = u32::MAX - 20 * FRAME;
+        let mut peer = Peer::new(std::ptr::null_mut(), StreamType::Audio);
+
+        packet(&mut peer, start, 0, true, &[0], start);
+        packet(&mut peer, start, 1, true, &[0], start);
+        assert!(peer.pop_next_frame().is_some());
+
+        for i in 0..MAX_PLAYOUT_BUFFER_NODES + EXTRA {
+            let timestamp = start.wrapping_add((i as u32 + 1) * FRAME);
+            packet(&mut peer, timestamp, i as u16 + 2, true, &[0], start);
+        }
+
+        let statistics = peer.playout_statistics();
+        assert_eq!(statistics.frames_buffered, MAX_PLAYOUT_BUFFER_NODES);
+        assert_eq!(statistics.evicted_capacity, EXTRA as u32);
+        assert_eq!(statistics.evicted_stale, 0);
+        assert_eq!(statistics.evicted_incomplete, 0);
+
+        // the first EXTRA frames went, in timestamp order
+        assert_eq!(
+            peer.playout_buffer[0].rtp_timestamp,
+            start.wrapping_add((EXTRA as u32 + 1) * FRAME)
+        );
+
+        // whatever comes out next goes with a gap
+        assert!(peer.gap_pending);
+    }
+
+    #[test]
+    fn redundant_copies_respect_the_buffer_limits() {
+        const FRAME: u32 = 960;
+
+        let mut peer = Peer::new(std::ptr::null_mut(), StreamType::Audio);
+
+        packet(&mut peer, 0, 0, true, &[0], 0);
+        packet(&mut peer, 0, 1, true, &[0], 0);
+        assert!(peer.pop_next_frame().is_some());
+
+        // every other frame, so there's room for redundant copies in between
+        let mut sequence_num = 2;
+        for i in 0..MAX_PLAYOUT_BUFFER_NODES as u32 {
+            let timestamp = (i + 1) * 2 * FRAME;
+            packet(&mut peer, timestamp, sequence_num, true, &[0], 0);
+            sequence_num += 1;
+        }
+
+        let redundant = |timestamp: u32, playout_time: u32| PlayoutBufferNode {
+            rtp_timestamp: timestamp,
+            playout_time,
+            coded_data: Vec::new(),
+        };
+
+        // a full buffer still makes room for it the same way
+        peer.add_redundant_node(
+            redundant(3 * FRAME, 3 * FRAME),
+            sequence_num - 1,
+            1,
+            Bytes::from_static(&[0]),
+            MAX_AGE,
+        );
+
+        let statistics = peer.playout_statistics();
+        assert_eq!(statistics.frames_buffered, MAX_PLAYOUT_BUFFER_NODES);
+        assert_eq!(statistics.evicted_capacity, 1);
+        assert_eq!(statistics.frames_recovered, 1);
+        assert_eq!(peer.playout_buffer[0].rtp_timestamp, 3 * FRAME);
+
+        // everything before it goes stale
+        let now = 1_000_000;
+        packet(&mut peer, now, sequence_num, true, &[0], now);
+
+        // and so is a copy that was due too long ago
+        peer.add_redundant_node(
+            redundant(now - FRAME, now - MAX_AGE - 1),
+            sequence_num,
+            1,
+            Bytes::from_static(&[0]),
+            MAX_AGE,
+        );
+
+        let statistics = peer.playout_statistics();
+        assert_eq!(statistics.frames_buffered, 1);
+        assert_eq!(statistics.frames_recovered, 1);
+    }
+
+    #[test]
+    fn reordered_packets_still_make_a_frame() {
+        let mut peer = video_peer();
+
+        packet(&mut peer, 6000, 4, true, FU_END, 3000);
+        assert!(peer.pop_next_frame().is_none());
+
+        packet(&mut peer, 6000, 2, false, FU_START, 3000);
+        assert!(peer.pop_next_frame().is_none());
+
+        packet(&mut peer, 6000, 3, false, FU_MIDDLE, 3000);
+        let frame = peer.pop_next_frame().unwrap();
+
+        assert_eq!(sequence_nums(&frame), vec![2, 3, 4]);
+        assert!(!frame.after_gap);
+    }
+
+    #[test]
+    fn a_lost_marker_is_made_up_for_by_the_next_frame() {
+        let mut peer = video_peer();
+
+        // nothing says this is the frame's last packet yet
+        packet(&mut peer, 6000, 2, false, SLICE, 3000);
+        assert!(peer.pop_next_frame().is_none());
+
+        // the next timestamp starting right after it does
+        packet(&mut peer, 9000, 3, true, SLICE, 3000);
+
+        let first = peer.pop_next_frame().unwrap();
+        assert_eq!(first.node.rtp_timestamp, 6000);
+        assert!(!first.after_gap);
+
+        let second = peer.pop_next_frame().unwrap();
+        assert_eq!(second.node.rtp_timestamp, 9000);
+        assert!(!second.after_gap);
+    }
+
+    #[test]
+    fn fu_a_frames_need_their_start_and_end() {
+        let mut peer = video_peer();
+
+        // the start fragment never arrived, the rest looks contiguous
+        packet(&mut peer, 6000, 2, false, FU_MIDDLE, 3000);
+        packet(&mut peer, 6000, 3, true, FU_END, 3000);
+        assert!(!peer.is_complete(0));
+
+        // a marker bit isn't enough without the end fragment
+        packet(&mut peer, 9000, 4, false, FU_START, 3000);
+        packet(&mut peer, 9000, 5, true, FU_MIDDLE, 3000);
+        assert!(!peer.is_complete(1));
+
+        packet(&mut peer, 12000, 6, false, FU_START, 3000);
+        packet(&mut peer, 12000, 7, true, FU_END, 3000);
+        assert!(peer.is_complete(2));
+    }
+
+    #[test]
+    fn frames_after_a_gap_wait_until_theyre_due() {
+        let mut peer = video_peer();
+
+        // sequence number 2 is lost, it might still be on its way
+        packet(&mut peer, 6000, 3, true, SLICE, 3000);
+        assert!(peer.pop_next_frame().is_none());
+
+        packet(&mut peer, 9000, 4, true, SLICE, 6000);
+
+        let late = peer.pop_next_frame().unwrap();
+        assert_eq!(late.node.rtp_timestamp, 6000);
+        assert!(late.after_gap);
+
+        let next = peer.pop_next_frame().unwrap();
+        assert_eq!(next.node.rtp_timestamp, 9000);
+        assert!(!next.after_gap);
+    }
+
+    #[test]
+    fn incomplete_frames_are_dropped_for_a_newer_one() {
+        let mut peer = video_peer();
+
+        // the end of this frame (sequence number 3) is lost
+        packet(&mut peer, 6000, 2, false, FU_START, 3000);
+        packet(&mut peer, 9000, 4, true, SLICE, 6000);
+
+        // the incomplete frame's due and goes, the next one waits for its own turn
+        assert!(peer.pop_next_frame().is_none());
+        assert_eq!(peer.playout_statistics().evicted_incomplete, 1);
+
+        packet(&mut peer, 12000, 5, true, SLICE, 9000);
+
+        let frame = peer.pop_next_frame().unwrap();
+        assert_eq!(frame.node.rtp_timestamp, 9000);
+        assert!(frame.after_gap);
+
+        // too late, the frame it belonged to is gone
+        packet(&mut peer, 6000, 3, true, FU_END, 9000);
+        assert_eq!(peer.playout_statistics().frames_buffered, 1);
+    }
+}
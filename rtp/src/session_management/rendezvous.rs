@@ -0,0 +1,397 @@
+/*
+    A rendezvous server, so nobody has to pass around someone's ip:port to get into a call.
+
+    It only knows who's in which room. A client connects, sends JoinRoom with a room ID
+    and its own participant details, and gets back everyone else in that room. It then
+    joins each of them directly through their signaling servers like it always has,
+    no media or call state goes through here.
+
+    The connection stays open with the usual heartbeats. Whenever someone joins, updates
+    their streams or leaves, the rest of the room hears about it. Going quiet counts as
+    leaving. Rooms exist while someone's in them.
+
+    src/bin/rendezvous.rs runs one of these.
+*/
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio::{
+    io,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{interval, timeout},
+};
+
+use crate::session_management::{
+    signaling_protocol::{
+        LeaveArgs, ParticipantArgs, SignalingMessage, receive_message, send_message,
+    },
+    signaling_server::{HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT, send_error},
+};
+
+/// relayed events waiting to go out to one member, a member this far behind isn't keeping up
+const MEMBER_QUEUE: usize = 32;
+
+struct Member {
+    /// which connection this is, someone reconnecting replaces their old one
+    id: u64,
+    participant: ParticipantArgs,
+    tx: mpsc::Sender<SignalingMessage>,
+}
+
+/// Rooms by ID, members by CNAME
+type Rooms = HashMap<String, HashMap<String, Member>>;
+
+pub struct Rendezvous {
+    rooms: Mutex<Rooms>,
+    next_member_id: AtomicU64,
+}
+
+impl Rendezvous {
+    pub fn new() -> Self {
+        Self {
+            rooms: Mutex::new(HashMap::new()),
+            next_member_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Accepts clients until the listener fails
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (socket, addr) = listener.accept().await?;
+            let rendezvous = Arc::clone(&self);
+
+            tokio::spawn(async move {
+                if let Err(e) = rendezvous.handle_client(socket).await {
+                    eprintln!("Rendezvous client {} failed: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// Everyone in a room right now, mostly for logging and tests
+    pub fn members(&self, room: &str) -> Vec<ParticipantArgs> {
+        let rooms = self.rooms.lock().unwrap();
+
+        rooms
+            .get(room)
+            .map(|members| members.values().map(|m| m.participant.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    async fn handle_client(&self, mut socket: TcpStream) -> io::Result<()> {
+        let (room, participant) = match timeout(HEARTBEAT_TIMEOUT, receive_message(&mut socket))
+            .await
+        {
+            Ok(Ok(Some(SignalingMessage::JoinRoom { room, participant }))) => (room, participant),
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Ok(Some(_))) => {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "Expected a JoinRoom");
+                let _ = send_error(&mut socket, &e).await;
+                return Err(e);
+            }
+            Ok(Err(e)) => {
+                let _ = send_error(&mut socket, &e).await;
+                return Err(e);
+            }
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "No JoinRoom"));
+            }
+        };
+
+        let id = self.next_member_id.fetch_add(1, Ordering::Relaxed);
+        let cname = participant.cname.clone();
+        let (tx, mut rx) = mpsc::channel::<SignalingMessage>(MEMBER_QUEUE);
+
+        // who's already here, and telling them about the new arrival, in one go.
+        // Otherwise two people joining at once could each miss the other
+        let others = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let members = rooms.entry(room.clone()).or_default();
+
+            let others: Vec<ParticipantArgs> = members
+                .iter()
+                .filter(|(member_cname, _)| **member_cname != cname)
+                .map(|(_, member)| member.participant.clone())
+                .collect();
+
+            relay(members, &cname, SignalingMessage::Join(participant.clone()));
+
+            // reconnecting after a network change, same person, new connection
+            members.insert(
+                cname.clone(),
+                Member {
+                    id,
+                    participant,
+                    tx: tx.clone(),
+                },
+            );
+
+            others
+        };
+
+        println!("{} joined room {}", cname, room);
+
+        let joined = SignalingMessage::RoomJoined {
+            participants: others,
+        };
+
+        let (mut reader, mut writer) = socket.into_split();
+
+        let writing = async {
+            send_message(&mut writer, &joined).await?;
+
+            let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+
+            loop {
+                let message = tokio::select! {
+                    _ = heartbeat.tick() => SignalingMessage::Ping,
+                    Some(message) = rx.recv() => message,
+                };
+
+                send_message(&mut writer, &message).await?;
+            }
+        };
+
+        let reading = async {
+            loop {
+                let message = match timeout(HEARTBEAT_TIMEOUT, receive_message(&mut reader)).await {
+                    Ok(Ok(Some(message))) => message,
+                    Ok(Ok(None)) => return Ok(()),
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "No heartbeat"));
+                    }
+                };
+
+                match message {
+                    SignalingMessage::Ping => {
+                        let _ = tx.try_send(SignalingMessage::Pong);
+                    }
+                    SignalingMessage::Pong => {}
+                    SignalingMessage::UpdateMedia(update) => {
+                        self.update_member(&room, &cname, id, update);
+                    }
+                    SignalingMessage::Leave(_) => return Ok(()),
+                    _ => {
+                        let _ = tx.try_send(SignalingMessage::Error {
+                            message: "Unexpected message in a room".to_string(),
+                        });
+                    }
+                }
+            }
+        };
+
+        let result: io::Result<()> = tokio::select! {
+            result = writing => result,
+            result = reading => result,
+        };
+
+        self.remove_member(&room, &cname, id);
+
+        result
+    }
+
+    /// Their streams changed, everyone else gets the new details
+    /// The CNAME they joined with sticks, whatever the update says
+    fn update_member(&self, room: &str, cname: &str, id: u64, mut update: ParticipantArgs) {
+        let mut rooms = self.rooms.lock().unwrap();
+
+        let Some(members) = rooms.get_mut(room) else {
+            return;
+        };
+
+        update.cname = cname.to_string();
+
+        match members.get_mut(cname) {
+            Some(member) if member.id == id => member.participant = update.clone(),
+            _ => return,
+        }
+
+        relay(members, cname, SignalingMessage::UpdateMedia(update));
+    }
+
+    /// Tells the room they're gone, unless a newer connection of theirs took over
+    fn remove_member(&self, room: &str, cname: &str, id: u64) {
+        let mut rooms = self.rooms.lock().unwrap();
+
+        let Some(members) = rooms.get_mut(room) else {
+            return;
+        };
+
+        if members.get(cname).is_none_or(|member| member.id != id) {
+            return;
+        }
+
+        let Some(member) = members.remove(cname) else {
+            return;
+        };
+
+        println!("{} left room {}", cname, room);
+
+        let leave = LeaveArgs {
+            signaling_address: member.participant.signaling_address,
+            ssrcs: member.participant.media.iter().map(|m| m.ssrc).collect(),
+        };
+        relay(members, cname, SignalingMessage::Leave(leave));
+
+        if members.is_empty() {
+            rooms.remove(room);
+        }
+    }
+}
+
+impl Default for Rendezvous {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sends to everyone in the room except `from`. Whoever's queue is full misses it,
+/// their heartbeat's probably about to time out anyway
+fn relay(members: &HashMap<String, Member>, from: &str, message: SignalingMessage) {
+    for (cname, member) in members {
+        if cname == from {
+            continue;
+        }
+
+        if member.tx.try_send(message.clone()).is_err() {
+            eprintln!("{} isn't keeping up, dropped a room event", cname);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_management::{
+        capabilities::Capabilities,
+        signaling_protocol::{MediaSection, StreamTypeWithArgs},
+    };
+
+    fn participant(cname: &str, port: u16) -> ParticipantArgs {
+        ParticipantArgs {
+            signaling_address: format!("127.0.0.1:{}", port),
+            cname: cname.to_string(),
+            media: vec![MediaSection {
+                local_rtp_address: format!("127.0.0.1:{}", port + 1),
+                ssrc: u32::from(port),
+                stream_type: StreamTypeWithArgs::Audio {
+                    sample_rate: 48000.0,
+                    channels: 1,
+                    use_inband_fec: true,
+                    red: true,
+                },
+            }],
+            capabilities: Capabilities::ours(true),
+        }
+    }
+
+    async fn start() -> (Arc<Rendezvous>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let rendezvous = Arc::new(Rendezvous::new());
+
+        tokio::spawn(Arc::clone(&rendezvous).serve(listener));
+
+        (rendezvous, addr)
+    }
+
+    /// Joins and returns who was already there
+    async fn join(addr: &str, room: &str, me: ParticipantArgs) -> (TcpStream, Vec<String>) {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+
+        let join = SignalingMessage::JoinRoom {
+            room: room.to_string(),
+            participant: me,
+        };
+        send_message(&mut socket, &join).await.unwrap();
+
+        let Some(SignalingMessage::RoomJoined { participants }) =
+            receive_message(&mut socket).await.unwrap()
+        else {
+            panic!("expected a RoomJoined");
+        };
+
+        let cnames = participants.into_iter().map(|p| p.cname).collect();
+        (socket, cnames)
+    }
+
+    /// The next relayed event, skipping heartbeats
+    async fn next_event(socket: &mut TcpStream) -> SignalingMessage {
+        loop {
+            match receive_message(socket).await.unwrap() {
+                Some(SignalingMessage::Ping) => {}
+                Some(message) => return message,
+                None => panic!("rendezvous hung up"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rooms_relay_arrivals_and_departures() {
+        let (rendezvous, addr) = start().await;
+
+        let (mut alice, already_there) = join(&addr, "standup", participant("alice", 4000)).await;
+        assert!(already_there.is_empty());
+
+        let (bob, already_there) = join(&addr, "standup", participant("bob", 5000)).await;
+        assert_eq!(already_there, vec!["alice".to_string()]);
+
+        // somebody else's call, nobody here should hear about it
+        let (_carol, already_there) = join(&addr, "retro", participant("carol", 6000)).await;
+        assert!(already_there.is_empty());
+
+        let SignalingMessage::Join(arrived) = next_event(&mut alice).await else {
+            panic!("expected bob's Join");
+        };
+        assert_eq!(arrived.cname, "bob");
+
+        drop(bob);
+
+        let SignalingMessage::Leave(left) = next_event(&mut alice).await else {
+            panic!("expected bob's Leave");
+        };
+        assert_eq!(left.signaling_address, "127.0.0.1:5000");
+        assert_eq!(left.ssrcs, vec![5000]);
+
+        assert_eq!(rendezvous.members("standup").len(), 1);
+        assert_eq!(rendezvous.members("retro").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnecting_replaces_the_old_connection() {
+        let (rendezvous, addr) = start().await;
+
+        let (mut alice, _) = join(&addr, "standup", participant("alice", 4000)).await;
+        let (old_bob, _) = join(&addr, "standup", participant("bob", 5000)).await;
+
+        // bob moved networks and came back before the old connection noticed
+        let (_new_bob, already_there) = join(&addr, "standup", participant("bob", 7000)).await;
+        assert_eq!(already_there, vec!["alice".to_string()]);
+
+        drop(old_bob);
+
+        for port in ["127.0.0.1:5000", "127.0.0.1:7000"] {
+            let SignalingMessage::Join(arrived) = next_event(&mut alice).await else {
+                panic!("expected bob's Join");
+            };
+            assert_eq!(arrived.signaling_address, port);
+        }
+
+        // the old connection closing didn't take the new one with it
+        let members = rendezvous.members("standup");
+        assert_eq!(members.len(), 2);
+        assert!(
+            members
+                .iter()
+                .any(|m| m.signaling_address == "127.0.0.1:7000")
+        );
+    }
+}
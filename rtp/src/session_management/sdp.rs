@@ -0,0 +1,702 @@
+/*
+    Session descriptions (RFC 8866, the one that replaced 4566) for our streams.
+
+    Signaling between our own peers is still the JSON in signaling_protocol.rs, this is for
+    everything else: writing a .sdp file gstreamer or ffplay can open, or reading one ffmpeg
+    wrote. Only covers what we actually send:
+
+    - Opus (RFC 7587), optionally with RED (RFC 2198) next to it
+    - H.264 (RFC 6184), packetization-mode 1, parameter sets in sprop-parameter-sets
+
+    plus the SSRC and CNAME of each stream (RFC 5576) and any rtcp-fb lines (RFC 4585).
+    RTCP is always on the port after RTP, so there's no a=rtcp.
+*/
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use tokio::io;
+
+use crate::{
+    interop::StreamType,
+    packets::rtp::{h264::H264_PAYLOAD_TYPE, opus::OPUS_PAYLOAD_TYPE, red::RED_PAYLOAD_TYPE},
+    session_management::{
+        capabilities::{Capabilities, Codec, CodecCapability, Feature},
+        signaling_protocol::{MediaSection, ParticipantArgs, StreamTypeWithArgs},
+    },
+};
+
+/// RFC 7587 says the rtpmap is always this, whatever the encoder's actually doing
+const OPUS_RTPMAP: &str = "opus/48000/2";
+
+/// same as what our jitter buffer's set up for
+const OPUS_DEFAULT_SAMPLE_RATE: f64 = 48000.0;
+
+pub struct SessionDescription {
+    /// the o= line's session id, anything unique enough
+    pub session_id: u64,
+
+    /// c= line, every stream's on it
+    pub address: IpAddr,
+
+    pub media: Vec<MediaDescription>,
+}
+
+/// One m= section
+pub struct MediaDescription {
+    pub port: u16,
+
+    /// the codec's payload type, RED rides along with its own for audio
+    pub payload_type: u8,
+
+    /// None if the description didn't say (ffmpeg doesn't)
+    pub ssrc: Option<u32>,
+    pub cname: Option<String>,
+
+    /// whatever follows `a=rtcp-fb:<pt>`, like "nack pli". We don't act on any of them yet
+    pub rtcp_feedback: Vec<String>,
+
+    pub stream_type: StreamTypeWithArgs,
+}
+
+impl SessionDescription {
+    /// Everything in a participant's Join, as SDP
+    pub fn from_participant(participant: &ParticipantArgs) -> io::Result<Self> {
+        let mut address = None;
+        let mut media = Vec::new();
+
+        for section in &participant.media {
+            let rtp_addr: SocketAddr = section
+                .local_rtp_address
+                .parse()
+                .map_err(|e| invalid(format!("bad RTP address, {}", e)))?;
+
+            // our streams all come off the same interface
+            address.get_or_insert(rtp_addr.ip());
+
+            let codec = match section.stream_type.kind() {
+                StreamType::Audio => Codec::Opus,
+                StreamType::Video => Codec::H264,
+            };
+
+            let mut rtcp_feedback = Vec::new();
+            if participant.capabilities.supports(codec, Feature::Nack) {
+                rtcp_feedback.push("nack".to_string());
+            }
+
+            media.push(MediaDescription {
+                port: rtp_addr.port(),
+                payload_type: match codec {
+                    Codec::Opus => OPUS_PAYLOAD_TYPE,
+                    _ => H264_PAYLOAD_TYPE,
+                },
+                ssrc: Some(section.ssrc),
+                cname: Some(participant.cname.clone()),
+                rtcp_feedback,
+                stream_type: section.stream_type.clone(),
+            });
+        }
+
+        let signaling_addr: SocketAddr = participant
+            .signaling_address
+            .parse()
+            .map_err(|e| invalid(format!("bad signaling address, {}", e)))?;
+
+        Ok(Self {
+            session_id: u64::from(media.first().and_then(|m| m.ssrc).unwrap_or(0)),
+            address: address.unwrap_or(signaling_addr.ip()),
+            media,
+        })
+    }
+
+    /// The other way around. Every stream needs an SSRC and CNAME, ours always have them.
+    /// SDP offers what it sends, so that's what they're taken to decode
+    pub fn to_participant(&self, signaling_address: String) -> io::Result<ParticipantArgs> {
+        let mut cname = None;
+        let mut media = Vec::new();
+        let mut codecs = Vec::new();
+
+        for description in &self.media {
+            let (Some(ssrc), Some(description_cname)) = (description.ssrc, &description.cname)
+            else {
+                return Err(invalid("m= section without an a=ssrc cname"));
+            };
+
+            if *cname.get_or_insert(description_cname) != description_cname {
+                return Err(invalid("streams from more than one participant"));
+            }
+
+            media.push(MediaSection {
+                local_rtp_address: SocketAddr::new(self.address, description.port).to_string(),
+                ssrc,
+                stream_type: description.stream_type.clone(),
+            });
+
+            codecs.push(description.capability());
+        }
+
+        Ok(ParticipantArgs {
+            signaling_address,
+            cname: cname.cloned().unwrap_or_default(),
+            media,
+            capabilities: Capabilities { codecs },
+        })
+    }
+}
+
+impl MediaDescription {
+    fn capability(&self) -> CodecCapability {
+        let mut features = Vec::new();
+
+        if self.rtcp_feedback.iter().any(|feedback| feedback == "nack") {
+            features.push(Feature::Nack);
+        }
+
+        match &self.stream_type {
+            StreamTypeWithArgs::Audio {
+                use_inband_fec,
+                red,
+                ..
+            } => {
+                if *red {
+                    features.push(Feature::Red);
+                }
+                if *use_inband_fec {
+                    features.push(Feature::InbandFec);
+                }
+
+                CodecCapability {
+                    codec: Codec::Opus,
+                    profile_level_id: None,
+                    features,
+                }
+            }
+            StreamTypeWithArgs::Video { sps, .. } => CodecCapability {
+                codec: Codec::H264,
+                profile_level_id: sps
+                    .get(1..4)
+                    .map(|p| format!("{:02x}{:02x}{:02x}", p[0], p[1], p[2])),
+                features,
+            },
+        }
+    }
+}
+
+impl fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address = connection_address(self.address);
+
+        write!(f, "v=0\r\n")?;
+        write!(f, "o=- {} 0 {}\r\n", self.session_id, address)?;
+        write!(f, "s=-\r\n")?;
+        write!(f, "c={}\r\n", address)?;
+        write!(f, "t=0 0\r\n")?;
+
+        for media in &self.media {
+            write!(f, "{}", media)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for MediaDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pt = self.payload_type;
+
+        match &self.stream_type {
+            StreamTypeWithArgs::Audio {
+                sample_rate,
+                channels,
+                use_inband_fec,
+                red,
+            } => {
+                if *red {
+                    write!(
+                        f,
+                        "m=audio {} RTP/AVP {} {}\r\n",
+                        self.port, pt, RED_PAYLOAD_TYPE
+                    )?;
+                } else {
+                    write!(f, "m=audio {} RTP/AVP {}\r\n", self.port, pt)?;
+                }
+
+                let stereo = u8::from(*channels > 1);
+
+                write!(f, "a=rtpmap:{} {}\r\n", pt, OPUS_RTPMAP)?;
+                write!(
+                    f,
+                    "a=fmtp:{} minptime=10;useinbandfec={};stereo={};sprop-stereo={};sprop-maxcapturerate={}\r\n",
+                    pt,
+                    u8::from(*use_inband_fec),
+                    stereo,
+                    stereo,
+                    *sample_rate as u32,
+                )?;
+
+                if *red {
+                    write!(f, "a=rtpmap:{} red/48000/2\r\n", RED_PAYLOAD_TYPE)?;
+                    write!(f, "a=fmtp:{} {}/{}\r\n", RED_PAYLOAD_TYPE, pt, pt)?;
+                }
+            }
+            StreamTypeWithArgs::Video { pps, sps } => {
+                write!(f, "m=video {} RTP/AVP {}\r\n", self.port, pt)?;
+                write!(f, "a=rtpmap:{} H264/90000\r\n", pt)?;
+
+                let mut fmtp = String::from("packetization-mode=1");
+
+                // profile_idc, constraint flags and level_idc, right after the NAL header
+                if let Some(profile_level) = sps.get(1..4) {
+                    write!(
+                        fmtp,
+                        ";profile-level-id={:02x}{:02x}{:02x}",
+                        profile_level[0], profile_level[1], profile_level[2]
+                    )?;
+                }
+
+                write!(
+                    fmtp,
+                    ";sprop-parameter-sets={},{}",
+                    base64_encode(sps),
+                    base64_encode(pps)
+                )?;
+
+                write!(f, "a=fmtp:{} {}\r\n", pt, fmtp)?;
+            }
+        }
+
+        for feedback in &self.rtcp_feedback {
+            write!(f, "a=rtcp-fb:{} {}\r\n", pt, feedback)?;
+        }
+
+        if let Some(ssrc) = self.ssrc {
+            match &self.cname {
+                Some(cname) => write!(f, "a=ssrc:{} cname:{}\r\n", ssrc, cname)?,
+                None => write!(f, "a=ssrc:{}\r\n", ssrc)?,
+            }
+        }
+
+        write!(f, "a=sendrecv\r\n")
+    }
+}
+
+impl FromStr for SessionDescription {
+    type Err = io::Error;
+
+    fn from_str(sdp: &str) -> io::Result<Self> {
+        let mut session_id = 0;
+        let mut address = None;
+        let mut sections: Vec<ParsedSection> = Vec::new();
+
+        for line in sdp.lines() {
+            let line = line.trim_end_matches('\r');
+
+            let Some((kind, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            match kind {
+                "o" => {
+                    session_id = value
+                        .split_whitespace()
+                        .nth(1)
+                        .and_then(|id| id.parse().ok())
+                        .unwrap_or(0);
+                }
+                // we only keep one address for everything, the first one we see
+                "c" if address.is_none() => {
+                    address = Some(parse_connection(value)?);
+                }
+                "m" => sections.push(ParsedSection::new(value)?),
+                "a" => {
+                    if let Some(section) = sections.last_mut() {
+                        section.attribute(value);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let Some(address) = address else {
+            return Err(invalid("no c= line"));
+        };
+
+        let mut media = Vec::new();
+        for section in sections {
+            // streams we can't send or play get left out, same as answering with port 0
+            if let Some(description) = section.into_description()? {
+                media.push(description);
+            }
+        }
+
+        Ok(Self {
+            session_id,
+            address,
+            media,
+        })
+    }
+}
+
+/// An m= section's lines, before we know which codec it's for
+struct ParsedSection {
+    kind: String,
+    port: u16,
+    rtpmaps: HashMap<u8, String>,
+    fmtps: HashMap<u8, String>,
+    feedback: Vec<(String, String)>,
+    ssrc: Option<u32>,
+    cname: Option<String>,
+}
+
+impl ParsedSection {
+    fn new(m_line: &str) -> io::Result<Self> {
+        let mut fields = m_line.split_whitespace();
+
+        let kind = fields.next().unwrap_or_default().to_string();
+        let port = fields
+            .next()
+            .and_then(|port| port.parse().ok())
+            .ok_or_else(|| invalid(format!("bad m= line, {}", m_line)))?;
+
+        Ok(Self {
+            kind,
+            port,
+            rtpmaps: HashMap::new(),
+            fmtps: HashMap::new(),
+            feedback: Vec::new(),
+            ssrc: None,
+            cname: None,
+        })
+    }
+
+    fn attribute(&mut self, attribute: &str) {
+        let Some((name, value)) = attribute.split_once(':') else {
+            return;
+        };
+
+        match name {
+            "rtpmap" | "fmtp" => {
+                let Some((pt, value)) = value.split_once(' ') else {
+                    return;
+                };
+
+                let Ok(pt) = pt.parse() else {
+                    return;
+                };
+
+                if name == "rtpmap" {
+                    self.rtpmaps.insert(pt, value.trim().to_lowercase());
+                } else {
+                    self.fmtps.insert(pt, value.trim().to_string());
+                }
+            }
+            "rtcp-fb" => {
+                if let Some((pt, value)) = value.split_once(' ') {
+                    self.feedback
+                        .push((pt.to_string(), value.trim().to_string()));
+                }
+            }
+            "ssrc" => {
+                let (ssrc, rest) = value.split_once(' ').unwrap_or((value, ""));
+
+                self.ssrc = ssrc.parse().ok();
+
+                if let Some(cname) = rest.strip_prefix("cname:") {
+                    self.cname = Some(cname.trim().to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// the payload type whose rtpmap starts with `encoding`
+    fn payload_type(&self, encoding: &str) -> Option<u8> {
+        self.rtpmaps
+            .iter()
+            .filter(|(_, rtpmap)| rtpmap.starts_with(encoding))
+            .map(|(pt, _)| *pt)
+            .min()
+    }
+
+    fn into_description(self) -> io::Result<Option<MediaDescription>> {
+        // port 0 means the stream's turned off
+        if self.port == 0 {
+            return Ok(None);
+        }
+
+        let (payload_type, stream_type) = match self.kind.as_str() {
+            "audio" => {
+                let Some(pt) = self.payload_type("opus/") else {
+                    return Ok(None);
+                };
+
+                let fmtp = fmtp_parameters(self.fmtps.get(&pt));
+
+                let flag = |name: &str| fmtp.get(name).is_some_and(|value| *value == "1");
+
+                let sample_rate = fmtp
+                    .get("sprop-maxcapturerate")
+                    .and_then(|rate| rate.parse().ok())
+                    .unwrap_or(OPUS_DEFAULT_SAMPLE_RATE);
+
+                let stereo = match fmtp.get("sprop-stereo") {
+                    Some(_) => flag("sprop-stereo"),
+                    None => flag("stereo"),
+                };
+
+                (
+                    pt,
+                    StreamTypeWithArgs::Audio {
+                        sample_rate,
+                        channels: if stereo { 2 } else { 1 },
+                        use_inband_fec: flag("useinbandfec"),
+                        red: self.payload_type("red/").is_some(),
+                    },
+                )
+            }
+            "video" => {
+                let Some(pt) = self.payload_type("h264/") else {
+                    return Ok(None);
+                };
+
+                let fmtp = fmtp_parameters(self.fmtps.get(&pt));
+
+                // 2 is interleaved, our depacketizer only does single NAL units and FU-A
+                if fmtp
+                    .get("packetization-mode")
+                    .is_some_and(|mode| *mode != "0" && *mode != "1")
+                {
+                    return Ok(None);
+                }
+
+                let Some(parameter_sets) = fmtp.get("sprop-parameter-sets") else {
+                    return Err(invalid("H264 without sprop-parameter-sets"));
+                };
+
+                let (mut sps, mut pps) = (None, None);
+
+                for parameter_set in parameter_sets.split(',') {
+                    let nal_unit = base64_decode(parameter_set.trim())
+                        .ok_or_else(|| invalid("sprop-parameter-sets isn't base64"))?;
+
+                    match nal_unit.first().map(|header| header & 0x1F) {
+                        Some(7) => sps = Some(nal_unit),
+                        Some(8) => pps = Some(nal_unit),
+                        _ => {}
+                    }
+                }
+
+                let (Some(sps), Some(pps)) = (sps, pps) else {
+                    return Err(invalid("sprop-parameter-sets needs an SPS and a PPS"));
+                };
+
+                (pt, StreamTypeWithArgs::Video { pps, sps })
+            }
+            _ => return Ok(None),
+        };
+
+        let rtcp_feedback = self
+            .feedback
+            .into_iter()
+            .filter(|(pt, _)| pt == "*" || pt.parse() == Ok(payload_type))
+            .map(|(_, feedback)| feedback)
+            .collect();
+
+        Ok(Some(MediaDescription {
+            port: self.port,
+            payload_type,
+            ssrc: self.ssrc,
+            cname: self.cname,
+            rtcp_feedback,
+            stream_type,
+        }))
+    }
+}
+
+/// `a=fmtp` parameters, ffmpeg puts spaces after the semicolons
+fn fmtp_parameters(fmtp: Option<&String>) -> HashMap<&str, &str> {
+    fmtp.map(|fmtp| {
+        fmtp.split(';')
+            .filter_map(|parameter| parameter.trim().split_once('='))
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn connection_address(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => format!("IN IP4 {}", address),
+        IpAddr::V6(address) => format!("IN IP6 {}", address),
+    }
+}
+
+fn parse_connection(value: &str) -> io::Result<IpAddr> {
+    // multicast addresses carry a /ttl
+    value
+        .split_whitespace()
+        .nth(2)
+        .and_then(|address| address.split('/').next())
+        .and_then(|address| address.parse().ok())
+        .ok_or_else(|| invalid(format!("bad c= line, {}", value)))
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3F;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=');
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+
+    let mut group = 0u32;
+    let mut bits = 0;
+
+    for c in encoded.bytes() {
+        let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+
+        group = (group << 6) | value;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((group >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participant() -> ParticipantArgs {
+        ParticipantArgs {
+            signaling_address: "10.0.0.1:4000".to_string(),
+            cname: "someone".to_string(),
+            media: vec![
+                MediaSection {
+                    local_rtp_address: "10.0.0.1:5000".to_string(),
+                    ssrc: 1234,
+                    stream_type: StreamTypeWithArgs::Audio {
+                        sample_rate: 48000.0,
+                        channels: 1,
+                        use_inband_fec: true,
+                        red: true,
+                    },
+                },
+                MediaSection {
+                    local_rtp_address: "10.0.0.1:5002".to_string(),
+                    ssrc: 5678,
+                    stream_type: StreamTypeWithArgs::Video {
+                        pps: vec![0x68, 0xCB, 0x8C, 0xB2],
+                        sps: vec![0x67, 0x4D, 0x00, 0x1F, 0x9A],
+                    },
+                },
+            ],
+            capabilities: Capabilities::ours(true),
+        }
+    }
+
+    #[test]
+    fn participants_survive_sdp() {
+        let sdp = SessionDescription::from_participant(&participant())
+            .unwrap()
+            .to_string();
+
+        assert!(sdp.contains("a=rtpmap:111 opus/48000/2\r\n"));
+        assert!(sdp.contains("a=fmtp:63 111/111\r\n"));
+        assert!(sdp.contains("profile-level-id=4d001f"));
+        assert!(sdp.contains("a=ssrc:5678 cname:someone\r\n"));
+
+        let parsed: SessionDescription = sdp.parse().unwrap();
+        let back = parsed.to_participant("10.0.0.1:4000".to_string()).unwrap();
+
+        assert_eq!(back.cname, "someone");
+        assert_eq!(back.media.len(), 2);
+
+        for (original, parsed) in participant().media.iter().zip(&back.media) {
+            assert_eq!(original.local_rtp_address, parsed.local_rtp_address);
+            assert_eq!(original.ssrc, parsed.ssrc);
+            assert_eq!(original.stream_type, parsed.stream_type);
+        }
+    }
+
+    #[test]
+    fn reads_what_ffmpeg_writes() {
+        let sdp = "v=0\n\
+                   o=- 0 0 IN IP4 127.0.0.1\n\
+                   s=No Name\n\
+                   c=IN IP4 127.0.0.1\n\
+                   t=0 0\n\
+                   a=tool:libavformat 60.16.100\n\
+                   m=video 5004 RTP/AVP 96\n\
+                   a=rtpmap:96 H264/90000\n\
+                   a=fmtp:96 packetization-mode=1; sprop-parameter-sets=Z0LAHtkDxWhAAAADAEAAAAwDxYuS,aMuMsg==; profile-level-id=42C01E\n\
+                   a=rtcp-fb:96 nack pli\n\
+                   m=application 5006 RTP/AVP 98\n";
+
+        let parsed: SessionDescription = sdp.parse().unwrap();
+
+        // the data channel isn't ours to deal with
+        assert_eq!(parsed.media.len(), 1);
+
+        let video = &parsed.media[0];
+        assert_eq!(video.port, 5004);
+        assert_eq!(video.ssrc, None);
+        assert_eq!(video.rtcp_feedback, vec!["nack pli".to_string()]);
+
+        let StreamTypeWithArgs::Video { pps, sps } = &video.stream_type else {
+            panic!("expected video");
+        };
+        assert_eq!(sps[..4], [0x67, 0x42, 0xC0, 0x1E]);
+        assert_eq!(pps[..], [0x68, 0xCB, 0x8C, 0xB2]);
+
+        // no SSRC, so it can't be one of our participants
+        assert!(parsed.to_participant(String::new()).is_err());
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        for length in 0..8 {
+            let data: Vec<u8> = (0..length).map(|i| i * 37).collect();
+            assert_eq!(base64_decode(&base64_encode(&data)), Some(data));
+        }
+
+        assert_eq!(
+            base64_encode(b"any carnal pleas"),
+            "YW55IGNhcm5hbCBwbGVhcw=="
+        );
+    }
+}
@@ -0,0 +1,568 @@
+/*
+    Signaling between participants, authenticated and encrypted with a secret everyone in
+    the call shares, like a Wi-Fi password. Without it nobody can join, listen in, or slip
+    a message into someone else's connection.
+
+    It's a Noise handshake (snow), XXpsk3: both sides send an ephemeral key, then their
+    static keys encrypted, and the room key gets mixed in at the very end. One frame each
+    (signaling_framing.rs):
+    - client: its ephemeral key, and our PROTOCOL_VERSION
+    - server: its ephemeral and static keys
+    - client: its static key, under everything so far plus the room key
+    - server: an empty message under the session keys, "you're in"
+
+    Nothing the server sends depends on the room key, so pretending to be a signaling
+    server gets nobody anything to check guesses against. The client's last message does,
+    and the server hasn't proven anything by then. Whoever answers a join gets that message,
+    and the address came from somewhere unauthenticated: an mDNS answer (discovery.rs), the
+    rendezvous server (rendezvous.rs), or someone's word. A fake server can then guess the
+    secret offline for as long as it likes. PBKDF2 salted with the room name makes each guess
+    expensive and only ever good for one room, but that doesn't hold up a human password.
+    Secrets should come from generate_secret (rust_generate_room_secret), just under 100
+    random bits.
+
+    After that every message is signaling_protocol.rs's versioned JSON sealed with
+    ChaCha20-Poly1305. Anything tampered with, replayed or reordered doesn't open, and
+    the connection's dropped.
+
+    Each session's static key is its identity, its CNAME is a fingerprint of it (see
+    Identity). signaling_server.rs checks whoever's on the other end of a connection only
+    ever speaks for the CNAME their key gives them, so knowing the secret isn't enough to
+    take over someone else's place in the call.
+
+    Failing to prove any of that is PermissionDenied, signaling_server.rs reports those as
+    security events.
+*/
+
+use pbkdf2::pbkdf2_hmac;
+use rand::RngExt;
+use sha2::{Digest, Sha256};
+use snow::{Builder, HandshakeState, StatelessTransportState, params::NoiseParams};
+use std::sync::{Arc, LazyLock};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+
+use crate::session_management::{
+    signaling_framing::{read_frame, write_frame},
+    signaling_protocol::{PROTOCOL_VERSION, SignalingMessage, decode_message, encode_message},
+};
+
+static NOISE_PARAMS: LazyLock<NoiseParams> = LazyLock::new(|| {
+    "Noise_XXpsk3_25519_ChaChaPoly_SHA256"
+        .parse()
+        .expect("Noise pattern is valid")
+});
+
+/// both sides have to agree on this, or the handshake fails
+const PROLOGUE: &[u8] = b"rtpcall signaling";
+
+/// changing any of these changes every room key, so it's a protocol change
+const KEY_SALT: &[u8] = b"rtpcall room secret";
+const KEY_ITERATIONS: u32 = 100_000;
+const KEY_LENGTH: usize = 32;
+
+/// where the room key goes in XXpsk3, after the last handshake message
+const PSK_LOCATION: u8 = 3;
+
+/// the biggest Noise message, payload included
+const MAX_NOISE_MESSAGE: usize = 65535;
+const TAG_LENGTH: usize = 16;
+
+/// bytes of the static key's hash that make up a CNAME
+const FINGERPRINT_LENGTH: usize = 16;
+
+/// what generate_secret picks from, no 0/O or 1/I/L to misread
+const SECRET_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+/// five groups of four, just under 100 bits
+const SECRET_GROUPS: usize = 5;
+const SECRET_GROUP_LENGTH: usize = 4;
+
+/// What everyone in a call shares, from the room's name and the secret they were given
+#[derive(Clone)]
+pub struct RoomKey([u8; KEY_LENGTH]);
+
+impl RoomKey {
+    /// Slow on purpose, see above. There are no open calls, an empty secret or room is InvalidInput
+    pub fn derive(room: &str, secret: &str) -> io::Result<Self> {
+        if room.is_empty() || secret.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Calls need a room name and a secret",
+            ));
+        }
+
+        let salt = [KEY_SALT, room.as_bytes()].concat();
+
+        let mut key = [0; KEY_LENGTH];
+        pbkdf2_hmac::<Sha256>(secret.as_bytes(), &salt, KEY_ITERATIONS, &mut key);
+        Ok(Self(key))
+    }
+}
+
+/// A secret for a new room, easy enough to read out to someone
+pub fn generate_secret() -> String {
+    let mut rng = rand::rng();
+
+    (0..SECRET_GROUPS)
+        .map(|_| {
+            (0..SECRET_GROUP_LENGTH)
+                .map(|_| {
+                    let index = rng.random_range(0..SECRET_ALPHABET.len());
+                    SECRET_ALPHABET[index] as char
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// A session's static key. Random per session, nobody else can prove they're it
+pub struct Identity {
+    private_key: Vec<u8>,
+    cname: String,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        let keypair = Builder::new(NOISE_PARAMS.clone())
+            .generate_keypair()
+            .expect("x25519 keys can always be generated");
+
+        Self {
+            private_key: keypair.private,
+            cname: fingerprint(&keypair.public),
+        }
+    }
+
+    /// our RTCP CNAME, only whoever has this key can join as it
+    pub fn cname(&self) -> &str {
+        &self.cname
+    }
+}
+
+/// The CNAME a static key speaks for
+fn fingerprint(public_key: &[u8]) -> String {
+    Sha256::digest(public_key)[..FINGERPRINT_LENGTH]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub(crate) struct SecureReader<R> {
+    reader: R,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    remote_cname: String,
+}
+
+pub(crate) struct SecureWriter<W> {
+    writer: W,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl<R: AsyncRead + Unpin> SecureReader<R> {
+    /// None if the other side hung up
+    pub async fn receive(&mut self) -> io::Result<Option<SignalingMessage>> {
+        let Some(sealed) = read_frame(&mut self.reader).await? else {
+            return Ok(None);
+        };
+
+        let payload = open(&self.transport, self.nonce, &sealed)?;
+        self.nonce += 1;
+
+        decode_message(&payload).map(Some)
+    }
+
+    /// The only CNAME the other side can speak for, they proved they have its key
+    pub fn remote_cname(&self) -> &str {
+        &self.remote_cname
+    }
+}
+
+impl<W: AsyncWrite + Unpin> SecureWriter<W> {
+    pub async fn send(&mut self, message: &SignalingMessage) -> io::Result<()> {
+        let sealed = seal(&self.transport, self.nonce, &encode_message(message)?)?;
+        self.nonce += 1;
+
+        write_frame(&mut self.writer, &sealed).await
+    }
+}
+
+/// The joining side of the handshake
+pub(crate) async fn connect<R, W>(
+    mut reader: R,
+    mut writer: W,
+    key: &RoomKey,
+    identity: &Identity,
+) -> io::Result<(SecureReader<R>, SecureWriter<W>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut handshake = handshake(key, identity)
+        .build_initiator()
+        .map_err(noise_error)?;
+    let mut buffer = vec![0; MAX_NOISE_MESSAGE];
+
+    let length = handshake
+        .write_message(&PROTOCOL_VERSION.to_be_bytes(), &mut buffer)
+        .map_err(noise_error)?;
+    write_frame(&mut writer, &buffer[..length]).await?;
+
+    let reply = handshake_frame(&mut reader).await?;
+    if handshake.read_message(&reply, &mut buffer).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a signaling server we can talk to",
+        ));
+    }
+
+    let length = handshake
+        .write_message(&[], &mut buffer)
+        .map_err(noise_error)?;
+    write_frame(&mut writer, &buffer[..length]).await?;
+
+    let remote_cname = remote_cname(&handshake)?;
+    let transport = Arc::new(
+        handshake
+            .into_stateless_transport_mode()
+            .map_err(noise_error)?,
+    );
+
+    // they hang up on a wrong secret, and nothing of theirs opens either
+    let turned_down = || {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "They don't know the room secret, or we've got the wrong one",
+        )
+    };
+    let welcome = read_frame(&mut reader).await?.ok_or_else(turned_down)?;
+    open(&transport, 0, &welcome).map_err(|_| turned_down())?;
+
+    Ok(channel(reader, writer, transport, remote_cname))
+}
+
+/// The side that was joined
+pub(crate) async fn accept<R, W>(
+    mut reader: R,
+    mut writer: W,
+    key: &RoomKey,
+    identity: &Identity,
+) -> io::Result<(SecureReader<R>, SecureWriter<W>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut handshake = handshake(key, identity)
+        .build_responder()
+        .map_err(noise_error)?;
+    let mut buffer = vec![0; MAX_NOISE_MESSAGE];
+
+    // ex: an older build sending its Join straight away
+    let hello = handshake_frame(&mut reader).await?;
+    let length = match handshake.read_message(&hello, &mut buffer) {
+        Ok(length) if length == 4 => length,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "No secure channel handshake",
+            ));
+        }
+    };
+
+    let version = u32::from_be_bytes(buffer[..length].try_into().unwrap());
+    if version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Unsupported signaling protocol version {}, we speak {}",
+                version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+
+    let length = handshake
+        .write_message(&[], &mut buffer)
+        .map_err(noise_error)?;
+    write_frame(&mut writer, &buffer[..length]).await?;
+
+    let last = handshake_frame(&mut reader).await?;
+    if handshake.read_message(&last, &mut buffer).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "They don't know the room secret",
+        ));
+    }
+
+    let remote_cname = remote_cname(&handshake)?;
+    let transport = Arc::new(
+        handshake
+            .into_stateless_transport_mode()
+            .map_err(noise_error)?,
+    );
+
+    let welcome = seal(&transport, 0, &[])?;
+    write_frame(&mut writer, &welcome).await?;
+
+    Ok(channel(reader, writer, transport, remote_cname))
+}
+
+fn handshake<'a>(key: &'a RoomKey, identity: &'a Identity) -> Builder<'a> {
+    Builder::new(NOISE_PARAMS.clone())
+        .local_private_key(&identity.private_key)
+        .psk(PSK_LOCATION, &key.0)
+        .prologue(PROLOGUE)
+}
+
+async fn handshake_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    read_frame(reader)
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Hung up during the handshake"))
+}
+
+fn remote_cname(handshake: &HandshakeState) -> io::Result<String> {
+    handshake
+        .get_remote_static()
+        .map(fingerprint)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "No static key"))
+}
+
+/// The welcome takes nonce 0 each way, messages count up from 1
+fn channel<R, W>(
+    reader: R,
+    writer: W,
+    transport: Arc<StatelessTransportState>,
+    remote_cname: String,
+) -> (SecureReader<R>, SecureWriter<W>) {
+    (
+        SecureReader {
+            reader,
+            transport: Arc::clone(&transport),
+            nonce: 1,
+            remote_cname,
+        },
+        SecureWriter {
+            writer,
+            transport,
+            nonce: 1,
+        },
+    )
+}
+
+fn seal(transport: &StatelessTransportState, nonce: u64, payload: &[u8]) -> io::Result<Vec<u8>> {
+    if payload.len() + TAG_LENGTH > MAX_NOISE_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Signaling message too big to seal",
+        ));
+    }
+
+    let mut sealed = vec![0; payload.len() + TAG_LENGTH];
+    let length = transport
+        .write_message(nonce, payload, &mut sealed)
+        .map_err(noise_error)?;
+    sealed.truncate(length);
+    Ok(sealed)
+}
+
+fn open(transport: &StatelessTransportState, nonce: u64, sealed: &[u8]) -> io::Result<Vec<u8>> {
+    let mut payload = vec![0; sealed.len()];
+    let length = transport
+        .read_message(nonce, sealed, &mut payload)
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Signaling message failed authentication",
+            )
+        })?;
+    payload.truncate(length);
+    Ok(payload)
+}
+
+fn noise_error(e: snow::Error) -> io::Error {
+    io::Error::other(format!("Noise: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_management::signaling_protocol::send_message;
+    use tokio::io::{AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf, duplex, split};
+
+    type Channel = (
+        SecureReader<ReadHalf<DuplexStream>>,
+        SecureWriter<WriteHalf<DuplexStream>>,
+    );
+
+    /// A failed handshake drops its halves, so the other side sees it hang up
+    async fn handshake(
+        client_key: RoomKey,
+        server_key: RoomKey,
+    ) -> (io::Result<Channel>, io::Result<Channel>) {
+        let (client, server) = duplex(1024);
+        let (client_reader, client_writer) = split(client);
+        let (server_reader, server_writer) = split(server);
+
+        let server = tokio::spawn(async move {
+            accept(
+                server_reader,
+                server_writer,
+                &server_key,
+                &Identity::generate(),
+            )
+            .await
+        });
+
+        let connected = connect(
+            client_reader,
+            client_writer,
+            &client_key,
+            &Identity::generate(),
+        )
+        .await;
+        (connected, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn the_same_secret_gets_a_working_channel() {
+        let key = RoomKey([7; KEY_LENGTH]);
+        let (client, server) = handshake(key.clone(), key).await;
+
+        let (mut client_reader, mut client_writer) = client.unwrap();
+        let (mut server_reader, mut server_writer) = server.unwrap();
+
+        client_writer.send(&SignalingMessage::Ping).await.unwrap();
+        client_writer.send(&SignalingMessage::Pong).await.unwrap();
+        server_writer.send(&SignalingMessage::Ping).await.unwrap();
+
+        assert!(matches!(
+            server_reader.receive().await.unwrap(),
+            Some(SignalingMessage::Ping)
+        ));
+        assert!(matches!(
+            server_reader.receive().await.unwrap(),
+            Some(SignalingMessage::Pong)
+        ));
+        assert!(matches!(
+            client_reader.receive().await.unwrap(),
+            Some(SignalingMessage::Ping)
+        ));
+    }
+
+    #[tokio::test]
+    async fn each_side_learns_the_others_cname() {
+        let key = RoomKey([7; KEY_LENGTH]);
+        let (client, server) = duplex(1024);
+        let (client_reader, client_writer) = split(client);
+        let (server_reader, server_writer) = split(server);
+
+        let client_identity = Identity::generate();
+        let server_identity = Identity::generate();
+        let server_cname = server_identity.cname().to_string();
+
+        let server_key = key.clone();
+        let server = tokio::spawn(async move {
+            accept(server_reader, server_writer, &server_key, &server_identity).await
+        });
+        let (client_reader, _) = connect(client_reader, client_writer, &key, &client_identity)
+            .await
+            .unwrap();
+        let (server_reader, _) = server.await.unwrap().unwrap();
+
+        assert_eq!(client_reader.remote_cname(), server_cname);
+        assert_eq!(server_reader.remote_cname(), client_identity.cname());
+    }
+
+    #[tokio::test]
+    async fn a_different_secret_is_refused() {
+        let (client, server) = handshake(RoomKey([1; KEY_LENGTH]), RoomKey([2; KEY_LENGTH])).await;
+
+        assert_eq!(
+            client.err().unwrap().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            server.err().unwrap().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn the_room_salts_the_key() {
+        let here = RoomKey::derive("kitchen", "hunter2").unwrap();
+        let there = RoomKey::derive("garage", "hunter2").unwrap();
+
+        assert_ne!(here.0, there.0);
+        assert_eq!(here.0, RoomKey::derive("kitchen", "hunter2").unwrap().0);
+    }
+
+    #[test]
+    fn empty_secrets_are_refused() {
+        assert_eq!(
+            RoomKey::derive("kitchen", "").err().unwrap().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            RoomKey::derive("", "hunter2").err().unwrap().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn generated_secrets_differ() {
+        let secret = generate_secret();
+
+        assert_eq!(secret.len(), SECRET_GROUPS * (SECRET_GROUP_LENGTH + 1) - 1);
+        assert_ne!(secret, generate_secret());
+    }
+
+    #[tokio::test]
+    async fn plaintext_joins_are_refused() {
+        let (mut client, server) = duplex(1024);
+        let (server_reader, server_writer) = split(server);
+
+        send_message(&mut client, &SignalingMessage::Ping)
+            .await
+            .unwrap();
+
+        let accepted = accept(
+            server_reader,
+            server_writer,
+            &RoomKey([3; KEY_LENGTH]),
+            &Identity::generate(),
+        )
+        .await;
+        assert_eq!(
+            accepted.err().unwrap().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_from_another_connection_dont_open() {
+        let key = RoomKey([7; KEY_LENGTH]);
+        let (client, server) = handshake(key.clone(), key.clone()).await;
+        let (other, _) = handshake(key.clone(), key).await;
+
+        let (_, mut client_writer) = client.unwrap();
+        let (mut server_reader, _) = server.unwrap();
+
+        // sealed under a different connection's keys, ex: recorded and played back later
+        let (_, other_writer) = other.unwrap();
+        let sealed = seal(
+            &other_writer.transport,
+            1,
+            &encode_message(&SignalingMessage::Ping).unwrap(),
+        )
+        .unwrap();
+
+        let mut framed = Vec::new();
+        write_frame(&mut framed, &sealed).await.unwrap();
+        client_writer.writer.write_all(&framed).await.unwrap();
+
+        assert_eq!(
+            server_reader.receive().await.unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+}
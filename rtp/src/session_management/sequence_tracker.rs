@@ -0,0 +1,290 @@
+/*
+    Sequence number validation and loss accounting.
+    Pretty much a straight port of RFC 3550, Appendix A.1 and A.3:
+    https://www.rfc-editor.org/rfc/rfc3550#appendix-A.1
+*/
+
+const RTP_SEQ_MOD: u32 = 1 << 16;
+const MAX_DROPOUT: u16 = 3000;
+const MAX_MISORDER: u16 = 100;
+
+/// how many packets in a row a new source needs before we believe it
+const MIN_SEQUENTIAL: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceStatus {
+    /// in order, a permissible gap, a reordered packet or a duplicate
+    Valid,
+
+    /// the sender restarted (large jump confirmed by the next packet),
+    /// statistics were reset and anything buffered for the old sequence is meaningless
+    Restarted,
+
+    /// still on probation, or the first packet of a large jump. Throw it away
+    Rejected,
+}
+
+pub struct SequenceTracker {
+    /// highest sequence number seen
+    max_seq: u16,
+
+    /// shifted count of sequence number cycles (multiples of 65536)
+    cycles: u32,
+
+    /// first sequence number of the current run (after init or restart)
+    base_seq: u32,
+
+    /// last 'bad' sequence number + 1, a packet matching it confirms a restart
+    bad_seq: u32,
+
+    /// sequential packets still needed before the source is valid
+    probation: u32,
+
+    /// packets received, including duplicates
+    received: u32,
+
+    /// packets expected at the last reception report
+    expected_prior: u32,
+
+    /// packets received at the last reception report
+    received_prior: u32,
+}
+
+impl SequenceTracker {
+    /// Starts a new source on probation, the packet carrying `seq` still needs to go through `update`
+    pub fn new(seq: u16) -> Self {
+        let mut tracker = Self {
+            max_seq: 0,
+            cycles: 0,
+            base_seq: 0,
+            bad_seq: 0,
+            probation: MIN_SEQUENTIAL,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+        };
+
+        tracker.init_seq(seq);
+        tracker.max_seq = seq.wrapping_sub(1);
+
+        tracker
+    }
+
+    fn init_seq(&mut self, seq: u16) {
+        self.base_seq = seq as u32;
+        self.max_seq = seq;
+        self.bad_seq = RTP_SEQ_MOD + 1; // so seq == bad_seq is false
+        self.cycles = 0;
+        self.received = 0;
+        self.received_prior = 0;
+        self.expected_prior = 0;
+    }
+
+    pub fn update(&mut self, seq: u16) -> SequenceStatus {
+        let udelta = seq.wrapping_sub(self.max_seq);
+
+        if self.probation > 0 {
+            // packet is in sequence
+            if seq == self.max_seq.wrapping_add(1) {
+                self.probation -= 1;
+                self.max_seq = seq;
+
+                if self.probation == 0 {
+                    self.init_seq(seq);
+                    self.received += 1;
+                    return SequenceStatus::Valid;
+                }
+            } else {
+                self.probation = MIN_SEQUENTIAL - 1;
+                self.max_seq = seq;
+            }
+
+            return SequenceStatus::Rejected;
+        }
+
+        let mut status = SequenceStatus::Valid;
+
+        if udelta < MAX_DROPOUT {
+            // in order, with permissible gap
+            if seq < self.max_seq {
+                // sequence number wrapped, count another 64k cycle
+                self.cycles = self.cycles.wrapping_add(RTP_SEQ_MOD);
+            }
+            self.max_seq = seq;
+        } else if udelta as u32 <= RTP_SEQ_MOD - MAX_MISORDER as u32 {
+            // the sequence number made a very large jump
+            if seq as u32 == self.bad_seq {
+                // two sequential packets, assume the other side restarted without telling us
+                self.init_seq(seq);
+                status = SequenceStatus::Restarted;
+            } else {
+                self.bad_seq = (seq as u32 + 1) & (RTP_SEQ_MOD - 1);
+                return SequenceStatus::Rejected;
+            }
+        } else {
+            // duplicate or reordered packet, counted but max_seq stays put
+        }
+
+        self.received += 1;
+        status
+    }
+
+    /// Extends a 16 bit sequence number to 32 bits relative to the highest one seen.
+    /// Handles packets reordered across a wrap (ex: 65535 arriving after 0).
+    pub fn extend(&self, seq: u16) -> u32 {
+        let delta = seq.wrapping_sub(self.max_seq) as i16;
+        self.extended_max().wrapping_add_signed(delta as i32)
+    }
+
+    pub fn extended_max(&self) -> u32 {
+        self.cycles.wrapping_add(self.max_seq as u32)
+    }
+
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    pub fn expected(&self) -> u32 {
+        self.extended_max()
+            .wrapping_sub(self.base_seq)
+            .wrapping_add(1)
+    }
+
+    /// Cumulative packets lost, clamped to the signed 24 bit range of the report field.
+    /// Duplicates can push this negative.
+    pub fn cumulative_lost(&self) -> i32 {
+        let lost = self.expected() as i64 - self.received as i64;
+        lost.clamp(-0x800000, 0x7FFFFF) as i32
+    }
+
+    /// Fraction lost since the last report, as a fixed point number with the binary point at the left.
+    /// Moves the report interval forward, so only call this when actually building a report.
+    pub fn fraction_lost(&mut self) -> u8 {
+        let expected = self.expected();
+        let expected_interval = expected.wrapping_sub(self.expected_prior);
+        self.expected_prior = expected;
+
+        let received_interval = self.received.wrapping_sub(self.received_prior);
+        self.received_prior = self.received;
+
+        let lost_interval = expected_interval as i64 - received_interval as i64;
+
+        if expected_interval == 0 || lost_interval <= 0 {
+            return 0;
+        }
+
+        ((lost_interval << 8) / expected_interval as i64) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// gets a tracker out of probation, `start` is the first valid packet
+    fn validated(start: u16) -> SequenceTracker {
+        let mut tracker = SequenceTracker::new(start.wrapping_sub(1));
+        assert_eq!(
+            tracker.update(start.wrapping_sub(1)),
+            SequenceStatus::Rejected
+        );
+        assert_eq!(tracker.update(start), SequenceStatus::Valid);
+        tracker
+    }
+
+    #[test]
+    fn probation_needs_sequential_packets() {
+        let mut tracker = SequenceTracker::new(10);
+
+        assert_eq!(tracker.update(10), SequenceStatus::Rejected);
+        // out of order restarts probation
+        assert_eq!(tracker.update(20), SequenceStatus::Rejected);
+        assert_eq!(tracker.update(21), SequenceStatus::Valid);
+        assert_eq!(tracker.update(22), SequenceStatus::Valid);
+
+        assert_eq!(tracker.received(), 2);
+        assert_eq!(tracker.expected(), 2);
+        assert_eq!(tracker.cumulative_lost(), 0);
+    }
+
+    #[test]
+    fn reordered_packets_are_valid_and_not_lost() {
+        let mut tracker = validated(100);
+
+        for seq in [101, 103, 102, 104] {
+            assert_eq!(tracker.update(seq), SequenceStatus::Valid);
+        }
+
+        assert_eq!(tracker.extended_max(), 104);
+        assert_eq!(tracker.extend(102), 102);
+        assert_eq!(tracker.cumulative_lost(), 0);
+    }
+
+    #[test]
+    fn gaps_count_as_lost() {
+        let mut tracker = validated(0);
+
+        for seq in [1, 2, 5, 6] {
+            tracker.update(seq);
+        }
+
+        assert_eq!(tracker.expected(), 7);
+        assert_eq!(tracker.received(), 5);
+        assert_eq!(tracker.cumulative_lost(), 2);
+        // 2 of 7 lost, 2 * 256 / 7
+        assert_eq!(tracker.fraction_lost(), 73);
+        // nothing new since the last report
+        assert_eq!(tracker.fraction_lost(), 0);
+    }
+
+    #[test]
+    fn duplicates_do_not_move_max_or_underflow() {
+        let mut tracker = validated(50);
+
+        assert_eq!(tracker.update(51), SequenceStatus::Valid);
+        assert_eq!(tracker.update(51), SequenceStatus::Valid);
+        assert_eq!(tracker.update(51), SequenceStatus::Valid);
+
+        assert_eq!(tracker.extended_max(), 51);
+        assert_eq!(tracker.received(), 4);
+        assert_eq!(tracker.cumulative_lost(), -2);
+        assert_eq!(tracker.fraction_lost(), 0);
+    }
+
+    #[test]
+    fn wraparound_counts_a_cycle() {
+        let mut tracker = validated(65534);
+
+        for seq in [65535, 0, 1] {
+            assert_eq!(tracker.update(seq), SequenceStatus::Valid);
+        }
+
+        assert_eq!(tracker.extended_max(), 65536 + 1);
+        assert_eq!(tracker.expected(), 4);
+        assert_eq!(tracker.cumulative_lost(), 0);
+
+        // a straggler from before the wrap extends into the previous cycle
+        assert_eq!(tracker.update(65535), SequenceStatus::Valid);
+        assert_eq!(tracker.extend(65535), 65535);
+        assert_eq!(tracker.extended_max(), 65537);
+    }
+
+    #[test]
+    fn sender_restart_needs_two_sequential_packets() {
+        let mut tracker = validated(1000);
+        tracker.update(1001);
+
+        // a single wild packet is thrown away
+        assert_eq!(tracker.update(40000), SequenceStatus::Rejected);
+        assert_eq!(tracker.update(1002), SequenceStatus::Valid);
+
+        // two in a row means the sender really started over
+        assert_eq!(tracker.update(20000), SequenceStatus::Rejected);
+        assert_eq!(tracker.update(20001), SequenceStatus::Restarted);
+        assert_eq!(tracker.update(20002), SequenceStatus::Valid);
+
+        assert_eq!(tracker.extended_max(), 20002);
+        assert_eq!(tracker.received(), 2);
+        assert_eq!(tracker.expected(), 2);
+    }
+}
@@ -0,0 +1,716 @@
+/*
+    One call. Owns everything the call needs (sockets, peer managers, signaling, tasks),
+    so leaving is just dropping it, and nothing stops a process from being in two.
+
+    Leaving goes:
+    1. tell everyone's signaling server we're gone
+    2. cancel the shutdown token. RTCP says BYE on its way out, everything else just stops
+    3. wait for every task to finish, which drops the sockets, listener and queued frames
+
+    The app gets it as an opaque pointer, see rust_session_create / rust_session_destroy.
+*/
+
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU8, Ordering},
+    },
+    time::Duration,
+};
+
+use local_ip_address::local_ip;
+use tokio::{
+    net::UdpSocket,
+    runtime::Handle,
+    sync::{mpsc, watch},
+    task::JoinSet,
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    interop::{
+        CHANNEL_BUFFER_SIZE, StreamType,
+        audio::{EncodedAudio, rtp_audio_receiver, rtp_audio_sender},
+        error::{Error, ErrorReporter, RtpError},
+        video::{EncodedFrame, rtp_frame_receiver, rtp_frame_sender},
+    },
+    packets::{
+        RTPSession,
+        rtcp::start_rtcp,
+        rtp::{opus::OPUS_CLOCK_RATE, red::MAX_REDUNDANCY_DEPTH},
+    },
+    session_management::{
+        discovery::advertise,
+        lip_sync::LipSync,
+        media_clock::MediaClock,
+        media_sink::{MediaSink, SessionEvents},
+        network_monitor::{SwappableSocket, monitor_network},
+        peer_manager::PeerManager,
+        secure_channel::RoomKey,
+        signaling_server::{CallEntry, H264Args, OpusArgs, SignalingServer},
+    },
+};
+
+/// H.264 over RTP is always 90kHz (RFC 6184)
+const VIDEO_CLOCK_RATE: u32 = 90_000;
+
+/// past this, whatever's still running gets aborted
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// how many RTP ports to try before giving up on finding a free one after it for RTCP
+const BIND_ATTEMPTS: usize = 16;
+
+/// Every task a session spawned, so they can all go down with it
+#[derive(Clone)]
+pub struct SessionTasks {
+    runtime: Handle,
+    shutdown: CancellationToken,
+
+    /// None once the session's shutting down
+    tasks: Arc<Mutex<Option<JoinSet<()>>>>,
+}
+
+impl SessionTasks {
+    pub fn new(runtime: Handle) -> Self {
+        Self {
+            runtime,
+            shutdown: CancellationToken::new(),
+            tasks: Arc::new(Mutex::new(Some(JoinSet::new()))),
+        }
+    }
+
+    /// Spawns `task`, dropping it wherever it is once the session shuts down
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+
+        self.spawn_graceful(async move {
+            tokio::select! {
+                _ = shutdown.cancelled() => {}
+                _ = task => {}
+            }
+        });
+    }
+
+    /// Spawns `task`, which has to watch `shutdown_token` itself to wrap up on its own terms
+    pub fn spawn_graceful<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.tasks.lock().unwrap();
+
+        // a task that was still running when the session closed can try to start another one
+        let Some(tasks) = tasks.as_mut() else {
+            return;
+        };
+
+        // forget about the ones that already finished (signaling clients mostly)
+        while tasks.try_join_next().is_some() {}
+
+        tasks.spawn_on(task, &self.runtime);
+    }
+
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Cancels everything and waits for it to finish. Stragglers get aborted
+    async fn shutdown(&self) {
+        self.shutdown.cancel();
+
+        let Some(mut tasks) = self.tasks.lock().unwrap().take() else {
+            return;
+        };
+
+        // dropping a JoinSet aborts whatever's left in it, nothing more to do about them
+        let _ = timeout(SHUTDOWN_TIMEOUT, async {
+            while tasks.join_next().await.is_some() {}
+        })
+        .await;
+    }
+}
+
+pub struct Session {
+    tasks: SessionTasks,
+    signaling: Arc<SignalingServer>,
+    lip_sync: Arc<LipSync>,
+    sink: Arc<dyn MediaSink>,
+    events: Arc<dyn SessionEvents>,
+    clock: Arc<dyn MediaClock>,
+    errors: Arc<ErrorReporter>,
+
+    /// our address as of the last check, streams move their sockets when it changes
+    local_ip: watch::Receiver<Option<IpAddr>>,
+
+    /// most redundant frames (RFC 2198) to tack onto each audio packet, 0 turns RED off.
+    /// the actual depth follows the loss peers report, this is just the ceiling
+    audio_redundancy: Arc<AtomicU8>,
+
+    frame_tx: OnceLock<mpsc::Sender<EncodedFrame>>,
+    audio_tx: OnceLock<mpsc::Sender<EncodedAudio>>,
+}
+
+impl Session {
+    /// `entry` is how to find the rest of the call, or CallEntry::Host to start a new one.
+    /// Everyone in the call needs the same `room_key` (see secure_channel.rs)
+    pub fn new(
+        runtime: Handle,
+        entry: CallEntry,
+        room_key: RoomKey,
+        sink: Arc<dyn MediaSink>,
+        events: Arc<dyn SessionEvents>,
+        clock: Arc<dyn MediaClock>,
+    ) -> Self {
+        let tasks = SessionTasks::new(runtime);
+        let lip_sync = Arc::new(LipSync::new());
+        let errors = Arc::new(ErrorReporter::new());
+
+        let signaling = Arc::new(SignalingServer::new(
+            entry,
+            room_key,
+            Arc::clone(&events),
+            Arc::clone(&lip_sync),
+            Arc::clone(&errors),
+            tasks.clone(),
+        ));
+
+        let (local_ip_tx, local_ip) = watch::channel(local_ip().ok());
+        tasks.spawn(monitor_network(
+            Arc::clone(&signaling),
+            local_ip_tx,
+            Arc::clone(&errors),
+        ));
+
+        Self {
+            tasks,
+            signaling,
+            lip_sync,
+            sink,
+            events,
+            clock,
+            errors,
+            local_ip,
+            audio_redundancy: Arc::new(AtomicU8::new(MAX_REDUNDANCY_DEPTH)),
+            frame_tx: OnceLock::new(),
+            audio_tx: OnceLock::new(),
+        }
+    }
+
+    /// Where background failures end up, the app can listen in
+    pub fn errors(&self) -> &ErrorReporter {
+        &self.errors
+    }
+
+    /// Starts sending and receiving one kind of media.
+    /// Anything that goes wrong once it's running goes to `errors`
+    pub fn start_stream(&self, stream_type: StreamType) -> Result<(), Error> {
+        let already_started = || {
+            Error::new(
+                RtpError::StreamAlreadyStarted,
+                format!("{:?} stream already initialized", stream_type),
+            )
+        };
+
+        let stream = match stream_type {
+            StreamType::Video => {
+                let (tx, rx) = mpsc::channel::<EncodedFrame>(CHANNEL_BUFFER_SIZE);
+
+                self.frame_tx.set(tx).map_err(|_| already_started())?;
+
+                MediaStream::Video(rx)
+            }
+            StreamType::Audio => {
+                let (tx, rx) = mpsc::channel::<EncodedAudio>(CHANNEL_BUFFER_SIZE);
+
+                self.audio_tx.set(tx).map_err(|_| already_started())?;
+
+                MediaStream::Audio(rx)
+            }
+        };
+
+        self.signaling.expect_stream(stream_type);
+
+        let stream_context = StreamContext {
+            tasks: self.tasks.clone(),
+            signaling: Arc::clone(&self.signaling),
+            lip_sync: Arc::clone(&self.lip_sync),
+            sink: Arc::clone(&self.sink),
+            events: Arc::clone(&self.events),
+            clock: Arc::clone(&self.clock),
+            errors: Arc::clone(&self.errors),
+            local_ip: self.local_ip.clone(),
+            audio_redundancy: Arc::clone(&self.audio_redundancy),
+        };
+
+        let errors = Arc::clone(&self.errors);
+        self.tasks.spawn(async move {
+            if let Err(e) = stream_context.run(stream).await {
+                errors.report(Error::new(
+                    RtpError::Network,
+                    format!("{:?} stream stopped: {}", stream_type, e),
+                ));
+            }
+        });
+
+        Ok(())
+    }
+
+    pub fn send_frame(&self, frame: EncodedFrame) -> Result<(), Error> {
+        let Some(tx) = self.frame_tx.get() else {
+            return Err(Error::new(
+                RtpError::StreamNotStarted,
+                "Video stream not initialized",
+            ));
+        };
+
+        try_send(tx, frame)
+    }
+
+    pub fn send_audio(&self, sample: EncodedAudio) -> Result<(), Error> {
+        let Some(tx) = self.audio_tx.get() else {
+            return Err(Error::new(
+                RtpError::StreamNotStarted,
+                "Audio stream not initialized",
+            ));
+        };
+
+        try_send(tx, sample)
+    }
+
+    /// Caps how much RED redundancy our audio carries, takes effect from the next packet
+    pub fn set_audio_redundancy(&self, max_depth: u8) {
+        self.audio_redundancy
+            .store(max_depth.min(MAX_REDUNDANCY_DEPTH), Ordering::Relaxed);
+    }
+
+    pub fn set_opus_args(&self, opus_args: OpusArgs) {
+        self.signaling.set_opus_args(opus_args);
+    }
+
+    pub fn set_h264_args(&self, h264_args: H264Args) {
+        self.signaling.set_h264_args(h264_args);
+    }
+
+    /// Our streams as SDP (see sdp.rs). Only has the ones that are running and have their codec config
+    pub async fn session_description(&self) -> Result<String, Error> {
+        self.signaling
+            .session_description()
+            .await
+            .map_err(|e| Error::new(RtpError::Signaling, e.to_string()))
+    }
+
+    /// Where others join us, what they'd hand CallEntry::Peer
+    pub async fn signaling_addr(&self) -> io::Result<SocketAddr> {
+        self.signaling.signaling_addr().await
+    }
+
+    /// Lets people on the local network find this call as `room` (see discovery.rs), until we leave
+    pub fn advertise(&self, room: String) {
+        let shutdown = self.tasks.shutdown_token();
+        self.tasks
+            .spawn_graceful(advertise(Arc::clone(&self.signaling), room, shutdown));
+    }
+
+    /// Leaves the call and waits until everything's stopped. Does nothing the second time
+    pub async fn leave(&self) {
+        if self.tasks.is_shut_down() {
+            return;
+        }
+
+        self.signaling.notify_leave().await;
+        self.tasks.shutdown().await;
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // the tasks below can take a while to stop, and the app's gone now
+        self.sink.detach();
+
+        if self.tasks.is_shut_down() {
+            return;
+        }
+
+        // same for whatever goes wrong from here on, there's nobody to tell
+        self.errors.set_callback(None, std::ptr::null_mut());
+
+        // nobody called leave(), do it in the background
+        let signaling = Arc::clone(&self.signaling);
+        let tasks = self.tasks.clone();
+
+        self.tasks.runtime.spawn(async move {
+            signaling.notify_leave().await;
+            tasks.shutdown().await;
+        });
+    }
+}
+
+fn try_send<T>(tx: &mpsc::Sender<T>, item: T) -> Result<(), Error> {
+    match tx.try_send(item) {
+        Ok(_) => Ok(()),
+        Err(mpsc::error::TrySendError::Full(_)) => Err(Error::new(
+            RtpError::ChannelFull,
+            "frame dropped - channel full",
+        )),
+        Err(mpsc::error::TrySendError::Closed(_)) => {
+            Err(Error::new(RtpError::ChannelClosed, "channel closed"))
+        }
+    }
+}
+
+enum MediaStream {
+    Video(mpsc::Receiver<EncodedFrame>),
+    Audio(mpsc::Receiver<EncodedAudio>),
+}
+
+/// The parts of a session a stream needs, without holding the session itself
+struct StreamContext {
+    tasks: SessionTasks,
+    signaling: Arc<SignalingServer>,
+    lip_sync: Arc<LipSync>,
+    sink: Arc<dyn MediaSink>,
+    events: Arc<dyn SessionEvents>,
+    clock: Arc<dyn MediaClock>,
+    errors: Arc<ErrorReporter>,
+    local_ip: watch::Receiver<Option<IpAddr>>,
+    audio_redundancy: Arc<AtomicU8>,
+}
+
+/// RTCP goes on the port after RTP, that's where peers send it.
+/// If the OS hands us the last port, or the one after is taken, just try another
+async fn bind_media_sockets(ip: IpAddr) -> io::Result<(UdpSocket, UdpSocket)> {
+    for _ in 0..BIND_ATTEMPTS {
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+
+        let Some(rtcp_port) = socket.local_addr()?.port().checked_add(1) else {
+            continue;
+        };
+
+        match UdpSocket::bind(SocketAddr::new(ip, rtcp_port)).await {
+            Ok(rtcp_socket) => return Ok((socket, rtcp_socket)),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "couldn't find two free ports in a row for RTP and RTCP",
+    ))
+}
+
+impl StreamContext {
+    async fn run(self, stream: MediaStream) -> io::Result<()> {
+        // reconnecting is network_monitor.rs's problem, the sockets below just get swapped out
+
+        let (stream_type, clock_rate) = match stream {
+            MediaStream::Audio(_) => (StreamType::Audio, OPUS_CLOCK_RATE),
+            MediaStream::Video(_) => (StreamType::Video, VIDEO_CLOCK_RATE),
+        };
+
+        let local_ip = local_ip().map_err(io::Error::other)?;
+
+        // RTCP: Sending to another peer's address is just their RTP address +1
+        let (socket, rtcp_socket) = bind_media_sockets(local_ip).await?;
+        let socket = Arc::new(SwappableSocket::new(socket));
+        let rtcp_socket = Arc::new(SwappableSocket::new(rtcp_socket));
+
+        // Session management objects
+        // we'll be using these throughout the program.
+        let rtp_session = RTPSession::new(socket.local_addr()?, clock_rate);
+        let peer_manager = Arc::new(PeerManager::new(
+            rtp_session,
+            stream_type,
+            Arc::clone(&self.events),
+        ));
+
+        // Signaling server thread
+        let signaling = Arc::clone(&self.signaling);
+        let peer_manager_clone = Arc::clone(&peer_manager);
+        let errors = Arc::clone(&self.errors);
+        self.tasks.spawn(async move {
+            if let Err(e) = signaling
+                .run_signaling_server(peer_manager_clone, stream_type)
+                .await
+            {
+                errors.report(Error::new(
+                    RtpError::Signaling,
+                    format!("Signaling server error: {}", e),
+                ));
+            }
+        });
+
+        // Moving the sockets if the network changes
+        let follow = Self::follow_network(
+            self.local_ip.clone(),
+            local_ip,
+            Arc::clone(&socket),
+            Arc::clone(&rtcp_socket),
+            Arc::clone(&peer_manager),
+            Arc::clone(&self.signaling),
+            Arc::clone(&self.errors),
+        );
+        self.tasks.spawn(follow);
+
+        // RTCP Sender and receiver threads
+        let peer_manager_clone = Arc::clone(&peer_manager);
+        let clock = Arc::clone(&self.clock);
+        let errors = Arc::clone(&self.errors);
+        let shutdown = self.tasks.shutdown_token();
+        self.tasks.spawn_graceful(async move {
+            start_rtcp(rtcp_socket, peer_manager_clone, clock, errors, shutdown).await
+        });
+
+        // Video and Audio sender and receiver threads
+        let sender_socket = Arc::clone(&socket);
+        let sender_peers = Arc::clone(&peer_manager);
+        match stream {
+            MediaStream::Video(rx) => {
+                self.tasks.spawn(async move {
+                    rtp_frame_sender(sender_socket, sender_peers, rx).await;
+                });
+
+                rtp_frame_receiver(socket, peer_manager, clock_rate, self.sink, self.lip_sync).await
+            }
+
+            MediaStream::Audio(rx) => {
+                let redundancy = self.audio_redundancy;
+                let errors = Arc::clone(&self.errors);
+                self.tasks.spawn(async move {
+                    rtp_audio_sender(sender_socket, sender_peers, rx, redundancy, errors).await;
+                });
+
+                rtp_audio_receiver(socket, peer_manager, clock_rate, self.sink, self.lip_sync).await
+            }
+        }
+    }
+
+    /// Rebinds the stream's sockets whenever our address changes, then has signaling
+    /// tell everyone where we went. The SSRC stays, so to peers it's the same stream
+    async fn follow_network(
+        mut local_ip: watch::Receiver<Option<IpAddr>>,
+        mut bound_ip: IpAddr,
+        socket: Arc<SwappableSocket>,
+        rtcp_socket: Arc<SwappableSocket>,
+        peer_manager: Arc<PeerManager>,
+        signaling: Arc<SignalingServer>,
+        errors: Arc<ErrorReporter>,
+    ) {
+        let stream_type = peer_manager.stream_type();
+
+        // the monitor only goes away with the session
+        while local_ip.changed().await.is_ok() {
+            let Some(ip) = *local_ip.borrow_and_update() else {
+                continue;
+            };
+
+            if ip == bound_ip {
+                continue;
+            }
+
+            let (new_socket, new_rtcp_socket) = match bind_media_sockets(ip).await {
+                Ok(sockets) => sockets,
+                Err(e) => {
+                    errors.report(Error::new(
+                        RtpError::Network,
+                        format!("{:?} stream couldn't move to {}: {}", stream_type, ip, e),
+                    ));
+                    continue;
+                }
+            };
+
+            let Ok(local_addr) = new_socket.local_addr() else {
+                continue;
+            };
+
+            socket.replace(new_socket);
+            rtcp_socket.replace(new_rtcp_socket);
+            peer_manager.rtp_session.set_local_addr(local_addr);
+            bound_ip = ip;
+
+            signaling.spawn_rejoin();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interop::audio::AudioFrameKind;
+    use crate::session_management::{media_clock::MonotonicClock, media_sink::NullSink};
+    use std::{
+        ffi::c_void,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    extern "C" fn count_release(context: *mut c_void) {
+        let released = unsafe { &*(context as *const AtomicUsize) };
+        released.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn frame(released: &'static AtomicUsize, timestamp: u32) -> EncodedFrame {
+        static DATA: [u8; 4] = [0, 0, 0, 0];
+
+        EncodedFrame {
+            data: DATA.as_ptr(),
+            len: DATA.len(),
+            context: released as *const AtomicUsize as *mut c_void,
+            release_callback: count_release,
+            timestamp,
+        }
+    }
+
+    #[tokio::test]
+    async fn rtcp_goes_on_the_next_port() {
+        let ip = "127.0.0.1".parse().unwrap();
+
+        for _ in 0..20 {
+            let (socket, rtcp_socket) = bind_media_sockets(ip).await.unwrap();
+
+            let rtp_port = socket.local_addr().unwrap().port();
+            assert_eq!(rtcp_socket.local_addr().unwrap().port(), rtp_port + 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn leaving_stops_everything() {
+        static RELEASED: AtomicUsize = AtomicUsize::new(0);
+
+        let sink = Arc::new(NullSink);
+        let session = Session::new(
+            Handle::current(),
+            CallEntry::Host,
+            RoomKey::derive("test room", "test secret").unwrap(),
+            Arc::clone(&sink) as Arc<dyn MediaSink>,
+            Arc::clone(&sink) as Arc<dyn SessionEvents>,
+            Arc::new(MonotonicClock::new()),
+        );
+
+        assert!(session.start_stream(StreamType::Video).is_ok());
+        assert!(session.start_stream(StreamType::Audio).is_ok());
+        assert_eq!(
+            session.start_stream(StreamType::Video).unwrap_err().code,
+            RtpError::StreamAlreadyStarted
+        );
+
+        // give the streams a moment to bind their sockets
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut sent = 0;
+        for timestamp in 0..20 {
+            if session
+                .send_frame(frame(&RELEASED, timestamp * 3000))
+                .is_ok()
+            {
+                sent += 1;
+            }
+        }
+
+        let signaling = Arc::downgrade(&session.signaling);
+        let lip_sync = Arc::downgrade(&session.lip_sync);
+
+        session.leave().await;
+
+        // whatever was still queued got handed back, the channel's gone
+        assert_eq!(
+            session.send_frame(frame(&RELEASED, 0)).unwrap_err().code,
+            RtpError::ChannelClosed
+        );
+        drop(session);
+
+        // the rejected frame above is released too
+        assert_eq!(RELEASED.load(Ordering::SeqCst), sent + 1);
+
+        // every task let go of its share of the session
+        assert!(signaling.upgrade().is_none());
+        assert!(lip_sync.upgrade().is_none());
+        assert_eq!(Arc::strong_count(&sink), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sessions_are_independent() {
+        let sink = Arc::new(NullSink);
+        let new_session = || {
+            Session::new(
+                Handle::current(),
+                CallEntry::Host,
+                RoomKey::derive("test room", "test secret").unwrap(),
+                Arc::clone(&sink) as Arc<dyn MediaSink>,
+                Arc::clone(&sink) as Arc<dyn SessionEvents>,
+                Arc::new(MonotonicClock::new()),
+            )
+        };
+
+        let first = new_session();
+        let second = new_session();
+
+        assert!(first.start_stream(StreamType::Audio).is_ok());
+        assert!(second.start_stream(StreamType::Audio).is_ok());
+
+        first.leave().await;
+
+        // the other call doesn't notice
+        assert!(!second.tasks.is_shut_down());
+
+        second.leave().await;
+        drop((first, second));
+
+        assert_eq!(Arc::strong_count(&sink), 1);
+    }
+
+    /// NullSink that remembers being detached
+    #[derive(Default)]
+    struct DetachingSink {
+        detached: AtomicBool,
+    }
+
+    impl MediaSink for DetachingSink {
+        fn receive_frame(&self, _context: *mut c_void, _frame: &mut [u8], _after_gap: bool) {}
+
+        fn receive_audio(
+            &self,
+            _context: *mut c_void,
+            _data: &[u8],
+            _ssrc: u32,
+            _playout_time: u32,
+            _samples: u32,
+            _kind: AudioFrameKind,
+        ) {
+        }
+
+        fn detach(&self) {
+            self.detached.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dropping_detaches_the_sink_right_away() {
+        let sink = Arc::new(DetachingSink::default());
+        let session = Session::new(
+            Handle::current(),
+            CallEntry::Host,
+            RoomKey::derive("test room", "test secret").unwrap(),
+            Arc::clone(&sink) as Arc<dyn MediaSink>,
+            Arc::new(NullSink),
+            Arc::new(MonotonicClock::new()),
+        );
+
+        assert!(session.start_stream(StreamType::Audio).is_ok());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // no leave(), the tasks are still winding down in the background
+        drop(session);
+
+        assert!(sink.detached.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,129 @@
+/*
+    How signaling messages go over TCP. Each one is a 4 byte big endian length,
+    then that many bytes of JSON.
+
+    TCP doesn't keep message boundaries, a big enough message (parameter sets plus
+    everyone's address) comes in over several reads, so we can't just read once and parse.
+*/
+
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// anything bigger isn't signaling, someone's confused (or not speaking our protocol)
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+pub async fn write_message<W, T>(writer: &mut W, message: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    write_frame(writer, &serde_json::to_vec(message)?).await
+}
+
+/// One length prefixed frame of whatever bytes, secure_channel.rs sends sealed JSON in these
+pub async fn write_frame<W>(writer: &mut W, payload: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Signaling message too big, {} bytes", payload.len()),
+        ));
+    }
+
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+/// None if the other side hung up cleanly instead of sending another message
+pub async fn read_message<R, T>(reader: &mut R) -> io::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let Some(payload) = read_frame(reader).await? else {
+        return Ok(None);
+    };
+
+    let message = serde_json::from_slice(&payload).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Could not parse request. {}", e),
+        )
+    })?;
+
+    Ok(Some(message))
+}
+
+/// None if the other side hung up cleanly
+pub async fn read_frame<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let length = match reader.read_u32().await {
+        Ok(length) => length as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    if length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Signaling message too big, {} bytes", length),
+        ));
+    }
+
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn messages_survive_being_split_up() {
+        // smaller than one message, so every read only gets part of it
+        let (mut writer, mut reader) = duplex(16);
+
+        let addresses: Vec<String> = (0..100).map(|i| format!("10.0.0.{}:5000", i)).collect();
+        let sent = addresses.clone();
+
+        let writing = tokio::spawn(async move {
+            write_message(&mut writer, &sent).await.unwrap();
+            write_message(&mut writer, &vec!["again".to_string()])
+                .await
+                .unwrap();
+        });
+
+        let first: Option<Vec<String>> = read_message(&mut reader).await.unwrap();
+        let second: Option<Vec<String>> = read_message(&mut reader).await.unwrap();
+        writing.await.unwrap();
+
+        assert_eq!(first, Some(addresses));
+        assert_eq!(second, Some(vec!["again".to_string()]));
+
+        // writer's gone
+        let end: Option<Vec<String>> = read_message(&mut reader).await.unwrap();
+        assert_eq!(end, None);
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused() {
+        let (mut writer, mut reader) = duplex(64);
+
+        writer.write_u32(MAX_MESSAGE_SIZE as u32 + 1).await.unwrap();
+
+        let read: io::Result<Option<String>> = read_message(&mut reader).await;
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let too_big = "a".repeat(MAX_MESSAGE_SIZE);
+        let written = write_message(&mut writer, &too_big).await;
+        assert_eq!(written.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,284 @@
+/*
+    What signaling servers say to each other.
+
+    Every message goes out as {"version": N, "message": {"type": "...", ...}}, framed
+    like signaling_framing.rs says. The version is checked before anything else is parsed,
+    so a newer build can change what's in a message without an older one choking on it
+    halfway through. Unknown versions get an Error back.
+
+    Between signaling servers that JSON is sealed first, after a handshake that proves both
+    sides know the room secret (secure_channel.rs). Only the rendezvous server gets it in the
+    clear, it learns addresses either way.
+
+    Everything's per participant, not per stream. A Join carries every stream the sender
+    has (its SSRC, RTP address and codec parameters), so the other side sets up their
+    audio and video together instead of hearing about them in two separate handshakes.
+    It also says which codecs they can decode (capabilities.rs), so nobody gets sent a
+    stream they can't play.
+
+    A conversation goes:
+    - Join, answered with a JoinAck carrying the other side's details and every signaling
+      address they know about (and the joiner then joins those too)
+    - the Join connection then stays open, both sides Ping every couple of seconds and
+      answer with a Pong. If it goes quiet for too long the peer's treated as gone
+    - UpdateMedia when someone's codec config changes mid-call or they start another
+      stream, not answered
+    - Leave on the way out, not answered
+
+    UpdateMedia and Leave go over the sender's own Join connection, so they can only ever
+    be about the participant on the other end of it.
+
+    The rendezvous server (rendezvous.rs) speaks the same thing. A client sends JoinRoom
+    with a room ID and gets a RoomJoined listing everyone already there, then joins
+    each of them directly as above. The rendezvous connection stays open with the same
+    heartbeats, and anyone else arriving, changing or leaving gets relayed down it as
+    the Join, UpdateMedia or Leave they'd have sent.
+*/
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+
+use crate::{
+    interop::StreamType,
+    session_management::{
+        capabilities::Capabilities,
+        signaling_framing::{read_frame, write_frame},
+    },
+};
+
+/// bump when a message changes in a way older builds can't read
+pub const PROTOCOL_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum StreamTypeWithArgs {
+    Video {
+        pps: Vec<u8>,
+        sps: Vec<u8>,
+    },
+    Audio {
+        sample_rate: f64,
+        channels: u32,
+        /// the sender can decode Opus in-band FEC, so encoders sending to it should turn it on
+        use_inband_fec: bool,
+        /// the sender can unpack RED (RFC 2198) redundant audio
+        red: bool,
+    },
+}
+
+impl StreamTypeWithArgs {
+    pub fn kind(&self) -> StreamType {
+        match self {
+            StreamTypeWithArgs::Audio { .. } => StreamType::Audio,
+            StreamTypeWithArgs::Video { .. } => StreamType::Video,
+        }
+    }
+}
+
+/// One of a participant's streams
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaSection {
+    pub local_rtp_address: String,
+    pub ssrc: u32,
+    pub stream_type: StreamTypeWithArgs,
+}
+
+/// One participant, every stream they're sending, and where to reach them
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ParticipantArgs {
+    pub signaling_address: String,
+    /// same for all of a participant's streams, it's how audio and video get paired up for lip sync
+    pub cname: String,
+    /// empty if they haven't got a stream going yet, an UpdateMedia follows once they do
+    pub media: Vec<MediaSection>,
+    /// what they decode
+    pub capabilities: Capabilities,
+}
+
+/// Sent to everyone's signaling server on the way out
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct LeaveArgs {
+    pub signaling_address: String,
+    pub ssrcs: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "body")]
+pub(crate) enum SignalingMessage {
+    /// "here are my streams, send me yours"
+    Join(ParticipantArgs),
+
+    /// answers a Join with the other side's streams
+    JoinAck {
+        args: ParticipantArgs,
+        peer_signalling_addresses: Vec<String>,
+    },
+
+    /// the sender's streams changed, replaces what their Join said
+    UpdateMedia(ParticipantArgs),
+
+    Leave(LeaveArgs),
+
+    /// to a rendezvous server, "put me in this room"
+    JoinRoom {
+        room: String,
+        participant: ParticipantArgs,
+    },
+
+    /// answers a JoinRoom with everyone else in the room
+    RoomJoined {
+        participants: Vec<ParticipantArgs>,
+    },
+
+    Ping,
+    Pong,
+
+    /// the other side couldn't make sense of what we sent
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Outgoing<'a> {
+    version: u32,
+    message: &'a SignalingMessage,
+}
+
+/// the message stays unparsed until we know which version it is
+#[derive(Deserialize)]
+struct Incoming {
+    version: u32,
+    message: serde_json::Value,
+}
+
+pub(crate) async fn send_message<W>(writer: &mut W, message: &SignalingMessage) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_frame(writer, &encode_message(message)?).await
+}
+
+/// None if the other side hung up. Anything we can't read is InvalidData
+pub(crate) async fn receive_message<R>(reader: &mut R) -> io::Result<Option<SignalingMessage>>
+where
+    R: AsyncRead + Unpin,
+{
+    match read_frame(reader).await? {
+        Some(payload) => decode_message(&payload).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The versioned JSON for one message, before framing (or sealing, see secure_channel.rs)
+pub(crate) fn encode_message(message: &SignalingMessage) -> io::Result<Vec<u8>> {
+    let outgoing = Outgoing {
+        version: PROTOCOL_VERSION,
+        message,
+    };
+
+    Ok(serde_json::to_vec(&outgoing)?)
+}
+
+pub(crate) fn decode_message(payload: &[u8]) -> io::Result<SignalingMessage> {
+    let parse_error = |e: serde_json::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Could not parse request. {}", e),
+        )
+    };
+
+    let incoming: Incoming = serde_json::from_slice(payload).map_err(parse_error)?;
+
+    if incoming.version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Unsupported signaling protocol version {}, we speak {}",
+                incoming.version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+
+    serde_json::from_value(incoming.message).map_err(parse_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_management::signaling_framing::write_message;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn other_versions_are_refused() {
+        let (mut writer, mut reader) = duplex(1024);
+
+        let from_the_future = serde_json::json!({
+            "version": PROTOCOL_VERSION + 1,
+            "message": { "type": "Hologram", "body": {} },
+        });
+        write_message(&mut writer, &from_the_future).await.unwrap();
+
+        let received = receive_message(&mut reader).await;
+        assert_eq!(received.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip() {
+        let (mut writer, mut reader) = duplex(1024);
+
+        send_message(&mut writer, &SignalingMessage::Ping)
+            .await
+            .unwrap();
+
+        let received = receive_message(&mut reader).await.unwrap();
+        assert!(matches!(received, Some(SignalingMessage::Ping)));
+    }
+
+    #[tokio::test]
+    async fn joins_carry_every_stream() {
+        let (mut writer, mut reader) = duplex(1024);
+
+        let join = SignalingMessage::Join(ParticipantArgs {
+            signaling_address: "10.0.0.1:4000".to_string(),
+            cname: "someone".to_string(),
+            media: vec![
+                MediaSection {
+                    local_rtp_address: "10.0.0.1:5000".to_string(),
+                    ssrc: 1,
+                    stream_type: StreamTypeWithArgs::Audio {
+                        sample_rate: 48000.0,
+                        channels: 1,
+                        use_inband_fec: true,
+                        red: true,
+                    },
+                },
+                MediaSection {
+                    local_rtp_address: "10.0.0.1:5002".to_string(),
+                    ssrc: 2,
+                    stream_type: StreamTypeWithArgs::Video {
+                        pps: vec![1, 2],
+                        sps: vec![3, 4],
+                    },
+                },
+            ],
+            capabilities: Capabilities::ours(true),
+        });
+
+        send_message(&mut writer, &join).await.unwrap();
+
+        let Some(SignalingMessage::Join(participant)) = receive_message(&mut reader).await.unwrap()
+        else {
+            panic!("expected a Join");
+        };
+
+        let kinds: Vec<StreamType> = participant
+            .media
+            .iter()
+            .map(|section| section.stream_type.kind())
+            .collect();
+        assert_eq!(kinds, vec![StreamType::Audio, StreamType::Video]);
+        assert_eq!(participant.cname, "someone");
+        assert_eq!(participant.capabilities, Capabilities::ours(true));
+    }
+}
@@ -1,90 +1,124 @@
 use bytes::Bytes;
-use core::slice;
-use dashmap::DashSet;
+use dashmap::{DashMap, DashSet};
 use local_ip_address::local_ip;
-use serde::{Deserialize, Serialize};
-use serde_json;
 use std::{
     collections::HashSet,
-    ffi::c_void,
-    net::SocketAddr,
-    sync::{Arc, OnceLock},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
 };
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::{Mutex, OnceCell},
+    io,
+    net::{
+        TcpListener, TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{Mutex, Notify, mpsc, oneshot},
+    time::{interval, sleep, timeout},
 };
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    interop::{StreamType, runtime},
-    session_management::peer_manager::PeerManager,
+    interop::{
+        StreamType,
+        error::{Error, ErrorReporter, RtpError},
+    },
+    session_management::{
+        capabilities::{Capabilities, Codec, Feature},
+        lip_sync::LipSync,
+        media_sink::SessionEvents,
+        peer_manager::PeerManager,
+        sdp::SessionDescription,
+        secure_channel::{self, Identity, RoomKey, SecureReader, SecureWriter},
+        session::SessionTasks,
+        signaling_protocol::{
+            LeaveArgs, MediaSection, ParticipantArgs, SignalingMessage, StreamTypeWithArgs,
+            receive_message, send_message,
+        },
+    },
 };
 
-const BUFFER_SIZE: usize = 1500;
-
-static AUDIO_PEERS: OnceLock<Arc<PeerManager>> = OnceLock::new();
-static FRAME_PEERS: OnceLock<Arc<PeerManager>> = OnceLock::new();
-
-static LISTENER: OnceCell<TcpListener> = OnceCell::const_new();
-
-static PEER_VIDEO_CONTEXT: OnceLock<PeerVideoManagerContext> = OnceLock::new();
-static AUDIO_MANAGER_CONTEXT: OnceLock<PeerVideoManagerContext> = OnceLock::new();
+/// how long to spend telling any one peer something (we're leaving, our media changed)
+const NOTIFY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// how often a joined connection gets pinged
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// how long a joined connection can go quiet before the peer is considered gone,
+/// a few missed heartbeats so one slow one doesn't drop them
+pub(crate) const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// how long to wait before trying the rendezvous server again after losing it
+const ROOM_RETRY: Duration = Duration::from_secs(2);
+
+/// How a session finds the rest of the call
+pub enum CallEntry {
+    /// a new call, people join us
+    Host,
+    /// the signaling address of anyone already in the call
+    Peer(String),
+    /// a rendezvous server (see rendezvous.rs) and the room to ask it for
+    Room {
+        rendezvous_addr: String,
+        room: String,
+    },
+}
 
-static PEER_SPECIFICATIONS: OnceLock<PeerSpecifications> = OnceLock::new();
-static SIGNALLING_ADDR: OnceLock<String> = OnceLock::new();
+/// The joined connection we're currently using for a participant
+struct LinkHandle {
+    id: u64,
 
-// TODO: update addr to use SSRC instead of address
-unsafe extern "C" {
-    fn swift_receive_pps_sps(
-        context: *mut c_void,
-        pps: *const u8,
-        pps_length: usize,
-        sps: *const u8,
-        sps_length: usize,
-        addr: *const u8,
-    ) -> *mut c_void;
+    /// closes the link without treating the peer as gone, for when a newer one replaces it
+    retire: CancellationToken,
 
-    fn swift_receive_audio_config(
-        audio_manager_context: *mut c_void,
-        sample_rate: f64,
-        channels: u32,
-        ssrc: u32,
-    ) -> *mut c_void;
+    /// our UpdateMedia and Leave go out over the link, so they can't come from anyone else
+    notices: mpsc::Sender<Notice>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(tag = "type")]
-enum StreamTypeWithArgs {
-    Video { pps: Vec<u8>, sps: Vec<u8> },
-    Audio { sample_rate: f64, channels: u32 },
+/// Our end of a link that's in `links` but not running yet, see open_link
+struct OpenLink {
+    id: u64,
+    retire: CancellationToken,
+    notice_rx: mpsc::Receiver<Notice>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ServerArgs {
-    signaling_address: String,
-    local_rtp_address: String,
-    ssrc: u32,
-    stream_type: StreamTypeWithArgs,
-    peer_signalling_addresses: Vec<String>,
+/// Something for a link to send, `sent` fires once it's gone out
+struct Notice {
+    message: SignalingMessage,
+    sent: oneshot::Sender<()>,
 }
 
-struct PeerVideoManagerContext {
-    context: *mut c_void,
+type SecureLink = (SecureReader<OwnedReadHalf>, SecureWriter<OwnedWriteHalf>);
+
+/// Who's on the other end of a joined signaling connection
+struct LinkPeer {
+    signaling_addr: SocketAddr,
+    cname: String,
 }
 
-// BAD BAD BAD!
-unsafe impl Send for PeerVideoManagerContext {}
-unsafe impl Sync for PeerVideoManagerContext {}
+impl LinkPeer {
+    fn new(args: &ParticipantArgs) -> io::Result<Self> {
+        Ok(Self {
+            signaling_addr: parse_addr(&args.signaling_address)?,
+            cname: args.cname.clone(),
+        })
+    }
+}
 
 pub struct H264Args {
-    sps: Bytes,
-    pps: Bytes,
+    pub sps: Bytes,
+    pub pps: Bytes,
 }
 
 pub struct OpusArgs {
-    sample_rate: f64,
-    channels: u32,
+    pub sample_rate: f64,
+    pub channels: u32,
+
+    /// advertised as `useinbandfec` (RFC 7587), we can rebuild lost frames from LBRR data
+    pub use_inband_fec: bool,
 }
 
 pub struct PeerSpecifications {
@@ -104,432 +138,1798 @@ impl PeerSpecifications {
 
     pub fn set_opus_args(&self, opus_args: OpusArgs) {
         let mut args = self.self_opus_args.blocking_lock();
-        *args = Some(opus_args);
+        args.replace(opus_args);
     }
 
     pub fn set_h264_args(&self, h264_args: H264Args) {
         let mut args = self.self_h264_args.blocking_lock();
-        *args = Some(h264_args);
+        args.replace(h264_args);
     }
 
     pub fn get_peers(&self) -> HashSet<SocketAddr> {
         self.peer_signaling_addresses
             .iter()
-            .map(|addr| addr.clone())
+            .map(|addr| *addr)
             .collect()
     }
 
     pub fn add_peer(&self, addr: SocketAddr) {
         self.peer_signaling_addresses.insert(addr);
     }
+
+    pub fn remove_peer(&self, addr: &SocketAddr) {
+        self.peer_signaling_addresses.remove(addr);
+    }
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn rust_set_signalling_addr(host_addr: *const u8, host_addr_length: usize) {
-    if !host_addr.is_null() {
-        let host_addr_slice = unsafe { slice::from_raw_parts(host_addr, host_addr_length) };
+impl Default for PeerSpecifications {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        let Ok(host_addr_str) = str::from_utf8(host_addr_slice) else {
-            return;
-        };
+/// One call's worth of signaling, shared by its audio and video streams
+pub struct SignalingServer {
+    audio_peers: OnceLock<Arc<PeerManager>>,
+    frame_peers: OnceLock<Arc<PeerManager>>,
 
-        let _ = SIGNALLING_ADDR.set(host_addr_str.to_string());
+    /// replaced when we move networks, see network_monitor.rs
+    listener: Mutex<Option<Arc<TcpListener>>>,
+    listener_moved: Notify,
 
-        println!("Set address!, {}", SIGNALLING_ADDR.get().unwrap())
-    }
-}
+    /// only the first stream to start runs the accept loop
+    serving: AtomicBool,
 
-#[unsafe(no_mangle)]
-pub extern "C" fn rust_send_video_callback(context: *mut c_void) {
-    let _ = PEER_VIDEO_CONTEXT.set(PeerVideoManagerContext { context });
-}
+    /// by the participant's CNAME
+    links: DashMap<String, LinkHandle>,
+    next_link_id: AtomicU64,
 
-#[unsafe(no_mangle)]
-pub extern "C" fn rust_send_audio_manger_context(context: *mut c_void) {
-    let _ = AUDIO_MANAGER_CONTEXT.set(PeerVideoManagerContext { context });
-}
+    /// everyone we've been told about by CNAME. Their streams get set up as ours start
+    participants: DashMap<String, ParticipantArgs>,
 
-#[unsafe(no_mangle)]
-pub extern "C" fn rust_send_opus_config(sample_rate: f64, channels: u32) {
-    let peer_specifications = PEER_SPECIFICATIONS.get_or_init(|| PeerSpecifications::new());
-    peer_specifications.set_opus_args(OpusArgs {
-        sample_rate,
-        channels,
-    });
+    /// streams the app started, we only join once each of them has its codec config
+    expects_audio: AtomicBool,
+    expects_video: AtomicBool,
+    joined: AtomicBool,
 
-    spawn_signaling_connection(StreamType::Audio);
-}
+    /// held while our Join, rejoin or UpdateMedia goes out, so they go out one at a time.
+    /// Otherwise an update could reach peers before the join does and get overwritten by it
+    announcing: Mutex<()>,
 
-#[unsafe(no_mangle)]
-pub extern "C" fn rust_send_h264_config(
-    pps: *const u8,
-    pps_length: usize,
-    sps: *const u8,
-    sps_length: usize,
-) {
-    let pps = unsafe { slice::from_raw_parts(pps, pps_length) };
+    specifications: PeerSpecifications,
 
-    let pps = Bytes::copy_from_slice(pps);
+    /// who to ask to join
+    entry: CallEntry,
 
-    let sps = unsafe { slice::from_raw_parts(sps, sps_length) };
+    /// our streams changed, the rendezvous server should hear about it too
+    room_update: Notify,
 
-    let sps = Bytes::copy_from_slice(sps);
+    /// our static key, random per session. Our RTCP CNAME comes from it, see secure_channel.rs
+    identity: Identity,
 
-    let peer_specifications = PEER_SPECIFICATIONS.get_or_init(|| PeerSpecifications::new());
-    peer_specifications.set_h264_args(H264Args { sps, pps });
+    /// every signaling connection proves it's in the call with this, see secure_channel.rs
+    room_key: RoomKey,
 
-    spawn_signaling_connection(StreamType::Video);
+    events: Arc<dyn SessionEvents>,
+    lip_sync: Arc<LipSync>,
+    errors: Arc<ErrorReporter>,
+    tasks: SessionTasks,
 }
 
-fn spawn_signaling_connection(stream_type: StreamType) {
-    let host_addr_str = match SIGNALLING_ADDR.get() {
-        Some(addr) => Some(addr),
-        None => None,
-    };
+impl SignalingServer {
+    pub fn new(
+        entry: CallEntry,
+        room_key: RoomKey,
+        events: Arc<dyn SessionEvents>,
+        lip_sync: Arc<LipSync>,
+        errors: Arc<ErrorReporter>,
+        tasks: SessionTasks,
+    ) -> Self {
+        Self {
+            audio_peers: OnceLock::new(),
+            frame_peers: OnceLock::new(),
+            listener: Mutex::new(None),
+            listener_moved: Notify::new(),
+            serving: AtomicBool::new(false),
+            links: DashMap::new(),
+            next_link_id: AtomicU64::new(0),
+            participants: DashMap::new(),
+            expects_audio: AtomicBool::new(false),
+            expects_video: AtomicBool::new(false),
+            joined: AtomicBool::new(false),
+            announcing: Mutex::new(()),
+            specifications: PeerSpecifications::new(),
+            entry,
+            room_update: Notify::new(),
+            identity: Identity::generate(),
+            room_key,
+            events,
+            lip_sync,
+            errors,
+            tasks,
+        }
+    }
 
-    runtime().spawn(async move {
-        println!("{:?} Making a request!", stream_type);
+    /// A stream is starting, the join waits for its codec config
+    pub fn expect_stream(&self, stream_type: StreamType) {
+        match stream_type {
+            StreamType::Audio => self.expects_audio.store(true, Ordering::SeqCst),
+            StreamType::Video => self.expects_video.store(true, Ordering::SeqCst),
+        }
+    }
 
-        if let Err(e) = connect_to_signaling_server(host_addr_str, stream_type).await {
-            eprintln!(
-                "{:?} Failed to connect to signaling server, {}",
-                stream_type, e
-            )
+    pub fn set_opus_args(self: &Arc<Self>, opus_args: OpusArgs) {
+        self.specifications.set_opus_args(opus_args);
+        self.spawn_join_or_update();
+    }
+
+    pub fn set_h264_args(self: &Arc<Self>, h264_args: H264Args) {
+        self.specifications.set_h264_args(h264_args);
+        self.spawn_join_or_update();
+    }
+
+    /// Joins the call once every started stream is ready to go, in one handshake.
+    /// After that, everyone gets told about whatever changed
+    fn spawn_join_or_update(self: &Arc<Self>) {
+        let server = Arc::clone(self);
+
+        self.tasks.spawn(async move {
+            let _announcing = server.announcing.lock().await;
+
+            // our codec config changed, what everyone can take from us might have too
+            server.renegotiate().await;
+
+            // the rest of our streams will call this again when they're ready
+            if !server.joined.load(Ordering::SeqCst) && !server.ready_to_join().await {
+                return;
+            }
+
+            if server.joined.swap(true, Ordering::SeqCst) {
+                server.send_media_update().await;
+            } else {
+                server.join().await;
+            }
+        });
+    }
+
+    async fn ready_to_join(&self) -> bool {
+        let expected = [
+            (StreamType::Audio, self.expects_audio.load(Ordering::SeqCst)),
+            (StreamType::Video, self.expects_video.load(Ordering::SeqCst)),
+        ];
+
+        for (stream_type, expected) in expected {
+            if expected && self.media_section(stream_type).await.is_none() {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    async fn send_media_update(&self) {
+        self.room_update.notify_one();
+
+        match self.participant_args().await {
+            Ok(update) => {
+                self.notify_peers(&SignalingMessage::UpdateMedia(update))
+                    .await
+            }
+            Err(e) => eprintln!("Failed to send media update, {}", e),
+        }
+    }
+
+    async fn join(self: &Arc<Self>) {
+        println!("Making a request!");
+
+        if let Err(e) = self.connect_to_signaling_server().await {
+            self.errors.report(Error::new(
+                error_code(&e),
+                format!("Failed to connect to signaling server, {}", e),
+            ));
         }
 
         // TODO: If the connection fails when you update your specs, try another peer
-    });
-}
+    }
 
-async fn listener() -> &'static TcpListener {
-    LISTENER
-        .get_or_init(|| async {
-            let local_ip = local_ip().unwrap();
-            TcpListener::bind(local_ip.to_string() + ":0")
-                .await
-                .unwrap()
-        })
-        .await
-}
+    /// Tells every peer's signaling server we're gone. RTCP BYE covers the media side,
+    /// this gets us out of the address lists that get handed to people joining
+    pub async fn notify_leave(&self) {
+        // never listened, so nobody knows about us
+        let listening = self.listener.lock().await.as_ref().map(|l| l.local_addr());
+        let Some(Ok(signaling_addr)) = listening else {
+            return;
+        };
 
-/// inject an instance of a peer manager for the server to manage
-pub async fn run_signaling_server(
-    peer_manager: Arc<PeerManager>,
-    stream_type: StreamType,
-) -> io::Result<()> {
-    let res = match stream_type {
-        StreamType::Audio => AUDIO_PEERS.set(Arc::clone(&peer_manager)),
-        StreamType::Video => FRAME_PEERS.set(Arc::clone(&peer_manager)),
-    };
+        let leave = SignalingMessage::Leave(LeaveArgs {
+            signaling_address: signaling_addr.to_string(),
+            ssrcs: [self.audio_peers.get(), self.frame_peers.get()]
+                .into_iter()
+                .flatten()
+                .map(|peer_manager| peer_manager.local_ssrc())
+                .collect(),
+        });
+
+        self.notify_peers(&leave).await;
+    }
+
+    /// Sends `message` down every link, nobody answers these
+    async fn notify_peers(&self, message: &SignalingMessage) {
+        let links: Vec<(String, mpsc::Sender<Notice>)> = self
+            .links
+            .iter()
+            .map(|link| (link.key().clone(), link.notices.clone()))
+            .collect();
+
+        for (cname, notices) in links {
+            let (sent, gone_out) = oneshot::channel();
+            let notice = Notice {
+                message: message.clone(),
+                sent,
+            };
+
+            let send = async {
+                notices.send(notice).await.ok()?;
+                gone_out.await.ok()
+            };
 
-    println!("Peer manager of type, {:?}, has been set", stream_type);
+            let failure = match timeout(NOTIFY_TIMEOUT, send).await {
+                Ok(Some(())) => continue,
+                Ok(None) => format!("Failed to notify {}, their link closed", cname),
+                Err(_) => format!("Timed out notifying {}", cname),
+            };
 
-    // return early. Do NOT run another instance of the server!
-    if res.is_err() {
-        return Ok(());
+            self.errors.report(Error::new(RtpError::Signaling, failure));
+        }
     }
 
-    println!("{}", listener().await.local_addr().unwrap());
+    fn handle_leave(&self, leave: &LeaveArgs) {
+        if let Ok(signaling_addr) = leave.signaling_address.parse::<SocketAddr>() {
+            self.specifications.remove_peer(&signaling_addr);
+        }
 
-    loop {
-        let (mut socket, client_addr) = match listener().await.accept().await {
-            Ok(conn) => conn,
-            Err(e) => {
-                eprintln!("Failed to accept connection: {}", e);
-                continue;
+        self.participants
+            .retain(|_, participant| participant.signaling_address != leave.signaling_address);
+
+        // their BYE probably beat us here, doesn't hurt to make sure
+        for ssrc in &leave.ssrcs {
+            for peer_manager in [self.audio_peers.get(), self.frame_peers.get()]
+                .into_iter()
+                .flatten()
+            {
+                peer_manager.remove_peer(*ssrc);
             }
+        }
+    }
+
+    /// A Leave relayed by the rendezvous server. That connection isn't authenticated, so it only
+    /// counts for someone we know at that address and have no link to (a link notices on its own
+    /// when they're gone), and only for the SSRCs they sent us themselves
+    fn handle_room_leave(&self, leave: &LeaveArgs) {
+        let Some(cname) = self
+            .participants
+            .iter()
+            .find(|participant| participant.signaling_address == leave.signaling_address)
+            .map(|participant| participant.key().clone())
+        else {
+            return;
+        };
+
+        if self.links.contains_key(&cname) {
+            return;
+        }
+
+        let ssrcs = leave
+            .ssrcs
+            .iter()
+            .copied()
+            .filter(|ssrc| self.owner(*ssrc).as_deref() == Some(cname.as_str()))
+            .collect();
+
+        self.handle_leave(&LeaveArgs {
+            signaling_address: leave.signaling_address.clone(),
+            ssrcs,
+        });
+    }
+
+    /// Puts a link in `links` before it runs, so notices sent in the meantime queue up for it
+    fn open_link(&self, peer: &LinkPeer) -> OpenLink {
+        let id = self.next_link_id.fetch_add(1, Ordering::Relaxed);
+        let retire = CancellationToken::new();
+        let (notices, notice_rx) = mpsc::channel::<Notice>(8);
+
+        let link_handle = LinkHandle {
+            id,
+            retire: retire.clone(),
+            notices,
         };
 
-        // just twaddle until we get our own specs,
-        // awaiting a connection should hold this off
-        if PEER_SPECIFICATIONS.get().is_none() {
-            continue;
+        // they joined again from somewhere else, the old connection's done
+        if let Some(old) = self.links.insert(peer.cname.clone(), link_handle) {
+            old.retire.cancel();
+        }
+
+        OpenLink {
+            id,
+            retire,
+            notice_rx,
         }
+    }
+
+    /// Keeps a joined connection alive with heartbeats until one side leaves or goes quiet,
+    /// which is how we find out about peers that crashed or lost their network
+    async fn run_link(&self, link: SecureLink, peer: LinkPeer, opened: OpenLink) {
+        let OpenLink {
+            id,
+            retire,
+            mut notice_rx,
+        } = opened;
+
+        let (mut reader, mut writer) = link;
+
+        // pongs get queued by the reading half
+        let (reply_tx, mut reply_rx) = mpsc::channel::<SignalingMessage>(8);
+
+        let writing = async {
+            let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    _ = heartbeat.tick() => writer.send(&SignalingMessage::Ping).await?,
+                    Some(reply) = reply_rx.recv() => writer.send(&reply).await?,
+                    Some(notice) = notice_rx.recv() => {
+                        writer.send(&notice.message).await?;
+                        let _ = notice.sent.send(());
+                    }
+                }
+            }
+        };
 
-        println!("Request from {}", client_addr.to_string());
+        let reading = async {
+            loop {
+                let message = match timeout(HEARTBEAT_TIMEOUT, reader.receive()).await {
+                    Ok(Ok(Some(message))) => message,
+                    Ok(Ok(None)) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Connection closed",
+                        ));
+                    }
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "No heartbeat"));
+                    }
+                };
+
+                match message {
+                    SignalingMessage::Ping => {
+                        let _ = reply_tx.try_send(SignalingMessage::Pong);
+                    }
+                    SignalingMessage::Pong => {}
+                    SignalingMessage::UpdateMedia(update) => {
+                        if update.cname != peer.cname {
+                            return Err(impersonation(&peer, "an UpdateMedia", &update.cname));
+                        }
+
+                        self.check_identity(&update)?;
+                        self.handle_participant(&update).await?;
+                    }
+                    SignalingMessage::Leave(leave) => {
+                        if parse_addr(&leave.signaling_address)? != peer.signaling_addr
+                            || leave.ssrcs.iter().any(|ssrc| {
+                                self.owner(*ssrc).is_some_and(|owner| owner != peer.cname)
+                            })
+                        {
+                            return Err(impersonation(&peer, "a Leave", &leave.signaling_address));
+                        }
+
+                        self.handle_leave(&leave);
+                        return Ok(());
+                    }
+                    SignalingMessage::Error { message } => {
+                        eprintln!("{} sent an error: {}", peer.signaling_addr, message);
+                    }
+                    SignalingMessage::Join(_)
+                    | SignalingMessage::JoinAck { .. }
+                    | SignalingMessage::JoinRoom { .. }
+                    | SignalingMessage::RoomJoined { .. } => {
+                        eprintln!("Unexpected message from {}", peer.signaling_addr);
+                    }
+                }
+            }
+        };
+
+        let result: io::Result<()> = tokio::select! {
+            result = writing => result,
+            result = reading => result,
+            _ = retire.cancelled() => return,
+        };
+
+        // only the newest link for a peer gets to decide they're gone
+        let current = self
+            .links
+            .remove_if(&peer.cname, |_, link| link.id == id)
+            .is_some();
+
+        if let Err(e) = result {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                self.report_security(format!(
+                    "Dropped {} ({}), {}",
+                    peer.cname, peer.signaling_addr, e
+                ));
+            } else {
+                eprintln!("Lost {}: {}", peer.signaling_addr, e);
+            }
 
-        runtime().spawn(async move {
-            if let Err(e) = handle_signaling_client(&mut socket).await {
-                eprintln!("Signaling error with {}: {}", client_addr, e);
+            if current {
+                self.handle_peer_lost(&peer);
+            }
+        }
+    }
+
+    /// Someone tried to get in without the room secret, or speak for someone else
+    fn report_security(&self, what: String) {
+        self.errors.report(Error::new(RtpError::Security, what));
+    }
+
+    /// The CNAME of whoever sends `ssrc`, if it's anyone we know about
+    fn owner(&self, ssrc: u32) -> Option<String> {
+        self.participants
+            .iter()
+            .find(|participant| participant.media.iter().any(|m| m.ssrc == ssrc))
+            .map(|participant| participant.key().clone())
+    }
+
+    /// Same as a Leave, for someone who didn't get to send one
+    fn handle_peer_lost(&self, peer: &LinkPeer) {
+        self.specifications.remove_peer(&peer.signaling_addr);
+
+        let Some((_, participant)) = self.participants.remove(&peer.cname) else {
+            return;
+        };
+
+        for section in &participant.media {
+            if let Some(peer_manager) = self.peer_manager(section.stream_type.kind()) {
+                peer_manager.remove_peer(section.ssrc);
+            }
+        }
+    }
+
+    /// Binds on first use. Fails instead of panicking when there's no network to bind to
+    async fn listener(&self) -> io::Result<Arc<TcpListener>> {
+        let mut listener = self.listener.lock().await;
+
+        if let Some(listener) = listener.as_ref() {
+            return Ok(Arc::clone(listener));
+        }
+
+        let local_ip = local_ip().map_err(io::Error::other)?;
+        let bound = Arc::new(TcpListener::bind(SocketAddr::new(local_ip, 0)).await?);
+
+        *listener = Some(Arc::clone(&bound));
+        Ok(bound)
+    }
+
+    /// Moves the listener to `ip` after a network change. The links we had are left to die
+    /// on their own, peers replace them once each stream joins them again (see spawn_rejoin)
+    pub async fn rebind(&self, ip: IpAddr) -> io::Result<()> {
+        let bound = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+
+        println!("Signaling moved to {}", bound.local_addr()?);
+
+        *self.listener.lock().await = Some(Arc::new(bound));
+        self.listener_moved.notify_one();
+
+        // whatever happens to them now, it's the old address failing, not the peer
+        self.links.clear();
+
+        Ok(())
+    }
+
+    /// Where people join us, binds the listener if nothing has yet
+    pub async fn signaling_addr(&self) -> io::Result<SocketAddr> {
+        self.listener().await?.local_addr()
+    }
+
+    pub fn cname(&self) -> &str {
+        self.identity.cname()
+    }
+
+    /// Joins everyone we know about again after a network change, same SSRC and CNAME
+    /// so they update where they send to instead of seeing someone new
+    pub fn spawn_rejoin(self: &Arc<Self>) {
+        // never joined in the first place, spawn_join_or_update will
+        if !self.joined.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let server = Arc::clone(self);
+
+        self.tasks.spawn(async move {
+            let _announcing = server.announcing.lock().await;
+
+            let request = match server.participant_args().await {
+                Ok(args) => SignalingMessage::Join(args),
+                Err(e) => {
+                    eprintln!("Failed to rejoin, {}", e);
+                    return;
+                }
+            };
+
+            for addr in server.specifications.get_peers() {
+                if let Err(e) = server
+                    .add_peers(&addr.to_string(), &request, &mut Vec::new())
+                    .await
+                {
+                    eprintln!("Failed to rejoin {}: {}", addr, e);
+                }
             }
         });
     }
-}
 
-async fn handle_signaling_client(socket: &mut TcpStream) -> io::Result<()> {
-    let mut buffer = [0; BUFFER_SIZE];
+    /// inject an instance of a peer manager for the server to manage
+    pub async fn run_signaling_server(
+        self: Arc<Self>,
+        peer_manager: Arc<PeerManager>,
+        stream_type: StreamType,
+    ) -> io::Result<()> {
+        let res = match stream_type {
+            StreamType::Audio => self.audio_peers.set(Arc::clone(&peer_manager)),
+            StreamType::Video => self.frame_peers.set(Arc::clone(&peer_manager)),
+        };
+
+        println!("Peer manager of type, {:?}, has been set", stream_type);
+
+        // this kind of stream's already running
+        if res.is_err() {
+            return Ok(());
+        }
+
+        // people who joined before this stream started, and our own section for it
+        self.attach_participants(stream_type).await;
+        self.spawn_join_or_update();
+
+        // return early. Do NOT run another instance of the server!
+        if self.serving.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        println!("{}", self.signaling_addr().await?);
 
-    let bytes_read = socket.read(&mut buffer).await?;
-    if bytes_read == 0 {
-        return Ok(());
+        loop {
+            let listener = self.listener().await?;
+
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.listener_moved.notified() => continue,
+            };
+
+            let (socket, client_addr) = match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            println!("Request from {}", client_addr);
+
+            let server = Arc::clone(&self);
+            self.tasks.spawn(async move {
+                match server.handle_signaling_client(socket).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        server.report_security(format!(
+                            "Rejected a join from {}, {}",
+                            client_addr, e
+                        ));
+                    }
+                    Err(e) => eprintln!("Signaling error with {}: {}", client_addr, e),
+                }
+            });
+        }
     }
 
-    // parsing the request
-    let request: ServerArgs = serde_json::from_slice(&buffer[..bytes_read]).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("Could not parse request. {}", e),
+    /// Everything that comes in has to prove it's in the call first. Then it has to be a Join,
+    /// the connection stays open as their link (see run_link) and everything else comes over that
+    async fn handle_signaling_client(&self, socket: TcpStream) -> io::Result<()> {
+        let (reader, writer) = socket.into_split();
+
+        let (mut reader, mut writer) = match timeout(
+            HEARTBEAT_TIMEOUT,
+            secure_channel::accept(reader, writer, &self.room_key, &self.identity),
         )
-    })?;
-
-    let request_stream_type = match request.stream_type {
-        StreamTypeWithArgs::Audio {
-            sample_rate: _,
-            channels: _,
-        } => StreamType::Audio,
-        StreamTypeWithArgs::Video { pps: _, sps: _ } => StreamType::Video,
-    };
+        .await
+        {
+            Ok(channel) => channel?,
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "No handshake"));
+            }
+        };
 
-    let personal_args = get_specifications(request_stream_type).await?;
+        // parsing the request
+        let message = match reader.receive().await {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // let them know why, if they're still listening
+                let _ = send_secure_error(&mut writer, &e).await;
+                return Err(e);
+            }
+        };
 
-    let response = write_response(personal_args).await?;
+        let request = match message {
+            SignalingMessage::Join(request) => request,
+            SignalingMessage::Ping => {
+                return writer.send(&SignalingMessage::Pong).await;
+            }
+            SignalingMessage::Error { message } => {
+                return Err(io::Error::other(format!("Peer sent an error: {}", message)));
+            }
+            // these only count over the sender's own link, from here they could be anyone
+            SignalingMessage::UpdateMedia(_) | SignalingMessage::Leave(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Sent an UpdateMedia or Leave outside of a link",
+                ));
+            }
+            SignalingMessage::JoinAck { .. }
+            | SignalingMessage::JoinRoom { .. }
+            | SignalingMessage::RoomJoined { .. }
+            | SignalingMessage::Pong => {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "Unexpected signaling message");
+                let _ = send_secure_error(&mut writer, &e).await;
+                return Err(e);
+            }
+        };
 
-    socket.write_all(&response.as_bytes()).await?;
+        if let Err(e) =
+            check_key(reader.remote_cname(), &request).and_then(|()| self.check_identity(&request))
+        {
+            let _ = send_secure_error(&mut writer, &e).await;
+            return Err(e);
+        }
 
-    println!("Handling a request");
-    handle_request(&request).await?;
+        // whatever streams we have so far, the rest follow in an UpdateMedia
+        let response = SignalingMessage::JoinAck {
+            args: self.participant_args().await?,
+            peer_signalling_addresses: self
+                .specifications
+                .get_peers()
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect(),
+        };
 
-    Ok(())
-}
+        writer.send(&response).await?;
 
-pub async fn connect_to_signaling_server(
-    server_addr: Option<&String>,
-    stream_type: StreamType,
-) -> io::Result<()> {
-    // this is the case when you're the first person.
-    // You don't have anyone to connect to
-    let Some(server_addr) = server_addr else {
-        return Ok(());
-    };
+        println!("Handling a request");
+        self.handle_participant(&request).await?;
+
+        let peer = LinkPeer::new(&request)?;
+        let opened = self.open_link(&peer);
+        self.run_link((reader, writer), peer, opened).await;
+
+        Ok(())
+    }
+
+    pub async fn connect_to_signaling_server(self: &Arc<Self>) -> io::Result<()> {
+        let server_addr = match &self.entry {
+            // this is the case when you're the first person.
+            // You don't have anyone to connect to
+            CallEntry::Host => return Ok(()),
+            CallEntry::Peer(server_addr) => server_addr,
+            // the room's joined from its own task, it stays connected for the whole call
+            CallEntry::Room {
+                rendezvous_addr,
+                room,
+            } => {
+                let server = Arc::clone(self);
+                let (rendezvous_addr, room) = (rendezvous_addr.clone(), room.clone());
+
+                self.tasks
+                    .spawn(async move { server.stay_in_room(&rendezvous_addr, &room).await });
+                return Ok(());
+            }
+        };
 
-    let personal_args = get_specifications(stream_type).await?;
+        let request = SignalingMessage::Join(self.participant_args().await?);
 
-    let request = write_response(personal_args).await?;
+        //  this is silly, but connect to the first person and get their data and everyone's signalling address
+        //  You'll only get their data! This is to make sure you connect to everyone
+        //  addresses will be stored in vector
+        let mut addresses: Vec<String> = Vec::new();
+        self.add_peers(server_addr, &request, &mut addresses)
+            .await?;
 
-    //  this is silly, but connect to the first person and get their data and everyone's signalling address
-    //  You'll only get their data! This is to make sure you connect to everyone
-    //  addresses will be stored in vector
-    let mut addresses: Vec<String> = Vec::new();
-    add_peers(&server_addr, &request, &mut addresses).await?;
+        //  now, just loop through the addresses and get their data.
+        //  The addresses are redundant since you got them already
+        //  hence the empty vector
 
-    //  now, just loop through the addresses and get their data.
-    //  The addresses are redundant since you got them already
-    //  hence the empty vector
+        println!("{}", addresses.len());
+        for signaling_addr in &addresses {
+            if *signaling_addr == self.signaling_addr().await?.to_string() {
+                continue;
+            }
 
-    println!("{}", addresses.len());
-    for signaling_addr in &addresses {
-        if *signaling_addr == listener().await.local_addr().unwrap().to_string() {
-            continue;
+            if let Err(e) = self
+                .add_peers(
+                    signaling_addr,
+                    &request,
+                    &mut Vec::with_capacity(addresses.len()),
+                )
+                .await
+            {
+                if e.kind() == io::ErrorKind::PermissionDenied {
+                    self.report_security(format!("Couldn't join {}, {}", signaling_addr, e));
+                } else {
+                    eprint!("Error! : {}", e);
+                }
+                continue;
+            }
         }
 
-        if let Err(e) = add_peers(
-            signaling_addr,
-            &request,
-            &mut Vec::with_capacity(addresses.len()),
+        Ok(())
+    }
+
+    /// Never returns, the rendezvous connection comes back whenever it drops (ex: we moved networks).
+    /// Only the first failure goes to the app, the rest would just be the same one again
+    async fn stay_in_room(self: &Arc<Self>, rendezvous_addr: &str, room: &str) -> ! {
+        let mut told_app = false;
+
+        loop {
+            if let Err(e) = self.room_session(rendezvous_addr, room).await {
+                if told_app {
+                    eprintln!("Lost the rendezvous server, {}", e);
+                } else {
+                    self.errors.report(Error::new(
+                        RtpError::Signaling,
+                        format!("Lost the rendezvous server, {}", e),
+                    ));
+                    told_app = true;
+                }
+            }
+
+            sleep(ROOM_RETRY).await;
+        }
+    }
+
+    /// Gets into the room and joins everyone there, then stays connected so people who
+    /// show up later can find us. Returns when the rendezvous connection drops
+    async fn room_session(self: &Arc<Self>, rendezvous_addr: &str, room: &str) -> io::Result<()> {
+        let mut socket = TcpStream::connect(rendezvous_addr).await?;
+
+        // updates wait until everyone here has our join
+        let announcing = self.announcing.lock().await;
+
+        let me = self.participant_args().await?;
+        let request = SignalingMessage::Join(me.clone());
+
+        let join_room = SignalingMessage::JoinRoom {
+            room: room.to_string(),
+            participant: me,
+        };
+        send_message(&mut socket, &join_room).await?;
+
+        let participants = match receive_message(&mut socket).await? {
+            Some(SignalingMessage::RoomJoined { participants }) => participants,
+            Some(SignalingMessage::Error { message }) => {
+                return Err(io::Error::other(format!(
+                    "{} turned us down: {}",
+                    rendezvous_addr, message
+                )));
+            }
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Expected a RoomJoined",
+                ));
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "No response from rendezvous server",
+                ));
+            }
+        };
+
+        // everyone already there gets joined directly, like any other join
+        for participant in &participants {
+            // still linked from before we lost the rendezvous server
+            if self.links.contains_key(&participant.cname) {
+                continue;
+            }
+
+            if let Err(e) = self
+                .add_peers(&participant.signaling_address, &request, &mut Vec::new())
+                .await
+            {
+                if e.kind() == io::ErrorKind::PermissionDenied {
+                    self.report_security(format!("Couldn't join {}, {}", participant.cname, e));
+                } else {
+                    eprintln!("Failed to join {}: {}", participant.cname, e);
+                }
+            }
+        }
+
+        drop(announcing);
+
+        let (mut reader, mut writer) = socket.into_split();
+        let (reply_tx, mut reply_rx) = mpsc::channel::<SignalingMessage>(8);
+
+        let writing = async {
+            let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+
+            loop {
+                let message = tokio::select! {
+                    _ = heartbeat.tick() => SignalingMessage::Ping,
+                    Some(reply) = reply_rx.recv() => reply,
+                    _ = self.room_update.notified() => {
+                        SignalingMessage::UpdateMedia(self.participant_args().await?)
+                    }
+                };
+
+                send_message(&mut writer, &message).await?;
+            }
+        };
+
+        let reading = async {
+            loop {
+                let message = match timeout(HEARTBEAT_TIMEOUT, receive_message(&mut reader)).await {
+                    Ok(Ok(Some(message))) => message,
+                    Ok(Ok(None)) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Connection closed",
+                        ));
+                    }
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "No heartbeat"));
+                    }
+                };
+
+                match message {
+                    SignalingMessage::Ping => {
+                        let _ = reply_tx.try_send(SignalingMessage::Pong);
+                    }
+                    SignalingMessage::Pong => {}
+                    // newcomers join us themselves, and changes come over their link
+                    SignalingMessage::Join(_) | SignalingMessage::UpdateMedia(_) => {}
+                    SignalingMessage::Leave(leave) => self.handle_room_leave(&leave),
+                    SignalingMessage::Error { message } => {
+                        eprintln!("Rendezvous server sent an error: {}", message);
+                    }
+                    _ => eprintln!("Unexpected message from the rendezvous server"),
+                }
+            }
+        };
+
+        tokio::select! {
+            result = writing => result,
+            result = reading => result,
+        }
+    }
+
+    async fn add_peers(
+        self: &Arc<Self>,
+        signaling_addr: &str,
+        request: &SignalingMessage,
+        addresses: &mut Vec<String>,
+    ) -> io::Result<()> {
+        let (reader, writer) = TcpStream::connect(signaling_addr).await?.into_split();
+
+        let (mut reader, mut writer) = match timeout(
+            HEARTBEAT_TIMEOUT,
+            secure_channel::connect(reader, writer, &self.room_key, &self.identity),
         )
         .await
         {
-            eprint!("Error! : {}", e);
-            continue;
+            Ok(channel) => channel?,
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "No handshake"));
+            }
+        };
+
+        writer.send(request).await?;
+
+        let (response, peer_signalling_addresses) = match reader.receive().await? {
+            Some(SignalingMessage::JoinAck {
+                args,
+                peer_signalling_addresses,
+            }) => (args, peer_signalling_addresses),
+            Some(SignalingMessage::Error { message }) => {
+                return Err(io::Error::other(format!(
+                    "{} turned us down: {}",
+                    signaling_addr, message
+                )));
+            }
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Expected a JoinAck",
+                ));
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "No response from server",
+                ));
+            }
+        };
+
+        println!("Adding a peer!");
+        check_key(reader.remote_cname(), &response)?;
+        self.check_identity(&response)?;
+        self.handle_participant(&response).await?;
+
+        // stays open so we notice if they vanish without a Leave.
+        // Opened here, an UpdateMedia right after this join has to go down it
+        let peer = LinkPeer::new(&response)?;
+        let opened = self.open_link(&peer);
+        let server = Arc::clone(self);
+        self.tasks
+            .spawn(async move { server.run_link((reader, writer), peer, opened).await });
+
+        addresses.extend(peer_signalling_addresses);
+
+        Ok(())
+    }
+
+    fn peer_manager(&self, stream_type: StreamType) -> Option<&Arc<PeerManager>> {
+        match stream_type {
+            StreamType::Audio => self.audio_peers.get(),
+            StreamType::Video => self.frame_peers.get(),
         }
     }
 
-    Ok(())
-}
+    /// None until the stream's running and the app gave us its codec config
+    async fn media_section(&self, stream_type: StreamType) -> Option<MediaSection> {
+        let peer_manager = self.peer_manager(stream_type)?;
+        let stream_type = self.get_specifications(stream_type).await.ok()?;
 
-async fn add_peers(
-    signaling_addr: &str,
-    packet: &str,
-    addresses: &mut Vec<String>,
-) -> io::Result<()> {
-    let mut buffer = [0u8; BUFFER_SIZE];
-    let mut socket = TcpStream::connect(signaling_addr).await?;
+        Some(MediaSection {
+            local_rtp_address: peer_manager.local_rtp_addr().to_string(),
+            ssrc: peer_manager.local_ssrc(),
+            stream_type,
+        })
+    }
+
+    /// Our streams as SDP, for tools that don't speak our signaling
+    pub async fn session_description(&self) -> io::Result<String> {
+        let participant = self.participant_args().await?;
+        Ok(SessionDescription::from_participant(&participant)?.to_string())
+    }
 
-    socket.write_all(packet.as_bytes()).await?;
+    /// Us, with every stream that's ready to go
+    async fn participant_args(&self) -> io::Result<ParticipantArgs> {
+        let signaling_addr = self.signaling_addr().await?;
 
-    let bytes_read = socket.read(&mut buffer).await?;
+        let mut media = Vec::new();
+        for stream_type in [StreamType::Audio, StreamType::Video] {
+            if let Some(section) = self.media_section(stream_type).await {
+                media.push(section);
+            }
+        }
 
-    if bytes_read == 0 {
-        return Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "No response from server",
-        ));
+        Ok(ParticipantArgs {
+            signaling_address: signaling_addr.to_string(),
+            cname: self.cname().to_string(),
+            media,
+            capabilities: self.capabilities().await,
+        })
     }
 
-    let response: ServerArgs = serde_json::from_slice(&buffer[..bytes_read]).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("Could not parse request. {}", e),
-        )
-    })?;
+    /// What we decode, FEC depends on what the app set up
+    async fn capabilities(&self) -> Capabilities {
+        let use_inband_fec = self
+            .specifications
+            .self_opus_args
+            .lock()
+            .await
+            .as_ref()
+            .is_some_and(|opus_args| opus_args.use_inband_fec);
+
+        Capabilities::ours(use_inband_fec)
+    }
+
+    /// A Join, JoinAck or UpdateMedia can't claim streams someone else already has, that'd hijack them.
+    /// Their own CNAME's fine, it's them again after moving networks
+    fn check_identity(&self, participant: &ParticipantArgs) -> io::Result<()> {
+        for section in &participant.media {
+            if let Some(owner) = self.owner(section.ssrc)
+                && owner != participant.cname
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "{} claimed SSRC {}, which is {}'s",
+                        participant.cname, section.ssrc, owner
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A participant's Join, JoinAck or UpdateMedia. Sets up every stream of theirs
+    /// we can take, the rest wait in `participants` until ours start
+    async fn handle_participant(&self, participant: &ParticipantArgs) -> io::Result<()> {
+        println!("{:?}", participant);
 
-    println!("Adding a peer!");
-    handle_request(&response).await?;
+        let signaling_addr = parse_addr(&participant.signaling_address)?;
 
-    let signaling_addr: SocketAddr = signaling_addr
-        .parse()
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let previous = self
+            .participants
+            .insert(participant.cname.clone(), participant.clone());
 
-    PEER_SPECIFICATIONS.get().unwrap().add_peer(signaling_addr);
+        // they moved networks, nobody's listening at the old address anymore
+        if let Some(previous) = &previous
+            && previous.signaling_address != participant.signaling_address
+            && let Ok(old_addr) = parse_addr(&previous.signaling_address)
+        {
+            self.specifications.remove_peer(&old_addr);
+        }
 
-    for response in response.peer_signalling_addresses {
-        addresses.push(response);
+        self.specifications.add_peer(signaling_addr);
+
+        for section in &participant.media {
+            let previous_section = previous
+                .as_ref()
+                .and_then(|previous| previous.media.iter().find(|m| m.ssrc == section.ssrc));
+
+            self.add_media(&participant.cname, section, previous_section)
+                .await?;
+        }
+
+        self.negotiate(participant).await;
+
+        Ok(())
     }
 
-    Ok(())
+    /// Works out what we and a participant can both decode, and what each of their
+    /// streams gets back from us. Anything of ours they can't decode isn't sent to them
+    async fn negotiate(&self, participant: &ParticipantArgs) {
+        let agreed = self
+            .capabilities()
+            .await
+            .intersect(&participant.capabilities);
+
+        for section in &participant.media {
+            let stream_type = section.stream_type.kind();
+
+            let Some(peer_manager) = self.peer_manager(stream_type) else {
+                continue;
+            };
+
+            // no config yet, nothing's being sent. spawn_join_or_update comes back here once there is
+            if let Some(ours) = self.media_section(stream_type).await {
+                let decodes = agreed.decodes(&ours.stream_type);
+                if !decodes {
+                    println!(
+                        "{} can't decode our {:?}, not sending it to them",
+                        participant.cname, stream_type
+                    );
+                }
+
+                peer_manager.set_peer_decodes_our_stream(section.ssrc, decodes);
+            }
+
+            if let StreamTypeWithArgs::Audio { red, .. } = section.stream_type {
+                let accepts_red = red && agreed.supports(Codec::Opus, Feature::Red);
+                peer_manager.set_peer_accepts_red(section.ssrc, accepts_red);
+            }
+        }
+    }
+
+    /// Everyone again, after our own config changed
+    async fn renegotiate(&self) {
+        let participants: Vec<ParticipantArgs> = self
+            .participants
+            .iter()
+            .map(|participant| participant.clone())
+            .collect();
+
+        for participant in &participants {
+            self.negotiate(participant).await;
+        }
+    }
+
+    /// One of their streams, for when ours of the same kind just started
+    async fn attach_participants(&self, stream_type: StreamType) {
+        let participants: Vec<ParticipantArgs> = self
+            .participants
+            .iter()
+            .map(|participant| participant.clone())
+            .collect();
+
+        for participant in &participants {
+            for section in &participant.media {
+                if section.stream_type.kind() != stream_type {
+                    continue;
+                }
+
+                if let Err(e) = self.add_media(&participant.cname, section, None).await {
+                    eprintln!("Failed to add {}'s stream: {}", participant.cname, e);
+                }
+            }
+
+            self.negotiate(participant).await;
+        }
+    }
+
+    /// `previous` is what they said about this stream last time, if anything
+    async fn add_media(
+        &self,
+        cname: &str,
+        section: &MediaSection,
+        previous: Option<&MediaSection>,
+    ) -> io::Result<()> {
+        let stream_type = section.stream_type.kind();
+
+        // we don't have this kind of stream (yet), attach_participants picks it up
+        let Some(peer_manager) = self.peer_manager(stream_type) else {
+            return Ok(());
+        };
+
+        let media_addr = parse_addr(&section.local_rtp_address)?;
+
+        // their codec config changed, whatever was decoding their old one has to go
+        if let Some(previous) = previous
+            && previous.stream_type != section.stream_type
+        {
+            peer_manager.remove_peer(section.ssrc);
+        }
+
+        // someone we already have (joining again after moving networks, or a repeat). Same peer, maybe a new address
+        if peer_manager.set_peer_addr(section.ssrc, media_addr) {
+            return Ok(());
+        }
+
+        // ex: a profile VideoToolbox won't take. Their stream's no use to us
+        if !self.capabilities().await.decodes(&section.stream_type) {
+            println!("Can't decode {}'s {:?}, skipping it", cname, stream_type);
+            return Ok(());
+        }
+
+        match &section.stream_type {
+            StreamTypeWithArgs::Audio {
+                sample_rate,
+                channels,
+                use_inband_fec,
+                ..
+            } => {
+                let swift_peer_model = self.events.audio_peer_joined(
+                    section.ssrc,
+                    *sample_rate,
+                    *channels,
+                    *use_inband_fec,
+                );
+
+                peer_manager.add_peer(section.ssrc, media_addr, swift_peer_model);
+            }
+            StreamTypeWithArgs::Video { pps, sps } => {
+                let swift_peer_model =
+                    self.events
+                        .video_peer_joined(section.ssrc, media_addr, pps, sps);
+
+                peer_manager.add_peer(section.ssrc, media_addr, swift_peer_model);
+            }
+        }
+
+        self.lip_sync.associate(section.ssrc, cname, stream_type);
+
+        Ok(())
+    }
+
+    async fn get_specifications(&self, stream_type: StreamType) -> io::Result<StreamTypeWithArgs> {
+        let specifications = &self.specifications;
+
+        let response_args = match stream_type {
+            StreamType::Audio => {
+                let Some(ref opus_args) = *specifications.self_opus_args.lock().await else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Opus args are not initialized",
+                    ));
+                };
+
+                StreamTypeWithArgs::Audio {
+                    sample_rate: opus_args.sample_rate,
+                    channels: opus_args.channels,
+                    use_inband_fec: opus_args.use_inband_fec,
+                    red: true,
+                }
+            }
+            StreamType::Video => {
+                let Some(ref h264_args) = *specifications.self_h264_args.lock().await else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "H264 args are not initialized",
+                    ));
+                };
+
+                StreamTypeWithArgs::Video {
+                    pps: h264_args.pps.to_vec(),
+                    sps: h264_args.sps.to_vec(),
+                }
+            }
+        };
+
+        Ok(response_args)
+    }
 }
 
-async fn write_response(media_type: StreamTypeWithArgs) -> io::Result<String> {
-    let peer_manager = match media_type {
-        StreamTypeWithArgs::Audio {
-            sample_rate: _,
-            channels: _,
-        } => AUDIO_PEERS.wait(),
-        StreamTypeWithArgs::Video { pps: _, sps: _ } => FRAME_PEERS.wait(),
+pub(crate) async fn send_error(socket: &mut TcpStream, error: &io::Error) -> io::Result<()> {
+    let message = SignalingMessage::Error {
+        message: error.to_string(),
     };
 
-    let Ok(signaling_addr) = listener().await.local_addr() else {
-        return Err(io::Error::new(
-            io::ErrorKind::Interrupted,
-            "Failed to get signaling address",
-        ));
+    send_message(socket, &message).await
+}
+
+async fn send_secure_error(
+    writer: &mut SecureWriter<OwnedWriteHalf>,
+    error: &io::Error,
+) -> io::Result<()> {
+    let message = SignalingMessage::Error {
+        message: error.to_string(),
     };
 
-    let Some(specifications) = PEER_SPECIFICATIONS.get() else {
+    writer.send(&message).await
+}
+
+/// Failing to prove who someone is (or who we are) is a security event, not just a network one
+fn error_code(error: &io::Error) -> RtpError {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => RtpError::Security,
+        _ => RtpError::Signaling,
+    }
+}
+
+/// A Join or JoinAck has to be for the CNAME the other side's key gives them,
+/// otherwise anyone with the room secret could take over someone else's place
+fn check_key(remote_cname: &str, participant: &ParticipantArgs) -> io::Result<()> {
+    if participant.cname != remote_cname {
         return Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            "Specification manager not initialized",
+            io::ErrorKind::PermissionDenied,
+            format!("{} tried to join as {}", remote_cname, participant.cname),
         ));
-    };
+    }
 
-    // writing the response
-    let response = ServerArgs {
-        signaling_address: signaling_addr.to_string(),
-        local_rtp_address: peer_manager.local_rtp_addr().to_string(),
-        ssrc: peer_manager.local_ssrc(),
-        stream_type: media_type,
-        peer_signalling_addresses: specifications
-            .get_peers()
-            .iter()
-            .map(|addr| addr.to_string())
-            .collect(),
-    };
+    Ok(())
+}
 
-    let json_response = serde_json::to_string(&response)?;
+/// A participant's link carried something about somebody else
+fn impersonation(peer: &LinkPeer, what: &str, claimed: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!(
+            "{} used their link to send {} for {}",
+            peer.cname, what, claimed
+        ),
+    )
+}
 
-    return Ok(json_response);
+fn parse_addr(addr: &str) -> io::Result<SocketAddr> {
+    addr.parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
-async fn handle_request(request: &ServerArgs) -> io::Result<()> {
-    let (specifications, peer_manager) = match request.stream_type {
-        StreamTypeWithArgs::Video { pps: _, sps: _ } => {
-            (PEER_SPECIFICATIONS.get(), FRAME_PEERS.wait())
+#[cfg(test)]
+mod tests {
+    use std::ffi::c_void;
+
+    use super::*;
+    use crate::packets::RTPSession;
+    use crate::session_management::{media_sink::NullSink, rendezvous::Rendezvous};
+    use tokio::runtime::Handle;
+
+    /// SSRCs of the peers the app was told about, by kind
+    #[derive(Default)]
+    struct Joined {
+        audio: std::sync::Mutex<Vec<u32>>,
+        video: std::sync::Mutex<Vec<u32>>,
+    }
+
+    impl SessionEvents for Joined {
+        fn video_peer_joined(
+            &self,
+            ssrc: u32,
+            _media_addr: SocketAddr,
+            _pps: &[u8],
+            _sps: &[u8],
+        ) -> *mut c_void {
+            self.video.lock().unwrap().push(ssrc);
+            std::ptr::null_mut()
         }
-        StreamTypeWithArgs::Audio {
-            sample_rate: _,
-            channels: _,
-        } => (PEER_SPECIFICATIONS.get(), AUDIO_PEERS.wait()),
-    };
 
-    println!("{:?}", request);
+        fn audio_peer_joined(
+            &self,
+            ssrc: u32,
+            _sample_rate: f64,
+            _channels: u32,
+            _use_inband_fec: bool,
+        ) -> *mut c_void {
+            self.audio.lock().unwrap().push(ssrc);
+            std::ptr::null_mut()
+        }
 
-    let Some(specifications) = specifications else {
-        return Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            "Specification manager not initialized",
+        fn peer_left(&self, _ssrc: u32, _stream_type: StreamType, _context: *mut c_void) {}
+    }
+
+    fn participant(identity: &Identity, rtp_address: &str) -> ParticipantArgs {
+        ParticipantArgs {
+            signaling_address: "127.0.0.1:4000".to_string(),
+            cname: identity.cname().to_string(),
+            media: vec![MediaSection {
+                local_rtp_address: rtp_address.to_string(),
+                ssrc: 1,
+                stream_type: StreamTypeWithArgs::Audio {
+                    sample_rate: 48000.0,
+                    channels: 1,
+                    use_inband_fec: false,
+                    red: false,
+                },
+            }],
+            capabilities: Capabilities::ours(false),
+        }
+    }
+
+    /// Connects to `server` as `identity` and sends `join`. The other side of it is
+    /// whatever handle_signaling_client made of the connection
+    async fn join(
+        server: &Arc<SignalingServer>,
+        key: &RoomKey,
+        identity: &Identity,
+        join: ParticipantArgs,
+    ) -> (SecureLink, tokio::task::JoinHandle<io::Result<()>>) {
+        let listener = server.listener().await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handling = Arc::clone(server);
+        let handled = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            handling.handle_signaling_client(socket).await
+        });
+
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let (reader, mut writer) = secure_channel::connect(reader, writer, key, identity)
+            .await
+            .unwrap();
+        writer.send(&SignalingMessage::Join(join)).await.unwrap();
+
+        ((reader, writer), handled)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_secret_alone_cant_take_over_a_cname() {
+        let key = RoomKey::derive("test room", "test secret").unwrap();
+        let server = Arc::new(SignalingServer::new(
+            CallEntry::Host,
+            key.clone(),
+            Arc::new(NullSink),
+            Arc::new(LipSync::new()),
+            Arc::new(ErrorReporter::new()),
+            SessionTasks::new(Handle::current()),
+        ));
+
+        let victim = Identity::generate();
+        let ((mut victim_reader, _victim_writer), _) = join(
+            &server,
+            &key,
+            &victim,
+            participant(&victim, "127.0.0.1:5000"),
+        )
+        .await;
+        assert!(matches!(
+            victim_reader.receive().await.unwrap(),
+            Some(SignalingMessage::JoinAck { .. })
         ));
-    };
 
-    // TOOO: If the ip address & ssrc is the same, remove the old peer ,then update their specs
+        // knows the room secret, but joins with its own key as the victim to get their media sent elsewhere
+        let attacker = Identity::generate();
+        let mut takeover = participant(&attacker, "10.6.6.6:5000");
+        takeover.cname = victim.cname().to_string();
+
+        let ((mut attacker_reader, _attacker_writer), handled) =
+            join(&server, &key, &attacker, takeover).await;
+
+        assert_eq!(
+            handled.await.unwrap().unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert!(matches!(
+            attacker_reader.receive().await.unwrap(),
+            Some(SignalingMessage::Error { .. })
+        ));
 
-    let media_addr: SocketAddr = request
-        .local_rtp_address
-        .parse()
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let kept = server.participants.get(victim.cname()).unwrap();
+        assert_eq!(kept.media[0].local_rtp_address, "127.0.0.1:5000");
+        assert!(server.links.contains_key(victim.cname()));
+    }
 
-    match &request.stream_type {
-        StreamTypeWithArgs::Audio {
-            sample_rate,
-            channels,
-        } => {
-            // TODO: We'll get there!
-            let audio_manager_context = AUDIO_MANAGER_CONTEXT.wait();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn one_join_sets_up_audio_and_video() {
+        let key = RoomKey::derive("test room", "test secret").unwrap();
+        let joined = Arc::new(Joined::default());
+
+        let server = Arc::new(SignalingServer::new(
+            CallEntry::Host,
+            key.clone(),
+            Arc::clone(&joined) as Arc<dyn SessionEvents>,
+            Arc::new(LipSync::new()),
+            Arc::new(ErrorReporter::new()),
+            SessionTasks::new(Handle::current()),
+        ));
 
-            let swift_peer_model = unsafe {
-                swift_receive_audio_config(
-                    audio_manager_context.context,
-                    *sample_rate,
-                    *channels,
-                    request.ssrc,
-                )
-            };
+        // the app hands these over from its own threads
+        let configuring = Arc::clone(&server);
+        tokio::task::spawn_blocking(move || {
+            configuring.set_opus_args(OpusArgs {
+                sample_rate: 48000.0,
+                channels: 1,
+                use_inband_fec: false,
+            });
+            configuring.set_h264_args(H264Args {
+                sps: Bytes::from_static(&[0x67, 0x42, 0xe0, 0x1f]),
+                pps: Bytes::from_static(&[0x68, 0xce, 0x3c, 0x80]),
+            });
+        })
+        .await
+        .unwrap();
 
-            peer_manager.add_peer(request.ssrc, media_addr, swift_peer_model);
+        let audio_peers = Arc::new(PeerManager::new(
+            RTPSession::new("127.0.0.1:5000".parse().unwrap(), 48000),
+            StreamType::Audio,
+            Arc::clone(&joined) as Arc<dyn SessionEvents>,
+        ));
+        let video_peers = Arc::new(PeerManager::new(
+            RTPSession::new("127.0.0.1:5002".parse().unwrap(), 90000),
+            StreamType::Video,
+            Arc::clone(&joined) as Arc<dyn SessionEvents>,
+        ));
+
+        tokio::spawn(
+            Arc::clone(&server).run_signaling_server(Arc::clone(&audio_peers), StreamType::Audio),
+        );
+        tokio::spawn(
+            Arc::clone(&server).run_signaling_server(Arc::clone(&video_peers), StreamType::Video),
+        );
+
+        // both streams are up once both of our sections are
+        for _ in 0..100 {
+            if server.participant_args().await.unwrap().media.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
-        StreamTypeWithArgs::Video { pps, sps } => {
-            let context = PEER_VIDEO_CONTEXT.wait();
 
-            let swift_peer_model = unsafe {
-                swift_receive_pps_sps(
-                    context.context,
-                    pps.as_ptr(),
-                    pps.len(),
-                    sps.as_ptr(),
-                    sps.len(),
-                    media_addr.to_string().as_ptr(),
-                )
-            };
+        let peer = Identity::generate();
+        let mut join = participant(&peer, "127.0.0.1:6000");
+        join.media.push(MediaSection {
+            local_rtp_address: "127.0.0.1:6002".to_string(),
+            ssrc: 2,
+            stream_type: StreamTypeWithArgs::Video {
+                pps: vec![0x68, 0xce, 0x3c, 0x80],
+                sps: vec![0x67, 0x42, 0xe0, 0x1f],
+            },
+        });
 
-            peer_manager.add_peer(request.ssrc, media_addr, swift_peer_model);
+        let addr = server.signaling_addr().await.unwrap();
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let (mut reader, mut writer) = secure_channel::connect(reader, writer, &key, &peer)
+            .await
+            .unwrap();
+        writer.send(&SignalingMessage::Join(join)).await.unwrap();
+
+        let Some(SignalingMessage::JoinAck { args, .. }) = reader.receive().await.unwrap() else {
+            panic!("expected a JoinAck");
+        };
+
+        let ours: Vec<(StreamType, u32)> = args
+            .media
+            .iter()
+            .map(|section| (section.stream_type.kind(), section.ssrc))
+            .collect();
+        assert_eq!(
+            ours,
+            vec![
+                (StreamType::Audio, audio_peers.local_ssrc()),
+                (StreamType::Video, video_peers.local_ssrc()),
+            ]
+        );
+
+        // the JoinAck goes out first, then their sections get set up
+        for _ in 0..100 {
+            if !audio_peers.get_peers().is_empty() && !video_peers.get_peers().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
+
+        assert_eq!(
+            audio_peers.get_peers(),
+            vec!["127.0.0.1:6000".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            video_peers.get_peers(),
+            vec!["127.0.0.1:6002".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(*joined.audio.lock().unwrap(), vec![1]);
+        assert_eq!(*joined.video.lock().unwrap(), vec![2]);
     }
 
-    let signaling_addr: SocketAddr = request
-        .signaling_address
-        .parse()
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_relayed_leave_only_removes_its_senders_streams() {
+        let key = RoomKey::derive("test room", "test secret").unwrap();
+        let server = Arc::new(SignalingServer::new(
+            CallEntry::Host,
+            key.clone(),
+            Arc::new(NullSink),
+            Arc::new(LipSync::new()),
+            Arc::new(ErrorReporter::new()),
+            SessionTasks::new(Handle::current()),
+        ));
+        let audio_peers = Arc::new(PeerManager::new(
+            RTPSession::new("127.0.0.1:5000".parse().unwrap(), 48000),
+            StreamType::Audio,
+            Arc::new(NullSink),
+        ));
+        assert!(server.audio_peers.set(Arc::clone(&audio_peers)).is_ok());
+
+        let victim = Identity::generate();
+        let ((mut victim_reader, _victim_writer), _) = join(
+            &server,
+            &key,
+            &victim,
+            participant(&victim, "127.0.0.1:5000"),
+        )
+        .await;
+        assert!(matches!(
+            victim_reader.receive().await.unwrap(),
+            Some(SignalingMessage::JoinAck { .. })
+        ));
 
-    specifications.add_peer(signaling_addr);
+        // someone we heard about but never linked to, at their own address with their own SSRC
+        let unlinked = Identity::generate();
+        let mut unlinked_args = participant(&unlinked, "127.0.0.1:6000");
+        unlinked_args.signaling_address = "127.0.0.1:4001".to_string();
+        unlinked_args.media[0].ssrc = 9;
+        server.handle_participant(&unlinked_args).await.unwrap();
 
-    Ok(())
-}
+        for _ in 0..100 {
+            if audio_peers.get_peers().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(server.links.contains_key(victim.cname()));
+
+        // anyone in the room name can relay these, without the room secret
+        for signaling_address in ["127.0.0.1:4000", "10.6.6.6:4000"] {
+            server.handle_room_leave(&LeaveArgs {
+                signaling_address: signaling_address.to_string(),
+                ssrcs: vec![1, 9],
+            });
+        }
+        assert_eq!(audio_peers.get_peers().len(), 2);
 
-async fn get_specifications(stream_type: StreamType) -> io::Result<StreamTypeWithArgs> {
-    let Some(specifications) = PEER_SPECIFICATIONS.get() else {
-        return Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            "Specification manager not initialized",
-        ));
-    };
+        // the unlinked one leaving can't take the victim's stream with them
+        server.handle_room_leave(&LeaveArgs {
+            signaling_address: "127.0.0.1:4001".to_string(),
+            ssrcs: vec![1, 9],
+        });
 
-    let response_args = match stream_type {
-        StreamType::Audio => {
-            let Some(ref opus_args) = *specifications.self_opus_args.lock().await else {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Opus args are not initialized",
-                ));
-            };
+        assert_eq!(
+            audio_peers.get_peers(),
+            vec!["127.0.0.1:5000".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(server.participants.contains_key(victim.cname()));
+        assert!(!server.participants.contains_key(unlinked.cname()));
+    }
 
-            StreamTypeWithArgs::Audio {
-                sample_rate: opus_args.sample_rate,
-                channels: opus_args.channels,
+    fn uses_inband_fec(server: &SignalingServer, cname: &str) -> bool {
+        server.participants.get(cname).is_some_and(|participant| {
+            matches!(
+                participant.media[0].stream_type,
+                StreamTypeWithArgs::Audio {
+                    use_inband_fec: true,
+                    ..
+                }
+            )
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_update_right_after_joining_isnt_lost() {
+        let key = RoomKey::derive("test room", "test secret").unwrap();
+
+        let host = Member::new(CallEntry::Host, &key, 5010).await;
+        let host_addr = host.server.signaling_addr().await.unwrap();
+
+        // a slow network in front of the host, so the join is still going out for a while
+        let slow = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let slow_addr = slow.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut joining, _) = slow.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+
+            let mut host = TcpStream::connect(host_addr).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut joining, &mut host).await;
+        });
+
+        let member = Member::new(CallEntry::Peer(slow_addr), &key, 5012).await;
+
+        // changed its mind in the meantime
+        let configuring = Arc::clone(&member.server);
+        tokio::task::spawn_blocking(move || {
+            configuring.set_opus_args(OpusArgs {
+                sample_rate: 48000.0,
+                channels: 1,
+                use_inband_fec: true,
+            });
+        })
+        .await
+        .unwrap();
+
+        let cname = member.server.cname();
+        assert!(eventually(|| uses_inband_fec(&host.server, cname)).await);
+
+        // and the join didn't land on top of it afterwards
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(uses_inband_fec(&host.server, cname));
+    }
+
+    /// false if `done` never was
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        for _ in 0..250 {
+            if done() {
+                return true;
             }
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
-        StreamType::Video => {
-            let Some(ref h264_args) = *specifications.self_h264_args.lock().await else {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "H264 args are not initialized",
-                ));
+
+        done()
+    }
+
+    /// Someone in a call, sending audio from `rtp_port`
+    struct Member {
+        server: Arc<SignalingServer>,
+        audio_peers: Arc<PeerManager>,
+        joined: Arc<Joined>,
+        shutdown: CancellationToken,
+    }
+
+    impl Member {
+        /// Gets into `room` on the rendezvous server at `rendezvous_addr`, like the app's
+        /// rust_session_create_in_room would
+        async fn in_room(rendezvous_addr: &str, room: &str, key: &RoomKey, rtp_port: u16) -> Self {
+            let entry = CallEntry::Room {
+                rendezvous_addr: rendezvous_addr.to_string(),
+                room: room.to_string(),
             };
 
-            StreamTypeWithArgs::Video {
-                pps: h264_args.pps.to_vec(),
-                sps: h264_args.sps.to_vec(),
+            Self::new(entry, key, rtp_port).await
+        }
+
+        /// Starts its audio and gets into the call through `entry`
+        async fn new(entry: CallEntry, key: &RoomKey, rtp_port: u16) -> Self {
+            let joined = Arc::new(Joined::default());
+            let tasks = SessionTasks::new(Handle::current());
+            let shutdown = tasks.shutdown_token();
+
+            let server = Arc::new(SignalingServer::new(
+                entry,
+                key.clone(),
+                Arc::clone(&joined) as Arc<dyn SessionEvents>,
+                Arc::new(LipSync::new()),
+                Arc::new(ErrorReporter::new()),
+                tasks,
+            ));
+            server.expect_stream(StreamType::Audio);
+
+            let configuring = Arc::clone(&server);
+            tokio::task::spawn_blocking(move || {
+                configuring.set_opus_args(OpusArgs {
+                    sample_rate: 48000.0,
+                    channels: 1,
+                    use_inband_fec: false,
+                });
+            })
+            .await
+            .unwrap();
+
+            let audio_peers = Arc::new(PeerManager::new(
+                RTPSession::new(SocketAddr::from(([127, 0, 0, 1], rtp_port)), 48000),
+                StreamType::Audio,
+                Arc::clone(&joined) as Arc<dyn SessionEvents>,
+            ));
+            tokio::spawn(
+                Arc::clone(&server)
+                    .run_signaling_server(Arc::clone(&audio_peers), StreamType::Audio),
+            );
+
+            Self {
+                server,
+                audio_peers,
+                joined,
+                shutdown,
             }
         }
-    };
 
-    Ok(response_args)
+        fn rtp_addr(&self) -> SocketAddr {
+            self.audio_peers.local_rtp_addr()
+        }
+
+        fn sends_to(&self, others: &[&Member]) -> bool {
+            let mut peers = self.audio_peers.get_peers();
+            peers.sort();
+
+            let mut expected: Vec<SocketAddr> =
+                others.iter().map(|other| other.rtp_addr()).collect();
+            expected.sort();
+
+            peers == expected
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_room_links_everyone_in_it() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rendezvous_addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(Arc::new(Rendezvous::new()).serve(listener));
+
+        let key = RoomKey::derive("standup", "test secret").unwrap();
+
+        let first = Member::in_room(&rendezvous_addr, "standup", &key, 5000).await;
+        let second = Member::in_room(&rendezvous_addr, "standup", &key, 5002).await;
+        let third = Member::in_room(&rendezvous_addr, "standup", &key, 5004).await;
+        let members = [&first, &second, &third];
+
+        // nobody was handed anyone's address, the room did that
+        for member in members {
+            let others: Vec<&Member> = members
+                .into_iter()
+                .filter(|other| !std::ptr::eq(*other, member))
+                .collect();
+
+            assert!(
+                eventually(|| member.sends_to(&others) && member.server.links.len() == 2).await,
+                "{} sends to {:?}",
+                member.rtp_addr(),
+                member.audio_peers.get_peers()
+            );
+
+            let mut joined = member.joined.audio.lock().unwrap().clone();
+            joined.sort();
+            let mut expected: Vec<u32> = others
+                .iter()
+                .map(|other| other.audio_peers.local_ssrc())
+                .collect();
+            expected.sort();
+            assert_eq!(joined, expected);
+        }
+
+        // the first one loses its link to the third without noticing they're gone,
+        // so only the rendezvous server can tell it when they leave
+        let (_, link) = first.server.links.remove(third.server.cname()).unwrap();
+        link.retire.cancel();
+        assert!(first.sends_to(&[&second, &third]));
+
+        third.shutdown.cancel();
+
+        assert!(
+            eventually(|| first.sends_to(&[&second])).await,
+            "still sends to {:?}",
+            first.audio_peers.get_peers()
+        );
+        assert!(
+            eventually(|| second.sends_to(&[&first])).await,
+            "still sends to {:?}",
+            second.audio_peers.get_peers()
+        );
+        assert!(!first.server.participants.contains_key(third.server.cname()));
+    }
 }